@@ -0,0 +1,167 @@
+//! Cancelling queries that run while a write is pending, or whose
+//! snapshot is no longer needed.
+
+use crate::{Database, Event, EventKind};
+use std::fmt;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// The panic payload that queries unwind with when they are cancelled,
+/// see [`Runtime::unwind_if_cancelled`](crate::Runtime::unwind_if_cancelled).
+/// Use [`catch_cancellation`] to turn it back into a value.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl Cancelled {
+    pub(crate) fn throw() -> ! {
+        // Resume rather than panic, so that the panic hook does not
+        // print a message and a backtrace for it.
+        panic::resume_unwind(Box::new(Cancelled))
+    }
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "query was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Calls `f`, returning `Err(Cancelled)` if a query it fetches is
+/// cancelled. Other panics are resumed.
+///
+/// This is meant for the top-level calls into a database snapshot,
+/// which should stop and let the pending write through once they are
+/// cancelled. Salsa keeps its own state consistent when a query
+/// unwinds, so `f` does not have to be `UnwindSafe`.
+pub fn catch_cancellation<T>(f: impl FnOnce() -> T) -> Result<T, Cancelled> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => Ok(value),
+        Err(payload) => match payload.downcast::<Cancelled>() {
+            Ok(cancelled) => Err(*cancelled),
+            Err(payload) => panic::resume_unwind(payload),
+        },
+    }
+}
+
+/// Calls `f`, noting in `cancelled` whether it unwinds with `Cancelled`
+/// or with some other panic before the unwind continues.
+pub(crate) fn note_cancellation<T>(cancelled: &AtomicBool, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        cancelled.store(payload.is::<Cancelled>(), Ordering::Relaxed);
+        panic::resume_unwind(payload)
+    })
+}
+
+/// Like [`note_cancellation`], for the future that `f` creates and each
+/// poll of it. Synchronous queries do all of their work when the future
+/// is created.
+pub(crate) async fn note_cancellation_async<F>(
+    cancelled: &AtomicBool,
+    f: impl FnOnce() -> F,
+) -> F::Output
+where
+    F: Future,
+{
+    let future = note_cancellation(cancelled, f);
+    let mut future = std::pin::pin!(future);
+    std::future::poll_fn(|cx| note_cancellation(cancelled, || future.as_mut().poll(cx))).await
+}
+
+/// Cancels the queries of a single runtime, without affecting the
+/// other snapshots or creating a new revision. Each snapshot and fork
+/// has its own token, see
+/// [`Runtime::cancellation_token`](crate::Runtime::cancellation_token);
+/// the token of a fork is also cancelled along with the token of the
+/// runtime that it was forked from.
+///
+/// Once the token is cancelled, every query that the runtime fetches
+/// unwinds with [`Cancelled`], and so does
+/// [`Runtime::unwind_if_cancelled`](crate::Runtime::unwind_if_cancelled).
+/// Queries of other runtimes that were blocked on those queries execute
+/// them themselves instead. There is no way to undo the cancellation,
+/// so the snapshot should be dropped. Only the database itself recovers,
+/// as it gets a new token once a new revision starts.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    state: Arc<TokenState>,
+}
+
+#[derive(Debug, Default)]
+struct TokenState {
+    cancelled: AtomicBool,
+    parent: Option<CancellationToken>,
+}
+
+impl CancellationToken {
+    /// Cancels the queries of the runtime this token belongs to, and of
+    /// the runtimes forked from it.
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::Release);
+    }
+
+    /// Whether this token, or the token of a runtime that this one was
+    /// forked from, was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        let mut token = self;
+        loop {
+            if token.state.cancelled.load(Ordering::Acquire) {
+                return true;
+            }
+            match &token.state.parent {
+                Some(parent) => token = parent,
+                None => return false,
+            }
+        }
+    }
+
+    /// A new token that is also cancelled when this one is.
+    pub(crate) fn child(&self) -> Self {
+        CancellationToken {
+            state: Arc::new(TokenState {
+                cancelled: AtomicBool::new(false),
+                parent: Some(self.clone()),
+            }),
+        }
+    }
+}
+
+/// Unwinds with `Cancelled` if the runtime of `db` is cancelled,
+/// reporting the cancellation to `salsa_event`. Called whenever a
+/// query is fetched.
+pub(crate) fn unwind_if_cancelled<D>(db: &D)
+where
+    D: ?Sized + Database,
+{
+    let runtime = db.salsa_runtime();
+    if runtime.is_cancelled() {
+        db.salsa_event(Event {
+            runtime_id: runtime.id(),
+            kind: EventKind::DidObserveCancellation,
+        });
+        Cancelled::throw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CancellationToken;
+
+    #[test]
+    fn cancelling_parent_cancels_child() {
+        let parent = CancellationToken::default();
+        let child = parent.child();
+        let sibling = parent.child();
+
+        child.cancel();
+        assert!(child.is_cancelled());
+        assert!(!parent.is_cancelled());
+        assert!(!sibling.is_cancelled());
+
+        parent.cancel();
+        assert!(sibling.is_cancelled());
+    }
+}
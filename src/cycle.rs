@@ -0,0 +1,77 @@
+//! Readable reports of the cycles that queries run into.
+
+use crate::plumbing::DatabaseOps;
+use crate::{CycleError, DatabaseKeyIndex, RuntimeId};
+use std::fmt;
+
+/// One participant of a cycle, as listed by [`CycleReport`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CycleStep {
+    /// The participant.
+    pub database_key: DatabaseKeyIndex,
+    /// The participant as `query_name(key)`.
+    pub query: String,
+    /// The runtime that was executing the participant, if known.
+    pub runtime_id: Option<RuntimeId>,
+}
+
+/// The participants of a cycle, with their query names and keys, as
+/// returned by [`CycleError::report`]. Displays as one participant per
+/// line; if the cycle spans several runtimes, each line also names the
+/// runtime that was executing the participant.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CycleReport {
+    steps: Vec<CycleStep>,
+}
+
+impl CycleReport {
+    /// The participants of the cycle, in the order they were entered.
+    pub fn steps(&self) -> &[CycleStep] {
+        &self.steps
+    }
+
+    /// Whether the cycle spans queries executing in several runtimes.
+    pub fn spans_runtimes(&self) -> bool {
+        let mut runtime_ids = self.steps.iter().filter_map(|step| step.runtime_id);
+        match runtime_ids.next() {
+            Some(first) => runtime_ids.any(|runtime_id| runtime_id != first),
+            None => false,
+        }
+    }
+}
+
+impl fmt::Display for CycleReport {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(fmt, "cycle detected:")?;
+        let spans_runtimes = self.spans_runtimes();
+        for step in &self.steps {
+            write!(fmt, "    {}", step.query)?;
+            match step.runtime_id {
+                Some(runtime_id) if spans_runtimes => writeln!(fmt, " on {:?}", runtime_id)?,
+                _ => writeln!(fmt)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CycleError<DatabaseKeyIndex> {
+    /// Resolves the participants of the cycle to their query names and
+    /// keys in `db`.
+    pub fn report<D>(&self, db: &D) -> CycleReport
+    where
+        D: ?Sized + DatabaseOps,
+    {
+        let steps = self
+            .cycle
+            .iter()
+            .enumerate()
+            .map(|(i, &database_key)| CycleStep {
+                database_key,
+                query: format!("{:?}", database_key.debug(db)),
+                runtime_id: self.runtimes.get(i).copied(),
+            })
+            .collect();
+        CycleReport { steps }
+    }
+}
@@ -3,9 +3,15 @@
 
 use crate::durability::Durability;
 use crate::plumbing::QueryStorageOps;
+use crate::revision::Revision;
+use crate::runtime::FxIndexMap;
 use crate::Query;
-use crate::{QueryDb, QueryTable};
+use crate::{Database, DatabaseKeyIndex, QueryDb, QueryTable};
+use std::io::{self, Write};
 use std::iter::FromIterator;
+use std::sync::Arc;
+
+pub use crate::runtime::QueryTrace;
 
 /// Additional methods on queries that can be used to "peek into"
 /// their current state. These methods are meant for debugging and
@@ -68,3 +74,244 @@ where
         QueryStorageOps::entries(&*self.storage, self.db)
     }
 }
+
+/// Output formats supported by [`write_query_graph`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// A Graphviz `digraph`.
+    Dot,
+
+    /// A JSON object with a `nodes` and an `edges` array.
+    Json,
+}
+
+/// A memoized value (or input, or interned value) in the dependency
+/// graph. Opaque to users.
+pub struct QueryGraphNode {
+    key: DatabaseKeyIndex,
+    durability: Durability,
+    changed_at: Revision,
+    verified_at: Option<Revision>,
+    has_value: bool,
+    inputs: Option<Arc<[DatabaseKeyIndex]>>,
+}
+
+impl QueryGraphNode {
+    /// `verified_at` is `None` for values that are never re-validated,
+    /// like inputs, and `inputs` is `None` if they are untracked.
+    pub(crate) fn new(
+        key: DatabaseKeyIndex,
+        durability: Durability,
+        changed_at: Revision,
+        verified_at: Option<Revision>,
+        has_value: bool,
+        inputs: Option<Arc<[DatabaseKeyIndex]>>,
+    ) -> Self {
+        QueryGraphNode {
+            key,
+            durability,
+            changed_at,
+            verified_at,
+            has_value,
+            inputs,
+        }
+    }
+
+    fn inputs(&self) -> &[DatabaseKeyIndex] {
+        self.inputs.as_deref().unwrap_or(&[])
+    }
+}
+
+/// Writes the dependency graph recorded by the memoized values in `db`
+/// to `out`. Each node is labeled with the name of its key, its
+/// durability and the revisions it changed and was last verified in;
+/// each edge points from a memoized value to one of the inputs it read.
+///
+/// If `root` is given, only the values it transitively depends on are
+/// included. Memos that read untracked inputs have no outgoing edges
+/// and are marked as such. Edges may point to keys that no longer have
+/// a memoized value (for example because it was swept); those are
+/// included as bare nodes.
+pub fn write_query_graph<DB>(
+    db: &DB,
+    root: Option<DatabaseKeyIndex>,
+    format: GraphFormat,
+    out: impl Write,
+) -> io::Result<()>
+where
+    DB: ?Sized + Database,
+{
+    let mut nodes = FxIndexMap::default();
+    db.for_each_query(&mut |query_storage| {
+        query_storage.for_each_memo(&mut |node| {
+            nodes.insert(node.key, node);
+        })
+    });
+
+    // Walk the graph from `root` (or from everything) so that every key
+    // that is referenced by an edge gets a name.
+    let mut keys: FxIndexMap<DatabaseKeyIndex, String> = FxIndexMap::default();
+    let mut stack: Vec<DatabaseKeyIndex> = match root {
+        Some(root) => vec![root],
+        None => nodes.keys().rev().copied().collect(),
+    };
+    while let Some(key) = stack.pop() {
+        if keys.contains_key(&key) {
+            continue;
+        }
+        keys.insert(key, format!("{:?}", key.debug(db)));
+        if let Some(node) = nodes.get(&key) {
+            stack.extend(node.inputs().iter().rev());
+        }
+    }
+    keys.sort_keys();
+
+    let graph = QueryGraph { nodes, keys };
+    match format {
+        GraphFormat::Dot => graph.write_dot(out),
+        GraphFormat::Json => graph.write_json(out),
+    }
+}
+
+struct QueryGraph {
+    nodes: FxIndexMap<DatabaseKeyIndex, QueryGraphNode>,
+    keys: FxIndexMap<DatabaseKeyIndex, String>,
+}
+
+impl QueryGraph {
+    fn write_dot(&self, mut out: impl Write) -> io::Result<()> {
+        writeln!(out, "digraph salsa {{")?;
+        for (key, name) in &self.keys {
+            let id = node_id(*key);
+            match self.nodes.get(key) {
+                Some(node) => {
+                    let mut label = format!(
+                        "{}\\n{} changed_at={:?}",
+                        dot_escape(name),
+                        durability_name(node.durability),
+                        node.changed_at
+                    );
+                    if let Some(verified_at) = node.verified_at {
+                        label += &format!(" verified_at={:?}", verified_at);
+                    }
+                    let mut attributes = String::new();
+                    if node.inputs.is_none() {
+                        label += "\\nuntracked";
+                        attributes += ", style=dashed";
+                    }
+                    if !node.has_value {
+                        attributes += ", color=gray";
+                    }
+                    writeln!(out, "    \"{}\" [label=\"{}\"{}];", id, label, attributes)?;
+                }
+                None => writeln!(
+                    out,
+                    "    \"{}\" [label=\"{}\", style=dotted];",
+                    id,
+                    dot_escape(name)
+                )?,
+            }
+        }
+        for (key, node) in self.memos() {
+            for input in node.inputs() {
+                writeln!(out, "    \"{}\" -> \"{}\";", node_id(key), node_id(*input))?;
+            }
+        }
+        writeln!(out, "}}")
+    }
+
+    fn write_json(&self, mut out: impl Write) -> io::Result<()> {
+        writeln!(out, "{{")?;
+        writeln!(out, "  \"nodes\": [")?;
+        for (i, (key, name)) in self.keys.iter().enumerate() {
+            write!(
+                out,
+                "    {{\"id\": \"{}\", \"name\": {}",
+                node_id(*key),
+                json_string(name)
+            )?;
+            if let Some(node) = self.nodes.get(key) {
+                write!(
+                    out,
+                    ", \"durability\": \"{}\", \"changed_at\": {}, \"verified_at\": ",
+                    durability_name(node.durability),
+                    node.changed_at.as_usize()
+                )?;
+                match node.verified_at {
+                    Some(verified_at) => write!(out, "{}", verified_at.as_usize())?,
+                    None => write!(out, "null")?,
+                }
+                write!(
+                    out,
+                    ", \"has_value\": {}, \"untracked\": {}",
+                    node.has_value,
+                    node.inputs.is_none()
+                )?;
+            }
+            let comma = if i + 1 < self.keys.len() { "," } else { "" };
+            writeln!(out, "}}{}", comma)?;
+        }
+        writeln!(out, "  ],")?;
+        writeln!(out, "  \"edges\": [")?;
+        let edges: Vec<_> = self
+            .memos()
+            .flat_map(|(key, node)| node.inputs().iter().map(move |input| (key, *input)))
+            .collect();
+        for (i, (from, to)) in edges.iter().enumerate() {
+            let comma = if i + 1 < edges.len() { "," } else { "" };
+            writeln!(
+                out,
+                "    {{\"from\": \"{}\", \"to\": \"{}\"}}{}",
+                node_id(*from),
+                node_id(*to),
+                comma
+            )?;
+        }
+        writeln!(out, "  ]")?;
+        writeln!(out, "}}")
+    }
+
+    /// The keys included in the graph that have a memoized value.
+    fn memos(&self) -> impl Iterator<Item = (DatabaseKeyIndex, &QueryGraphNode)> {
+        self.keys
+            .keys()
+            .filter_map(move |key| Some((*key, self.nodes.get(key)?)))
+    }
+}
+
+fn node_id(key: DatabaseKeyIndex) -> String {
+    format!(
+        "{}:{}:{}",
+        key.group_index(),
+        key.query_index(),
+        key.key_index()
+    )
+}
+
+fn durability_name(durability: Durability) -> &'static str {
+    match durability.index() {
+        0 => "LOW",
+        1 => "MEDIUM",
+        _ => "HIGH",
+    }
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -1,21 +1,39 @@
-use crate::debug::TableEntry;
+use crate::debug::{QueryGraphNode, TableEntry};
 use crate::durability::Durability;
-use crate::lru::Lru;
+use crate::eviction::{CustomEvictionPolicy, EvictionList, EvictionPolicy, PolicyList};
+use crate::key_table::KeyTable;
+use crate::lru::{BudgetNode, Eviction, LruNode};
+use crate::memory::{HeapSize, HeapSizeVtable, QueryMemory};
+use crate::persist::{
+    invalid_data, restore_exact, Persist, PersistVtable, PersistedQuery, PersistedQueryId,
+    PersistedTables, StableKey,
+};
+use crate::plumbing::CustomEvictionQueryStorageOps;
 use crate::plumbing::DerivedQueryStorageOps;
+use crate::plumbing::HeapSizeQueryStorageOps;
 use crate::plumbing::LruQueryStorageOps;
+use crate::plumbing::MemoryBudgetQueryStorageOps;
+use crate::plumbing::PersistentQueryStorageOps;
+use crate::plumbing::ProvisionalUpdate;
 use crate::plumbing::QueryFunction;
 use crate::plumbing::QueryStorageMassOps;
 #[cfg(feature = "async")]
 use crate::plumbing::{AsyncQueryFunction, QueryStorageOpsAsync};
 use crate::plumbing::{QueryFunctionBase, QueryStorageOps, QueryStorageOpsSync};
-use crate::runtime::{FxIndexMap, StampedValue};
+use crate::profile::QueryProfile;
+#[cfg(feature = "profiling")]
+use crate::profile::QueryStats;
+use crate::runtime::StampedValue;
+use crate::span::Instrument;
 use crate::{
     blocking_future::{BlockingFuture, BlockingFutureTrait},
-    CycleError, Database, DatabaseKeyIndex, QueryBase, QueryDb, Revision, Runtime, SweepStrategy,
+    CycleError, Database, DatabaseKeyIndex, Event, EventKind, ExecuteReason, LruStats, QueryBase,
+    QueryDb, Revision, Runtime, SweepStrategy,
 };
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use std::convert::TryFrom;
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 
 mod slot;
@@ -33,6 +51,30 @@ pub type MemoizedStorage<Q> = DerivedStorage<Q, AlwaysMemoizeValue>;
 /// storage requirements.
 pub type DependencyStorage<Q> = DerivedStorage<Q, NeverMemoizeValue>;
 
+type ToBudgetNode<Q, MP> = fn(Arc<Slot<Q, MP>>) -> Arc<dyn BudgetNode>;
+type ValueWeight<V> = fn(&V) -> usize;
+
+/// How the cycles that a query is the head of are iterated, see
+/// `QueryTableMut::set_cycle_fixpoint`.
+struct Fixpoint<Q>
+where
+    Q: QueryBase,
+{
+    initial: fn(&Q::Key) -> Q::Value,
+    max_iterations: usize,
+}
+
+impl<Q> Clone for Fixpoint<Q>
+where
+    Q: QueryBase,
+{
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Q> Copy for Fixpoint<Q> where Q: QueryBase {}
+
 /// Handles storage where the value is 'derived' by executing a
 /// function (in contrast to "inputs").
 pub struct DerivedStorage<Q, MP>
@@ -41,11 +83,37 @@ where
     MP: MemoizationPolicy<Q>,
 {
     group_index: u16,
-    lru_list: Lru<Slot<Q, MP>>,
-    slot_map: RwLock<FxIndexMap<Q::Key, Arc<Slot<Q, MP>>>>,
+    lru_list: RwLock<PolicyList<Slot<Q, MP>>>,
+    /// The weight function of the LRU list, if it is limited by weight.
+    lru_weight: RwLock<Option<ValueWeight<Q::Value>>>,
+    /// How many times a value was executed again after being evicted.
+    evicted_executions: AtomicU64,
+    /// Whether the LRU list evicts memos that read untracked inputs.
+    evict_untracked: Arc<AtomicBool>,
+    deferred_evictions: Mutex<DeferredEvictions<Q, MP>>,
+    slot_map: RwLock<KeyTable<Q::Key, Arc<Slot<Q, MP>>>>,
+    persist: RwLock<Option<PersistVtable<Q::Key, Q::Value>>>,
+    heap_size: RwLock<HeapSizeVtable<Q::Key, Q::Value>>,
+    /// Converts a slot into a node of the memory budget, if this query
+    /// takes part in it.
+    budget_node: RwLock<Option<ToBudgetNode<Q, MP>>>,
+    fixpoint: RwLock<Option<Fixpoint<Q>>>,
+    profile: QueryProfile,
     policy: PhantomData<MP>,
 }
 
+/// Memos with untracked inputs that the LRU list evicted in `revision`,
+/// the revision they were verified in. They are discarded once a later
+/// revision has started.
+struct DeferredEvictions<Q, MP>
+where
+    Q: QueryFunctionBase,
+    MP: MemoizationPolicy<Q>,
+{
+    revision: Revision,
+    slots: Vec<Arc<Slot<Q, MP>>>,
+}
+
 impl<Q, MP> std::panic::RefUnwindSafe for DerivedStorage<Q, MP>
 where
     Q: QueryFunctionBase,
@@ -105,42 +173,166 @@ where
         durability: Durability,
         changed_at: Revision,
     ) {
-        if let Some(evicted) = self.lru_list.record_use(slot) {
-            evicted.evict();
+        let evicted = {
+            let lru_list = self.lru_list.read();
+            match *self.lru_weight.read() {
+                Some(weight) => lru_list.record_weighted_use(slot, slot.value_weight(weight)),
+                None => lru_list.record_use(slot),
+            }
+        };
+        if self.evict_untracked.load(Ordering::Acquire) {
+            self.evict_memos(db, evicted);
+        } else {
+            for evicted in evicted {
+                if evicted.evict() == Eviction::Evicted {
+                    self.remove_budget_node(db.salsa_runtime(), &evicted);
+                    db.salsa_event(Event {
+                        runtime_id: db.salsa_runtime().id(),
+                        kind: EventKind::DidEvictValue {
+                            database_key: evicted.database_key_index(),
+                        },
+                    });
+                }
+            }
+        }
+
+        if let Some(budget_node) = *self.budget_node.read() {
+            let weight = slot.weight(&self.heap_size.read());
+            let evicted = db
+                .salsa_runtime()
+                .record_budget_use(&budget_node(slot.clone()), weight);
+            for database_key in evicted {
+                db.salsa_event(Event {
+                    runtime_id: db.salsa_runtime().id(),
+                    kind: EventKind::DidEvictValue { database_key },
+                });
+            }
         }
 
         db.salsa_runtime()
             .report_query_read(slot.database_key_index(), durability, changed_at);
     }
 
-    fn maybe_changed_since_get_slot(&self, input: &DatabaseKeyIndex) -> Arc<Slot<Q, MP>> {
+    /// Evicts `evicted` along with the memos whose eviction was deferred
+    /// to a later revision, see `Slot::evict_memo`.
+    fn evict_memos(&self, db: &<Q as QueryDb<'_>>::DynDb, mut evicted: Vec<Arc<Slot<Q, MP>>>) {
+        let runtime = db.salsa_runtime();
+        let revision_now = runtime.current_revision();
+        {
+            let mut deferred = self.deferred_evictions.lock();
+            if deferred.revision < revision_now {
+                // Slots that were used again since have rejoined the LRU
+                // list, which is in charge of them again.
+                evicted.extend(
+                    deferred
+                        .slots
+                        .drain(..)
+                        .filter(|slot| !slot.lru_index().is_in_lru()),
+                );
+            }
+        }
+
+        let mut deferred = Vec::new();
+        for slot in evicted {
+            match slot.evict_memo(runtime) {
+                Eviction::Evicted => {
+                    self.remove_budget_node(runtime, &slot);
+                    db.salsa_event(Event {
+                        runtime_id: runtime.id(),
+                        kind: EventKind::DidEvictValue {
+                            database_key: slot.database_key_index(),
+                        },
+                    })
+                }
+                Eviction::Empty => {}
+                Eviction::Kept => deferred.push(slot),
+            }
+        }
+        if !deferred.is_empty() {
+            let mut deferred_evictions = self.deferred_evictions.lock();
+            deferred_evictions.revision = revision_now;
+            deferred_evictions.slots.extend(deferred);
+        }
+    }
+
+    /// Replaces the LRU list, forgetting the members of the old one and
+    /// giving the new one the same capacity.
+    fn replace_lru_list(&self, new_list: PolicyList<Slot<Q, MP>>) {
+        let mut lru_list = self.lru_list.write();
+        let stats = lru_list.stats();
+        lru_list.set_lru_capacity(0);
+        *lru_list = new_list;
+        if stats.weight_capacity != 0 {
+            lru_list.set_weight_capacity(stats.weight_capacity);
+        } else {
+            lru_list.set_lru_capacity(stats.capacity);
+        }
+    }
+
+    /// Stops counting the memo of `slot` towards the memory budget, once
+    /// its value was discarded.
+    fn remove_budget_node(&self, runtime: &Runtime, slot: &Arc<Slot<Q, MP>>) {
+        if let Some(budget_node) = *self.budget_node.read() {
+            runtime.remove_budget_node(&budget_node(slot.clone()));
+        }
+    }
+
+    /// Returns the slot of `input`, or `None` if its key was removed by
+    /// `compact`. A slot created after `revision` has the index of a
+    /// removed key, which is not the key that was read before then.
+    fn maybe_changed_since_get_slot(
+        &self,
+        input: &DatabaseKeyIndex,
+        revision: Revision,
+    ) -> Option<Arc<Slot<Q, MP>>> {
         assert_eq!(input.group_index, self.group_index);
         assert_eq!(input.query_index, Q::QUERY_INDEX);
-        self.slot_map
-            .read()
-            .get_index(input.key_index as usize)
-            .unwrap()
-            .1
-            .clone()
+        let slot = self.slot_map.read().get_index(input.key_index).cloned()?;
+        if slot.created_at() > revision {
+            return None;
+        }
+        Some(slot)
     }
 
-    fn slot(&self, key: &Q::Key) -> Arc<Slot<Q, MP>> {
+    fn slot(&self, runtime: &Runtime, key: &Q::Key) -> Arc<Slot<Q, MP>> {
         if let Some(v) = self.slot_map.read().get(key) {
             return v.clone();
         }
 
+        let high_durability_changed_at = runtime.last_changed_revision(Durability::HIGH);
         let mut write = self.slot_map.write();
-        let entry = write.entry(key.clone());
-        let key_index = u32::try_from(entry.index()).unwrap();
-        let database_key_index = DatabaseKeyIndex {
-            group_index: self.group_index,
-            query_index: Q::QUERY_INDEX,
-            key_index: key_index,
-        };
-        entry
-            .or_insert_with(|| Arc::new(Slot::new(key.clone(), database_key_index)))
+        write
+            .get_or_insert_with(key.clone(), high_durability_changed_at, |key_index| {
+                let database_key_index = DatabaseKeyIndex {
+                    group_index: self.group_index,
+                    query_index: Q::QUERY_INDEX,
+                    key_index,
+                };
+                Arc::new(Slot::new(
+                    key.clone(),
+                    database_key_index,
+                    runtime.current_revision(),
+                    self.evict_untracked.clone(),
+                ))
+            })
             .clone()
     }
+
+    fn fixpoint(&self) -> Option<Fixpoint<Q>> {
+        *self.fixpoint.read()
+    }
+
+    /// A memo computed in the middle of a transaction would be valid for
+    /// the whole revision, including the writes that come after it.
+    fn check_not_in_transaction(&self, runtime: &Runtime) {
+        if runtime.in_transaction() {
+            panic!(
+                "cannot fetch `{:?}` during a transaction, since later writes \
+                 would not invalidate it",
+                Q::default()
+            );
+        }
+    }
 }
 
 impl<Q, MP> QueryStorageOps<Q> for DerivedStorage<Q, MP>
@@ -151,8 +343,20 @@ where
     fn new(group_index: u16) -> Self {
         DerivedStorage {
             group_index,
-            slot_map: RwLock::new(FxIndexMap::default()),
+            slot_map: RwLock::new(KeyTable::default()),
             lru_list: Default::default(),
+            lru_weight: RwLock::new(None),
+            evicted_executions: AtomicU64::new(0),
+            evict_untracked: Arc::new(AtomicBool::new(false)),
+            deferred_evictions: Mutex::new(DeferredEvictions {
+                revision: Revision::start(),
+                slots: Vec::new(),
+            }),
+            persist: RwLock::new(None),
+            heap_size: RwLock::new(HeapSizeVtable::default()),
+            budget_node: RwLock::new(None),
+            fixpoint: RwLock::new(None),
+            profile: QueryProfile::new(std::any::type_name::<Q::Group>(), Q::QUERY_NAME),
             policy: PhantomData,
         }
     }
@@ -165,13 +369,14 @@ where
     ) -> std::fmt::Result {
         assert_eq!(index.group_index, self.group_index);
         assert_eq!(index.query_index, Q::QUERY_INDEX);
-        let slot_map = self.slot_map.read();
-        let key = slot_map.get_index(index.key_index as usize).unwrap().0;
-        write!(fmt, "{}({:?})", Q::QUERY_NAME, key)
+        match self.slot_map.read().get_index(index.key_index) {
+            Some(slot) => write!(fmt, "{}({:?})", Q::QUERY_NAME, slot.key()),
+            None => write!(fmt, "{}(<removed>)", Q::QUERY_NAME),
+        }
     }
 
     fn durability(&self, db: &<Q as QueryDb<'_>>::DynDb, key: &Q::Key) -> Durability {
-        self.slot(key).durability(db)
+        self.slot(db.salsa_runtime(), key).durability(db)
     }
 
     fn entries<C>(&self, _db: &<Q as QueryDb<'_>>::DynDb) -> C
@@ -186,7 +391,18 @@ where
     }
 
     fn peek(&self, db: &<Q as QueryDb<'_>>::DynDb, key: &Q::Key) -> Option<Q::Value> {
-        self.slot(key).peek(db).map(|v| v.value)
+        self.slot(db.salsa_runtime(), key).peek(db).map(|v| v.value)
+    }
+
+    fn database_key_index(
+        &self,
+        _db: &<Q as QueryDb<'_>>::DynDb,
+        key: &Q::Key,
+    ) -> Option<DatabaseKeyIndex> {
+        self.slot_map
+            .read()
+            .get(key)
+            .map(|slot| slot.database_key_index())
     }
 }
 
@@ -204,8 +420,22 @@ where
         input: DatabaseKeyIndex,
         revision: Revision,
     ) -> bool {
-        let slot = self.maybe_changed_since_get_slot(&input);
-        crate::plumbing::sync_future(slot.maybe_changed_since(db, revision))
+        // The key was removed, so it can't be the same as it used to be.
+        let slot = match self.maybe_changed_since_get_slot(&input, revision) {
+            Some(slot) => slot,
+            None => return true,
+        };
+        let span = key_span!("maybe_changed_since", db, input, revision);
+        crate::plumbing::sync_future(
+            slot.maybe_changed_since(
+                db,
+                revision,
+                &self.profile,
+                &self.evicted_executions,
+                self.fixpoint(),
+            )
+            .instrument(span),
+        )
     }
 
     fn try_fetch(
@@ -213,12 +443,23 @@ where
         db: &mut <Q as QueryDb<'_>>::Db,
         key: &Q::Key,
     ) -> Result<Q::Value, CycleError<DatabaseKeyIndex>> {
-        let slot = self.slot(key);
+        self.check_not_in_transaction(db.salsa_runtime());
+        self.profile.record_fetch();
+        let slot = self.slot(db.salsa_runtime(), key);
+        let span = key_span!(
+            "fetch",
+            db,
+            slot.database_key_index(),
+            db.salsa_runtime().current_revision()
+        );
         let StampedValue {
             value,
             durability,
             changed_at,
-        } = crate::plumbing::sync_future(slot.read(db))?;
+        } = crate::plumbing::sync_future(
+            slot.read(db, &self.profile, &self.evicted_executions, self.fixpoint())
+                .instrument(span),
+        )?;
 
         self.record_fetch(db, &slot, durability, changed_at);
 
@@ -244,8 +485,20 @@ where
         revision: Revision,
     ) -> crate::BoxFuture<'f, bool> {
         Box::pin(async move {
-            let slot = self.maybe_changed_since_get_slot(&input);
-            slot.maybe_changed_since(db, revision).await
+            let slot = match self.maybe_changed_since_get_slot(&input, revision) {
+                Some(slot) => slot,
+                None => return true,
+            };
+            let span = key_span!("maybe_changed_since", db, input, revision);
+            slot.maybe_changed_since(
+                db,
+                revision,
+                &self.profile,
+                &self.evicted_executions,
+                self.fixpoint(),
+            )
+            .instrument(span)
+            .await
         })
     }
 
@@ -255,12 +508,23 @@ where
         key: &'f Q::Key,
     ) -> crate::BoxFuture<'f, Result<Q::Value, CycleError<DatabaseKeyIndex>>> {
         Box::pin(async move {
-            let slot = self.slot(key);
+            self.check_not_in_transaction(db.salsa_runtime());
+            self.profile.record_fetch();
+            let slot = self.slot(db.salsa_runtime(), key);
+            let span = key_span!(
+                "fetch",
+                db,
+                slot.database_key_index(),
+                db.salsa_runtime().current_revision()
+            );
             let StampedValue {
                 value,
                 durability,
                 changed_at,
-            } = slot.read(db).await?;
+            } = slot
+                .read(db, &self.profile, &self.evicted_executions, self.fixpoint())
+                .instrument(span)
+                .await?;
 
             self.record_fetch(db, &slot, durability, changed_at);
 
@@ -274,16 +538,271 @@ where
     for<'f, 'd> Q: QueryFunction<'f, 'd>,
     MP: MemoizationPolicy<Q>,
 {
-    fn sweep(&self, runtime: &Runtime, strategy: SweepStrategy) {
-        let map_read = self.slot_map.read();
-        let revision_now = runtime.current_revision();
-        for slot in map_read.values() {
-            slot.sweep(revision_now, strategy);
+    fn sweep(&self, db: &dyn Database, strategy: SweepStrategy) {
+        let runtime = db.salsa_runtime();
+        let swept: Vec<_> = self
+            .slot_map
+            .read()
+            .values()
+            .filter_map(|slot| {
+                let kind = slot.sweep(runtime, strategy)?;
+                self.lru_list.read().remove(slot);
+                self.remove_budget_node(runtime, slot);
+                Some(kind)
+            })
+            .collect();
+        for kind in swept {
+            db.salsa_event(Event {
+                runtime_id: runtime.id(),
+                kind,
+            });
         }
     }
-    fn purge(&self) {
-        self.lru_list.purge();
+    fn purge(&self, runtime: &Runtime) {
+        for slot in self.slot_map.read().values() {
+            self.remove_budget_node(runtime, slot);
+        }
+        self.lru_list.read().purge();
+        self.deferred_evictions.lock().slots.clear();
+        self.evicted_executions.store(0, Ordering::Relaxed);
         *self.slot_map.write() = Default::default();
+        runtime.remove_dependents_in_query(self.group_index, Q::QUERY_INDEX);
+    }
+
+    fn compact(&self, runtime: &Runtime) {
+        // A slot without a memo only holds its key. Unlink it from the
+        // lists that may still point to it, so that it can go unless a
+        // running query is using it.
+        let discarded: Vec<_> = self
+            .slot_map
+            .read()
+            .values()
+            .filter(|slot| !slot.has_memo())
+            .cloned()
+            .collect();
+        if discarded.is_empty() {
+            return;
+        }
+        for slot in &discarded {
+            self.lru_list.read().remove(slot);
+            self.remove_budget_node(runtime, slot);
+        }
+        self.deferred_evictions
+            .lock()
+            .slots
+            .retain(|slot| slot.has_memo());
+        drop(discarded);
+
+        self.slot_map
+            .write()
+            .retain(runtime.current_revision(), |slot| {
+                Arc::strong_count(slot) > 1 || slot.has_memo()
+            });
+    }
+
+    fn index_dependents(&self, runtime: &Runtime) {
+        for slot in self.slot_map.read().values() {
+            slot.index_dependents(runtime);
+        }
+    }
+
+    fn update_provisional(
+        &self,
+        runtime: &Runtime,
+        participants: &[DatabaseKeyIndex],
+        update: &ProvisionalUpdate,
+    ) {
+        let slots: Vec<_> = {
+            let slot_map = self.slot_map.read();
+            participants
+                .iter()
+                .filter(|participant| {
+                    participant.group_index == self.group_index
+                        && participant.query_index == Q::QUERY_INDEX
+                })
+                .filter_map(|participant| slot_map.get_index(participant.key_index).cloned())
+                .collect()
+        };
+        for slot in slots {
+            slot.update_provisional(runtime, &update.0);
+        }
+    }
+
+    fn for_each_memo(&self, op: &mut dyn FnMut(QueryGraphNode)) {
+        for slot in self.slot_map.read().values() {
+            if let Some(node) = slot.graph_node() {
+                op(node);
+            }
+        }
+    }
+
+    fn memory_usage(&self) -> Option<QueryMemory> {
+        let heap_size = *self.heap_size.read();
+        let mut memory = QueryMemory::new(std::any::type_name::<Q::Group>(), Q::QUERY_NAME);
+        let map_read = self.slot_map.read();
+        memory.bytes += map_read.allocated_bytes();
+        for (key, slot) in map_read.iter() {
+            memory.bytes += (heap_size.key)(key);
+            slot.add_memory_usage(&heap_size, &mut memory);
+        }
+        memory.bytes += self.lru_list.read().heap_size();
+        Some(memory)
+    }
+
+    #[cfg(feature = "profiling")]
+    fn profile(&self) -> Option<QueryStats> {
+        Some(self.profile.stats())
+    }
+
+    #[cfg(feature = "profiling")]
+    fn reset_profile(&self) {
+        self.profile.reset()
+    }
+
+    fn persisted_query(&self) -> Option<PersistedQueryId> {
+        self.persist
+            .read()
+            .map(|_| PersistedQueryId::of::<Q>(self.group_index))
+    }
+
+    fn persist(&self, db: &dyn Database) -> Option<PersistedQuery> {
+        let vtable = (*self.persist.read())?;
+
+        // Encoding the memos looks up the stable keys of their inputs,
+        // which may live in this very table, so don't hold the lock.
+        let slots: Vec<_> = self
+            .slot_map
+            .read()
+            .values()
+            .map(|slot| (vtable.key_bytes(slot.key()), slot.clone()))
+            .collect();
+        let mut keys = Vec::with_capacity(slots.len());
+        let mut data = Vec::new();
+        for (key, slot) in slots {
+            keys.push(key);
+            slot.persist(db, &vtable, &mut data);
+        }
+        Some(PersistedQuery::new(
+            &PersistedQueryId::of::<Q>(self.group_index),
+            keys,
+            data,
+        ))
+    }
+
+    fn restore(&self, tables: &PersistedTables) -> std::io::Result<()> {
+        let vtable = match *self.persist.read() {
+            Some(vtable) => vtable,
+            None => return Ok(()),
+        };
+        let id = PersistedQueryId::of::<Q>(self.group_index);
+        let query = match tables.get(&id) {
+            Some(query) => query,
+            None => return Ok(()),
+        };
+
+        let mut slot_map = self.slot_map.write();
+        if !slot_map.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "cannot restore into a query table that is not empty",
+            ));
+        }
+
+        // Slots are restored in the order of the persisted keys, which
+        // is what `PersistedTables::resolve` relies on.
+        let mut data = query.data();
+        for (index, key) in query.keys().enumerate() {
+            let key = restore_exact(vtable.restore_key, key)?;
+            let database_key_index = id.key(u32::try_from(index).unwrap());
+            let slot = Slot::restore(
+                key.clone(),
+                database_key_index,
+                self.evict_untracked.clone(),
+                &vtable,
+                tables,
+                &mut data,
+            )?;
+            if !slot_map.insert(key, Arc::new(slot)) {
+                return Err(invalid_data("duplicate key in persisted query"));
+            }
+        }
+
+        if !data.is_empty() {
+            return Err(invalid_data("trailing data in persisted query"));
+        }
+        Ok(())
+    }
+
+    fn stable_key(&self, index: DatabaseKeyIndex) -> Option<StableKey> {
+        let vtable = (*self.persist.read())?;
+        let id = PersistedQueryId::of::<Q>(self.group_index);
+        if !id.contains(index) {
+            return None;
+        }
+        let slot_map = self.slot_map.read();
+        let slot = slot_map.get_index(index.key_index)?;
+        Some(StableKey::new(
+            id.group_name(),
+            id.query_name(),
+            vtable.key_bytes(slot.key()),
+        ))
+    }
+
+    fn resolve_stable_key(&self, key: &StableKey) -> Option<DatabaseKeyIndex> {
+        let vtable = (*self.persist.read())?;
+        let id = PersistedQueryId::of::<Q>(self.group_index);
+        if !key.is_query(&id) {
+            return None;
+        }
+        let key = restore_exact(vtable.restore_key, key.key_bytes()).ok()?;
+        let key_index = self.slot_map.read().get_index_of(&key)?;
+        Some(id.key(key_index))
+    }
+}
+
+impl<Q, MP> PersistentQueryStorageOps for DerivedStorage<Q, MP>
+where
+    for<'f, 'd> Q: QueryFunction<'f, 'd>,
+    MP: MemoizationPolicy<Q>,
+    Q::Key: Persist,
+    Q::Value: Persist,
+{
+    fn enable_persistence(&self) {
+        *self.persist.write() = Some(PersistVtable::new());
+    }
+}
+
+impl<Q, MP> HeapSizeQueryStorageOps for DerivedStorage<Q, MP>
+where
+    for<'f, 'd> Q: QueryFunction<'f, 'd>,
+    MP: MemoizationPolicy<Q>,
+    Q::Key: HeapSize,
+    Q::Value: HeapSize,
+{
+    fn enable_heap_size(&self) {
+        *self.heap_size.write() = HeapSizeVtable::new();
+    }
+}
+
+impl<Q, MP> MemoryBudgetQueryStorageOps for DerivedStorage<Q, MP>
+where
+    for<'f, 'd> Q: QueryFunction<'f, 'd>,
+    MP: MemoizationPolicy<Q>,
+    Slot<Q, MP>: Send + Sync + 'static,
+{
+    fn enable_memory_budget(&self) {
+        *self.budget_node.write() = Some(|slot| slot);
+    }
+}
+
+impl<Q, MP> CustomEvictionQueryStorageOps for DerivedStorage<Q, MP>
+where
+    for<'f, 'd> Q: QueryFunction<'f, 'd>,
+    MP: MemoizationPolicy<Q>,
+    Slot<Q, MP>: Send + Sync + 'static,
+{
+    fn set_custom_eviction_policy(&self, policy: &impl CustomEvictionPolicy) {
+        self.replace_lru_list(PolicyList::Custom(policy.new_list()));
     }
 }
 
@@ -293,7 +812,8 @@ where
     MP: MemoizationPolicy<Q>,
 {
     fn set_lru_capacity(&self, new_capacity: usize) {
-        self.lru_list.set_lru_capacity(new_capacity);
+        *self.lru_weight.write() = None;
+        self.lru_list.read().set_lru_capacity(new_capacity);
     }
 }
 
@@ -303,17 +823,74 @@ where
     MP: MemoizationPolicy<Q>,
 {
     fn invalidate(&self, db: &mut <Q as QueryDb<'_>>::DynDb, key: &Q::Key) {
-        db.salsa_runtime_mut()
+        let mut invalidated = None;
+        let new_revision = db
+            .salsa_runtime_mut()
             .with_incremented_revision(&mut |_new_revision| {
                 let map_read = self.slot_map.read();
 
                 if let Some(slot) = map_read.get(key) {
-                    if let Some(durability) = slot.invalidate() {
+                    if let Some((durability, inputs)) = slot.invalidate() {
+                        invalidated = Some((slot.database_key_index(), inputs));
                         return Some(durability);
                     }
                 }
 
                 None
-            })
+            });
+        if let Some((revision, durability)) = new_revision {
+            db.salsa_event(Event {
+                runtime_id: db.salsa_runtime().id(),
+                kind: EventKind::DidIncrementRevision {
+                    revision,
+                    durability,
+                },
+            });
+        }
+
+        if let Some((database_key_index, inputs)) = invalidated {
+            db.salsa_runtime()
+                .update_dependents(database_key_index, inputs.tracked(), &[]);
+        }
+    }
+
+    fn set_lru_weight_capacity(&self, capacity: usize, weight: fn(&Q::Value) -> usize) {
+        // The cached weights may have been computed by another function.
+        for slot in self.slot_map.read().values() {
+            slot.forget_value_weight();
+        }
+        *self.lru_weight.write() = Some(weight);
+        self.lru_list.read().set_weight_capacity(capacity);
+    }
+
+    fn set_lru_evict_untracked(&self, evict: bool) {
+        self.evict_untracked.store(evict, Ordering::Release);
+        if !evict {
+            self.deferred_evictions.lock().slots.clear();
+        }
+    }
+
+    fn set_eviction_policy(&self, policy: EvictionPolicy) {
+        if self.lru_list.read().policy() != Some(policy) {
+            self.replace_lru_list(PolicyList::new(policy));
+        }
+    }
+
+    fn explain(&self, key: &Q::Key) -> Option<ExecuteReason> {
+        self.slot_map.read().get(key)?.explain()
+    }
+
+    fn lru_stats(&self) -> LruStats {
+        LruStats {
+            evicted_executions: self.evicted_executions.load(Ordering::Relaxed),
+            ..self.lru_list.read().stats()
+        }
+    }
+
+    fn set_cycle_fixpoint(&self, initial: fn(&Q::Key) -> Q::Value, max_iterations: usize) {
+        *self.fixpoint.write() = Some(Fixpoint {
+            initial,
+            max_iterations,
+        });
     }
 }
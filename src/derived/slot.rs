@@ -1,27 +1,35 @@
 use crate::blocking_future::{BlockingFutureTrait, PromiseTrait};
-use crate::debug::TableEntry;
-use crate::derived::MemoizationPolicy;
+use crate::cancellation::{note_cancellation, note_cancellation_async};
+use crate::debug::{QueryGraphNode, TableEntry};
+use crate::derived::{Fixpoint, MemoizationPolicy};
 use crate::durability::Durability;
 use crate::lru::LruIndex;
-use crate::lru::LruNode;
-use crate::plumbing::CycleDetected;
+use crate::lru::{BudgetNode, Eviction, LruNode};
+use crate::memory::{HeapSizeVtable, QueryMemory, ARC_HEADER};
+use crate::persist::{invalid_data, Persist, PersistVtable, PersistedTables, StableKey};
+use crate::plumbing::{CycleDetected, CycleUpdate, ProvisionalUpdate};
 use crate::plumbing::{DatabaseOps, QueryFunction, QueryFunctionBase};
+use crate::profile::QueryProfile;
 use crate::revision::Revision;
 use crate::runtime::Runtime;
 use crate::runtime::RuntimeId;
 use crate::runtime::StampedValue;
+use crate::span::Instrument;
 use crate::{
     AsAsyncDatabase, CycleError, Database, DatabaseKeyIndex, DiscardIf, DiscardWhat, Event,
-    EventKind, QueryBase, QueryDb, SweepStrategy,
+    EventKind, ExecuteReason, QueryBase, QueryDb, SweepStrategy,
 };
 
 use log::{debug, info};
 use parking_lot::Mutex;
 use parking_lot::{RawRwLock, RwLock};
+use rustc_hash::FxHashSet;
 use smallvec::SmallVec;
 
+use std::io;
 use std::marker::PhantomData;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 type Promise<Q> = <<Q as QueryFunctionBase>::BlockingFuture as BlockingFutureTrait<
@@ -38,12 +46,34 @@ where
     state: RwLock<QueryState<Q>>,
     policy: PhantomData<MP>,
     lru_index: LruIndex,
+    budget_index: LruIndex,
+
+    /// The estimated size of the memo, or `usize::MAX` if it has not
+    /// been estimated since the memo was last replaced.
+    weight: AtomicUsize,
+
+    /// Like `weight`, for the weight of the value given by the weight
+    /// function of a weighted LRU.
+    value_weight: AtomicUsize,
+
+    /// Whether evicting the slot discards a memo that read an untracked
+    /// input, see `evict_memo`. Shared with the storage, since the
+    /// memory budget evicts slots without going through it.
+    evict_untracked: Arc<AtomicBool>,
+
+    /// The revision the slot was created in. The index of the slot may
+    /// have belonged to a key that `compact` removed, so memos verified
+    /// before this revision did not read this key, even if they store
+    /// the index.
+    created_at: Revision,
 }
 
 #[doc(hidden)]
 #[derive(Clone)]
 pub struct WaitResult<V, K> {
-    value: StampedValue<V>,
+    /// The value, or `None` if the query has to be read again, because
+    /// the cycle it was computed in was executed without it.
+    value: Option<StampedValue<V>>,
     cycle: Vec<K>,
 }
 
@@ -52,7 +82,8 @@ enum QueryState<Q>
 where
     Q: QueryFunctionBase,
 {
-    NotComputed,
+    /// There is no memo, for the given reason.
+    NotComputed(ExecuteReason),
 
     /// The runtime with the given id is currently computing the
     /// result of this query; if we see this value in the table, it
@@ -60,12 +91,40 @@ where
     InProgress {
         id: RuntimeId,
         waiting: Mutex<SmallVec<[Promise<Q>; 2]>>,
+        provisional: Option<Provisional<Q>>,
     },
 
     /// We have computed the query already, and here is the result.
     Memoized(Memo<Q>),
 }
 
+/// The state of a query that takes part in a cycle that is iterated to
+/// a fixpoint, see `QueryTableMut::set_cycle_fixpoint`. The query stays
+/// in progress until the head of the cycle is done iterating, so that
+/// other runtimes only see its final value.
+enum Provisional<Q>
+where
+    Q: QueryFunctionBase,
+{
+    /// The head of the cycle is executing again; reads of it inside the
+    /// cycle see the value from the previous iteration.
+    Head(Q::Value),
+
+    /// Completed with a value depending on the provisional value of the
+    /// head. `old_memo` is the memo from before the cycle was executed.
+    Completed {
+        value: StampedValue<Q::Value>,
+        memo: Memo<Q>,
+        old_memo: Option<Memo<Q>>,
+    },
+
+    /// Has to be executed again, because the head changed.
+    Stale {
+        old_memo: Option<Memo<Q>>,
+        reason: ExecuteReason,
+    },
+}
+
 struct Memo<Q>
 where
     Q: QueryBase,
@@ -73,6 +132,14 @@ where
     /// The result of the query, if we decide to memoize it.
     value: Option<Q::Value>,
 
+    /// Why the value can't be reused any more, if it can't: it was
+    /// never memoized or has been discarded since.
+    discarded: Option<ExecuteReason>,
+
+    /// Why the query was executed to produce this memo. Not known for
+    /// restored memos.
+    executed_because: Option<ExecuteReason>,
+
     /// Revision information
     revisions: MemoRevisions,
 }
@@ -118,12 +185,22 @@ where
     Q: QueryFunctionBase,
     MP: MemoizationPolicy<Q>,
 {
-    pub(super) fn new(key: Q::Key, database_key_index: DatabaseKeyIndex) -> Self {
+    pub(super) fn new(
+        key: Q::Key,
+        database_key_index: DatabaseKeyIndex,
+        created_at: Revision,
+        evict_untracked: Arc<AtomicBool>,
+    ) -> Self {
         Self {
             key,
             database_key_index,
-            state: RwLock::new(QueryState::NotComputed),
+            state: RwLock::new(QueryState::NotComputed(ExecuteReason::NotComputed)),
             lru_index: LruIndex::default(),
+            budget_index: LruIndex::default(),
+            weight: AtomicUsize::new(usize::MAX),
+            value_weight: AtomicUsize::new(usize::MAX),
+            evict_untracked,
+            created_at,
             policy: PhantomData,
         }
     }
@@ -131,6 +208,79 @@ where
     pub(super) fn database_key_index(&self) -> DatabaseKeyIndex {
         self.database_key_index
     }
+
+    pub(super) fn created_at(&self) -> Revision {
+        self.created_at
+    }
+
+    pub(super) fn key(&self) -> &Q::Key {
+        &self.key
+    }
+
+    /// Whether the slot has a memo, or is being computed.
+    pub(super) fn has_memo(&self) -> bool {
+        match *self.state.read() {
+            QueryState::NotComputed(_) => false,
+            QueryState::InProgress { .. } | QueryState::Memoized(_) => true,
+        }
+    }
+
+    pub(super) fn persist(
+        &self,
+        db: &dyn Database,
+        vtable: &PersistVtable<Q::Key, Q::Value>,
+        out: &mut Vec<u8>,
+    ) {
+        match &*self.state.read() {
+            QueryState::Memoized(memo) => {
+                out.push(1);
+                memo.persist(db, vtable, out);
+            }
+
+            // A computation that is still in progress is not saved, just
+            // as if it had panicked.
+            QueryState::NotComputed(_) | QueryState::InProgress { .. } => out.push(0),
+        }
+    }
+
+    pub(super) fn restore(
+        key: Q::Key,
+        database_key_index: DatabaseKeyIndex,
+        evict_untracked: Arc<AtomicBool>,
+        vtable: &PersistVtable<Q::Key, Q::Value>,
+        tables: &PersistedTables,
+        input: &mut &[u8],
+    ) -> io::Result<Self> {
+        let state = match u8::restore(input)? {
+            0 => QueryState::NotComputed(ExecuteReason::NotComputed),
+            1 => {
+                let mut memo = Memo::restore(vtable, tables, input)?;
+                if memo.value.is_none() {
+                    memo.discarded = Some(if MP::should_memoize_value(&key) {
+                        ExecuteReason::Evicted
+                    } else {
+                        ExecuteReason::NotMemoized
+                    });
+                }
+                QueryState::Memoized(memo)
+            }
+            _ => return Err(invalid_data("invalid query state")),
+        };
+        Ok(Self {
+            key,
+            database_key_index,
+            state: RwLock::new(state),
+            lru_index: LruIndex::default(),
+            budget_index: LruIndex::default(),
+            weight: AtomicUsize::new(usize::MAX),
+            value_weight: AtomicUsize::new(usize::MAX),
+            evict_untracked,
+            // Restored memos are verified in the saved revisions, before
+            // the current one.
+            created_at: Revision::start(),
+            policy: PhantomData,
+        })
+    }
 }
 
 enum MaybeChangedSinceState<F> {
@@ -172,33 +322,39 @@ where
     pub(super) async fn read<'d>(
         &self,
         db: &mut <Q as QueryDb<'d>>::Db,
+        profile: &QueryProfile,
+        evicted_executions: &AtomicU64,
+        fixpoint: Option<Fixpoint<Q>>,
     ) -> Result<StampedValue<Q::Value>, CycleError<DatabaseKeyIndex>> {
-        let revision_now;
-        {
+        let revision_now = loop {
             // NB: We don't need to worry about people modifying the
             // revision out from under our feet. Either `db` is a frozen
             // database, in which case there is a lock, or the mutator
             // thread is the current thread, and it will be prevented from
             // doing any `set` invocations while the query function runs.
-            revision_now = db.salsa_runtime().current_revision();
+            let revision_now = db.salsa_runtime().current_revision();
 
             info!("{:?}: invoked at {:?}", self, revision_now,);
 
             // First, do a check with a read-lock.
-            let opt = match self.probe(db, self.state.read(), revision_now) {
+            let opt = match self.probe(db, self.state.read(), revision_now, profile, fixpoint) {
                 ProbeState::Pending(future, other_id) => Some((future, other_id)),
                 ProbeState::UpToDate(v) => return v,
                 ProbeState::StaleOrAbsent(_guard) => None,
             };
 
-            if let Some((future, other_id)) = opt {
-                return self.wait_for_value(db, other_id, future).await;
+            match opt {
+                Some((future, other_id)) => {
+                    if let Some(result) = self.wait_for_value(db, other_id, future).await {
+                        return result;
+                    }
+                }
+                None => break revision_now,
             }
-
-            revision_now
         };
 
-        self.read_upgrade(db, revision_now).await
+        self.read_upgrade(db, revision_now, profile, evicted_executions, fixpoint)
+            .await
     }
 
     /// Second phase of a read operation: acquires an upgradable-read
@@ -209,18 +365,27 @@ where
         &self,
         db: &mut <Q as QueryDb<'d>>::Db,
         revision_now: Revision,
+        profile: &QueryProfile,
+        evicted_executions: &AtomicU64,
+        fixpoint: Option<Fixpoint<Q>>,
     ) -> Result<StampedValue<Q::Value>, CycleError<DatabaseKeyIndex>> {
         debug!("{:?}: read_upgrade(revision_now={:?})", self, revision_now,);
 
         // Check with an upgradable read to see if there is a value
         // already. (This permits other readers but prevents anyone
         // else from running `read_upgrade` at the same time.)
-        let old_memo = {
+        let (old_memo, not_computed, validate) = loop {
             enum State<F, V> {
                 Wait(F, RuntimeId),
-                Memo(Option<V>),
+                Memo(Option<V>, ExecuteReason, bool),
             }
-            let state = match self.probe(db, self.state.upgradable_read(), revision_now) {
+            let state = match self.probe(
+                db,
+                self.state.upgradable_read(),
+                revision_now,
+                profile,
+                fixpoint,
+            ) {
                 ProbeState::Pending(future, other_id) => State::Wait(future, other_id),
                 ProbeState::UpToDate(v) => return v,
                 ProbeState::StaleOrAbsent(state) => {
@@ -229,22 +394,38 @@ where
 
                     let mut state = RwLockUpgradableReadGuard::upgrade(state);
                     let runtime = db.salsa_runtime();
-                    State::Memo(
-                        match std::mem::replace(&mut *state, QueryState::in_progress(runtime.id()))
-                        {
-                            QueryState::Memoized(old_memo) => Some(old_memo),
+                    match &mut *state {
+                        // We are iterating a cycle that this query is part
+                        // of, and it has to be executed again. Keep it in
+                        // progress, so that whoever waits for it keeps
+                        // waiting for the final value.
+                        QueryState::InProgress { provisional, .. } => match provisional.take() {
+                            Some(Provisional::Stale { old_memo, reason }) => {
+                                State::Memo(old_memo, reason, false)
+                            }
+                            _ => unreachable!(),
+                        },
+                        _ => match std::mem::replace(
+                            &mut *state,
+                            QueryState::in_progress(runtime.id()),
+                        ) {
+                            QueryState::Memoized(old_memo) => {
+                                State::Memo(Some(old_memo), ExecuteReason::NotComputed, true)
+                            }
                             QueryState::InProgress { .. } => unreachable!(),
-                            QueryState::NotComputed => None,
+                            QueryState::NotComputed(reason) => State::Memo(None, reason, true),
                         },
-                    )
+                    }
                 }
             };
 
             match state {
                 State::Wait(future, other_id) => {
-                    return self.wait_for_value(db, other_id, future).await;
+                    if let Some(result) = self.wait_for_value(db, other_id, future).await {
+                        return result;
+                    }
                 }
-                State::Memo(x) => x,
+                State::Memo(memo, reason, validate) => break (memo, reason, validate),
             }
         };
 
@@ -255,54 +436,147 @@ where
         // has been a new revision since the last time we checked. So,
         // first things first, let's walk over each of our previous
         // inputs and check whether they are out of date.
-        if let Some(memo) = &mut panic_guard.memo {
-            if let Some(value) = memo.validate_memoized_value(db, revision_now).await {
-                info!("{:?}: validated old memoized value", self,);
-
-                let runtime = db.salsa_runtime();
+        let mut reason = match &mut panic_guard.memo {
+            Some(memo) if validate => match note_cancellation_async(&panic_guard.cancelled, || {
+                self.validate_memo(memo, db, revision_now)
+            })
+            .await
+            {
+                Ok(value) => {
+                    info!("{:?}: validated old memoized value", self,);
 
-                db.salsa_event(Event {
-                    runtime_id: runtime.id(),
-                    kind: EventKind::DidValidateMemoizedValue {
-                        database_key: self.database_key_index,
-                    },
-                });
+                    profile.record_validation();
+                    let runtime = db.salsa_runtime();
+                    runtime.verify_dependent(self.database_key_index, revision_now);
 
-                panic_guard.proceed(
-                    &value,
-                    // The returned value could have been produced as part of a cycle but since
-                    // we returned the memoized value we know we short-circuited the execution
-                    // just as we entered the cycle. Therefore there is no values to invalidate
-                    // and no need to call a cycle handler so we do not need to return the
-                    // actual cycle
-                    Vec::new(),
-                );
+                    db.salsa_event(Event {
+                        runtime_id: runtime.id(),
+                        kind: EventKind::DidValidateMemoizedValue {
+                            database_key: self.database_key_index,
+                        },
+                    });
+
+                    panic_guard.proceed(
+                        &value,
+                        // The returned value could have been produced as part of a cycle but since
+                        // we returned the memoized value we know we short-circuited the execution
+                        // just as we entered the cycle. Therefore there is no values to invalidate
+                        // and no need to call a cycle handler so we do not need to return the
+                        // actual cycle
+                        Vec::new(),
+                    );
 
-                return Ok(value);
-            }
+                    return Ok(value);
+                }
+                Err(reason) => reason,
+            },
+            _ => not_computed,
+        };
+        if reason == ExecuteReason::Evicted {
+            evicted_executions.fetch_add(1, Ordering::Relaxed);
         }
+        let executed_because = reason;
 
         // Query was not previously executed, or value is potentially
         // stale, or value is absent. Let's execute!
-        let mut result = {
-            let active_query = Runtime::prepare_query_implementation(db, self.database_key_index);
+        let mut executions = 0;
+        let mut is_head = false;
+        let mut result = loop {
+            let result = {
+                let active_query = Runtime::prepare_query_implementation(
+                    db,
+                    self.database_key_index,
+                    reason,
+                    fixpoint.is_some(),
+                );
+
+                info!("{:?}: executing query", self);
+
+                // Execute user's code, accumulating inputs etc.
+                let span = key_span!(
+                    "execute",
+                    active_query.db,
+                    self.database_key_index,
+                    revision_now
+                );
+                let value = note_cancellation_async(&panic_guard.cancelled, || {
+                    Q::execute(active_query.db, self.key.clone()).instrument(span)
+                })
+                .await;
 
-            info!("{:?}: executing query", self);
+                Runtime::complete_query(active_query, value)
+            };
+            profile.record_execution(&result.timer);
+            executions += 1;
+
+            // If we are the head of a cycle that is iterated to a
+            // fixpoint, execute the cycle again with our new value until
+            // it stops changing.
+            let fixpoint = match fixpoint {
+                Some(fixpoint) if result.provisional_heads.contains(&self.database_key_index) => {
+                    fixpoint
+                }
+                _ => break result,
+            };
+            is_head = true;
+            if self.has_converged(fixpoint, &result.value) {
+                debug!(
+                    "{:?}: cycle converged after {} executions",
+                    self, executions
+                );
+                break result;
+            }
 
-            // Execute user's code, accumulating inputs etc.
-            let value = Q::execute(active_query.db, self.key.clone()).await;
+            let runtime = db.salsa_runtime();
+            if executions >= fixpoint.max_iterations {
+                let participants = runtime.take_cycle_participants(self.database_key_index);
+                update_participants(
+                    &**db,
+                    &participants,
+                    CycleUpdate::Discard { panicked: false },
+                );
+                let cycle: Vec<_> = std::iter::once(self.database_key_index)
+                    .chain(participants)
+                    .collect();
+                let err = CycleError {
+                    runtimes: vec![runtime.id(); cycle.len()],
+                    cycle,
+                    durability: result.durability,
+                    changed_at: result.changed_at,
+                };
+                panic_guard.report_unexpected_cycle();
+                return Err(err);
+            }
 
-            Runtime::complete_query(active_query, value)
+            debug!("{:?}: cycle did not converge, executing it again", self);
+            if let QueryState::InProgress { provisional, .. } = &mut *self.state.write() {
+                *provisional = Some(Provisional::Head(result.value));
+            }
+            let participants = runtime.cycle_participants(self.database_key_index);
+            update_participants(
+                &**db,
+                &participants,
+                CycleUpdate::Reset {
+                    head: self.database_key_index,
+                },
+            );
+            reason = ExecuteReason::InputChanged {
+                input: self.database_key_index,
+            };
         };
 
         let runtime = db.salsa_runtime();
 
         if !result.cycle.is_empty() {
-            result.value = match Q::recover(db, &result.cycle, &self.key) {
+            let recovered = note_cancellation(&panic_guard.cancelled, || {
+                Q::recover(db, &result.cycle, &self.key)
+            });
+            result.value = match recovered {
                 Some(v) => v,
                 None => {
                     let err = CycleError {
                         cycle: result.cycle,
+                        runtimes: Vec::new(),
                         durability: result.durability,
                         changed_at: result.changed_at,
                     };
@@ -351,10 +625,10 @@ where
             changed_at: result.changed_at,
         };
 
-        let value = if self.should_memoize_value(&self.key) {
-            Some(new_value.value.clone())
+        let (value, discarded) = if self.should_memoize_value(&self.key) {
+            (Some(new_value.value.clone()), None)
         } else {
-            None
+            (None, Some(ExecuteReason::NotMemoized))
         };
 
         debug!(
@@ -363,7 +637,41 @@ where
             self, result.changed_at, result.durability, result.dependencies,
         );
 
-        let inputs = match result.dependencies {
+        // The inputs of the queries in a cycle include each other, so
+        // validating one of them later on would run into the cycle. The
+        // value of each query in the cycle only depends on the inputs of
+        // the cycle as a whole, so that is what they are memoized with.
+        result
+            .provisional_heads
+            .retain(|&head| head != self.database_key_index);
+        let in_cycle = is_head || !result.provisional_heads.is_empty();
+        let cycle_inputs = match (&result.dependencies, result.cycle_inputs) {
+            (Some(dependencies), Some(mut cycle_inputs)) if in_cycle => {
+                cycle_inputs.extend(dependencies.iter().copied());
+                Some(cycle_inputs)
+            }
+            _ => None,
+        };
+        let mut participants = Vec::new();
+        let dependencies = if is_head && result.provisional_heads.is_empty() {
+            participants = runtime.take_cycle_participants(self.database_key_index);
+            let members: FxHashSet<_> = participants
+                .iter()
+                .copied()
+                .chain(Some(self.database_key_index))
+                .collect();
+            cycle_inputs.as_ref().map(|cycle_inputs| {
+                cycle_inputs
+                    .iter()
+                    .copied()
+                    .filter(|input| !members.contains(input))
+                    .collect()
+            })
+        } else {
+            result.dependencies
+        };
+
+        let inputs = match dependencies {
             None => MemoInputs::Untracked,
 
             Some(dependencies) => {
@@ -378,21 +686,92 @@ where
         };
         debug!("read_upgrade({:?}): inputs={:?}", self, inputs);
 
-        panic_guard.memo = Some(Memo {
+        let memo = Memo {
             value,
+            discarded,
+            executed_because: Some(executed_because),
             revisions: MemoRevisions {
                 changed_at: result.changed_at,
                 verified_at: revision_now,
                 inputs,
                 durability: result.durability,
             },
-        });
+        };
 
+        // Our value depends on the provisional value of a cycle that is
+        // still being iterated, so it is not final yet.
+        if !result.provisional_heads.is_empty() {
+            debug!(
+                "read_upgrade({:?}): provisional value depending on {:?}",
+                self, result.provisional_heads
+            );
+            runtime.complete_provisional(
+                self.database_key_index,
+                result.provisional_heads,
+                cycle_inputs.as_ref(),
+            );
+            panic_guard.proceed_provisionally(new_value.clone(), memo);
+            return Ok(new_value);
+        }
+        runtime.forget_cycle_participant(self.database_key_index);
+
+        if is_head {
+            update_participants(
+                &**db,
+                &participants,
+                CycleUpdate::Memoize {
+                    inputs: match &memo.revisions.inputs {
+                        MemoInputs::Tracked { inputs } => Some(inputs.clone()),
+                        MemoInputs::NoInputs => Some(Vec::new().into()),
+                        MemoInputs::Untracked => None,
+                    },
+                    durability: result.durability,
+                },
+            );
+        }
+
+        let old_inputs = match &panic_guard.memo {
+            Some(old_memo) => old_memo.revisions.inputs.tracked(),
+            None => &[],
+        };
+        runtime.update_dependents(
+            self.database_key_index,
+            old_inputs,
+            memo.revisions.inputs.tracked(),
+        );
+
+        panic_guard.memo = Some(memo);
         panic_guard.proceed(&new_value, result.cycle);
 
         Ok(new_value)
     }
 
+    /// Whether `value`, which this query computed as the head of a
+    /// cycle, is the same as the provisional value it started from.
+    fn has_converged(&self, fixpoint: Fixpoint<Q>, value: &Q::Value) -> bool {
+        match &*self.state.read() {
+            QueryState::InProgress {
+                provisional: Some(Provisional::Head(provisional)),
+                ..
+            } => MP::memoized_value_eq(provisional, value),
+            _ => MP::memoized_value_eq(&(fixpoint.initial)(&self.key), value),
+        }
+    }
+
+    /// Validates `memo` for the current revision, recording the
+    /// validation in the trace if one is being recorded.
+    async fn validate_memo(
+        &self,
+        memo: &mut Memo<Q>,
+        db: &mut <Q as QueryDb<'_>>::Db,
+        revision_now: Revision,
+    ) -> Result<StampedValue<Q::Value>, ExecuteReason> {
+        let _span = db
+            .salsa_runtime()
+            .trace_span("validate", || format!("{}({:?})", Q::QUERY_NAME, self.key));
+        memo.validate_memoized_value(db, revision_now).await
+    }
+
     /// Helper for `read` that does a shallow check (not recursive) if we have an up-to-date value.
     ///
     /// Invoked with the guard `state` corresponding to the `QueryState` of some `Slot` (the guard
@@ -409,11 +788,13 @@ where
         db: &mut <Q as QueryDb<'_>>::Db,
         state: StateGuard,
         revision_now: Revision,
+        profile: &QueryProfile,
+        fixpoint: Option<Fixpoint<Q>>,
     ) -> ProbeState<StampedValue<Q::Value>, DatabaseKeyIndex, StateGuard, Q::BlockingFuture>
     where
         StateGuard: Deref<Target = QueryState<Q>>,
     {
-        match self.probe_inner(db, &state, revision_now) {
+        match self.probe_inner(db, &state, revision_now, profile, fixpoint) {
             ProbeState::Pending(future, other_id) => ProbeState::Pending(future, other_id),
             ProbeState::UpToDate(v) => ProbeState::UpToDate(v),
             ProbeState::StaleOrAbsent(()) => ProbeState::StaleOrAbsent(state),
@@ -425,12 +806,30 @@ where
         db: &mut <Q as QueryDb<'_>>::Db,
         state: &QueryState<Q>,
         revision_now: Revision,
+        profile: &QueryProfile,
+        fixpoint: Option<Fixpoint<Q>>,
     ) -> ProbeState<StampedValue<Q::Value>, DatabaseKeyIndex, (), Q::BlockingFuture> {
         match state {
-            QueryState::NotComputed => { /* fall through */ }
+            QueryState::NotComputed(_) => { /* fall through */ }
 
-            QueryState::InProgress { id, waiting } => {
+            QueryState::InProgress {
+                id,
+                waiting,
+                provisional,
+            } => {
                 let other_id = *id;
+                if other_id == db.salsa_runtime().id() {
+                    if let Some(Provisional::Stale { .. }) = provisional {
+                        return ProbeState::StaleOrAbsent(());
+                    }
+                    if let Some(value) =
+                        self.probe_provisional(db, provisional, revision_now, fixpoint)
+                    {
+                        info!("{:?}: returning provisional value", self);
+                        return ProbeState::UpToDate(Ok(value));
+                    }
+                }
+
                 let result = self.register_with_in_progress_thread(
                     db,
                     db.salsa_runtime(),
@@ -441,11 +840,21 @@ where
                     Ok(future) => ProbeState::Pending(future, other_id),
 
                     Err(err) => {
+                        if other_id == db.salsa_runtime().id() {
+                            self.check_cycle_fixpoints(db);
+                        }
                         let err = db.salsa_runtime().report_unexpected_cycle(
                             self.database_key_index,
                             err,
                             revision_now,
                         );
+                        db.salsa_event(Event {
+                            runtime_id: db.salsa_runtime().id(),
+                            kind: EventKind::DidDetectCycle {
+                                database_key: self.database_key_index,
+                                cycle: err.cycle.clone(),
+                            },
+                        });
                         ProbeState::UpToDate(
                             Q::recover(db, &err.cycle, &self.key)
                                 .map(|value| StampedValue {
@@ -477,6 +886,7 @@ where
                             "{:?}: returning memoized value changed at {:?}",
                             self, value.changed_at
                         );
+                        profile.record_hit();
 
                         return ProbeState::UpToDate(Ok(value));
                     }
@@ -487,12 +897,69 @@ where
         ProbeState::StaleOrAbsent(())
     }
 
+    /// Helper for `probe_inner`, for a query that this runtime is
+    /// executing: reads its provisional value if it takes part in a cycle
+    /// that is iterated to a fixpoint. Returns `None` if it does not, and
+    /// we ran into an ordinary cycle instead.
+    fn probe_provisional(
+        &self,
+        db: &<Q as QueryDb<'_>>::DynDb,
+        provisional: &Option<Provisional<Q>>,
+        revision_now: Revision,
+        fixpoint: Option<Fixpoint<Q>>,
+    ) -> Option<StampedValue<Q::Value>> {
+        let runtime = db.salsa_runtime();
+        match provisional {
+            Some(Provisional::Completed { value, .. }) => {
+                runtime.report_participant_read(self.database_key_index);
+                Some(value.clone())
+            }
+            Some(Provisional::Stale { .. }) => None,
+            Some(Provisional::Head(_)) | None => {
+                let fixpoint = fixpoint?;
+                if !runtime.report_provisional_read(self.database_key_index) {
+                    return None;
+                }
+                self.check_cycle_fixpoints(db);
+                let value = match provisional {
+                    Some(Provisional::Head(value)) => value.clone(),
+                    _ => (fixpoint.initial)(&self.key),
+                };
+
+                // The value changes with every iteration, and whatever
+                // read it can be no more durable than its actual inputs.
+                Some(StampedValue {
+                    value,
+                    changed_at: revision_now,
+                    durability: Durability::MAX,
+                })
+            }
+        }
+    }
+
+    /// Panics if the cycle that we just ran into is iterated to a
+    /// fixpoint by some of its queries but not by others. Which queries
+    /// are configured would otherwise decide whether the cycle fails,
+    /// depending on the query it is entered through.
+    fn check_cycle_fixpoints(&self, db: &<Q as QueryDb<'_>>::DynDb) {
+        let runtime = db.salsa_runtime();
+        if let Some(query) = runtime.cycle_query_without_fixpoint(self.database_key_index) {
+            panic!(
+                "{:?} is part of a cycle that is iterated to a fixpoint, \
+                 but `set_cycle_fixpoint` was not called for it",
+                query.debug(db)
+            );
+        }
+    }
+
+    /// Waits for the value that another runtime is computing. Returns
+    /// `None` if the value has to be read again instead.
     async fn wait_for_value(
         &self,
         db: &mut <Q as QueryDb<'_>>::Db,
         other_id: RuntimeId,
         future: Q::BlockingFuture,
-    ) -> Result<StampedValue<Q::Value>, CycleError<DatabaseKeyIndex>> {
+    ) -> Option<Result<StampedValue<Q::Value>, CycleError<DatabaseKeyIndex>>> {
         db.salsa_event(Event {
             runtime_id: db.salsa_runtime().id(),
             kind: EventKind::WillBlockOn {
@@ -501,14 +968,19 @@ where
             },
         });
 
-        let result = future.await.unwrap_or_else(|| db.on_propagated_panic());
-        if result.cycle.is_empty() {
-            Ok(result.value)
+        let result = future.await.unwrap_or_else(|| {
+            crate::cancellation::unwind_if_cancelled(&**db);
+            db.on_propagated_panic()
+        });
+        let value = result.value?;
+        Some(if result.cycle.is_empty() {
+            Ok(value)
         } else {
             let err = CycleError {
                 cycle: result.cycle,
-                changed_at: result.value.changed_at,
-                durability: result.value.durability,
+                runtimes: Vec::new(),
+                changed_at: value.changed_at,
+                durability: value.durability,
             };
             db.salsa_runtime().mark_cycle_participants(&err.cycle);
             Q::recover(db, &err.cycle, &self.key)
@@ -518,12 +990,12 @@ where
                     changed_at: err.changed_at,
                 })
                 .ok_or_else(|| err)
-        }
+        })
     }
 
     pub(super) fn durability(&self, db: &<Q as QueryDb<'_>>::DynDb) -> Durability {
         match &*self.state.read() {
-            QueryState::NotComputed => Durability::LOW,
+            QueryState::NotComputed(_) => Durability::LOW,
             QueryState::InProgress { .. } => panic!("query in progress"),
             QueryState::Memoized(memo) => {
                 if memo.revisions.check_durability(db.salsa_runtime()) {
@@ -537,7 +1009,7 @@ where
 
     pub(super) fn as_table_entry(&self) -> Option<TableEntry<Q::Key, Q::Value>> {
         match &*self.state.read() {
-            QueryState::NotComputed => None,
+            QueryState::NotComputed(_) => None,
             QueryState::InProgress { .. } => Some(TableEntry::new(self.key.clone(), None)),
             QueryState::Memoized(memo) => {
                 Some(TableEntry::new(self.key.clone(), memo.value.clone()))
@@ -545,7 +1017,8 @@ where
         }
     }
 
-    pub(super) fn evict(&self) {
+    /// Discards the memoized value.
+    pub(super) fn evict(&self) -> Eviction {
         let mut state = self.state.write();
         if let QueryState::Memoized(memo) = &mut *state {
             // Similar to GC, evicting a value with an untracked input could
@@ -553,22 +1026,62 @@ where
             // `has_untracked_input` when we add the value to the cache,
             // because inputs can become untracked in the next revision.
             if memo.revisions.has_untracked_input() {
-                return;
+                return Eviction::Kept;
+            }
+            if memo.value.take().is_some() {
+                memo.discarded = Some(ExecuteReason::Evicted);
+                return Eviction::Evicted;
             }
-            memo.value = None;
         }
+        Eviction::Empty
     }
 
-    pub(super) fn sweep(&self, revision_now: Revision, strategy: SweepStrategy) {
+    /// Like `evict`, but a memo that read an untracked input is
+    /// discarded entirely instead of being kept. That is only done once
+    /// a new revision has started since the memo was verified, as
+    /// executing the query again in the same revision could produce a
+    /// different value.
+    pub(super) fn evict_memo(&self, runtime: &Runtime) -> Eviction {
+        let revision_now = runtime.current_revision();
+        let mut state = self.state.write();
+        let memo = match &mut *state {
+            QueryState::Memoized(memo) => memo,
+            QueryState::NotComputed(_) | QueryState::InProgress { .. } => return Eviction::Empty,
+        };
+        if !memo.revisions.has_untracked_input() {
+            if memo.value.take().is_none() {
+                return Eviction::Empty;
+            }
+            memo.discarded = Some(ExecuteReason::Evicted);
+            return Eviction::Evicted;
+        }
+        if memo.revisions.verified_at == revision_now {
+            return Eviction::Kept;
+        }
+
+        runtime.update_dependents(
+            self.database_key_index,
+            memo.revisions.inputs.tracked(),
+            &[],
+        );
+        *state = QueryState::NotComputed(ExecuteReason::Evicted);
+        Eviction::Evicted
+    }
+
+    /// Discards the memo or its value according to `strategy`, returning
+    /// the event to report if something was discarded.
+    pub(super) fn sweep(&self, runtime: &Runtime, strategy: SweepStrategy) -> Option<EventKind> {
+        let revision_now = runtime.current_revision();
         let mut state = self.state.write();
         match &mut *state {
-            QueryState::NotComputed => (),
+            QueryState::NotComputed(_) => None,
 
             // Leave stuff that is currently being computed -- the
             // other thread doing that work has unique access to
             // this slot and we should not interfere.
             QueryState::InProgress { .. } => {
                 debug!("sweep({:?}): in-progress", self);
+                None
             }
 
             // Otherwise, drop only value or the whole memo according to the
@@ -602,22 +1115,39 @@ where
 
                     // If we are only discarding outdated things,
                     // and this is not outdated, keep it.
-                    DiscardIf::Outdated if memo.revisions.verified_at == revision_now => (),
+                    DiscardIf::Outdated if memo.revisions.verified_at == revision_now => None,
 
                     // As explained on the `has_untracked_input` variable
                     // definition, if this is a volatile entry, we
                     // can't discard it unless it is outdated.
                     DiscardIf::Always
-                        if has_untracked_input && memo.revisions.verified_at == revision_now => {}
+                        if has_untracked_input && memo.revisions.verified_at == revision_now =>
+                    {
+                        None
+                    }
 
                     // Otherwise, we can discard -- discard whatever the user requested.
                     DiscardIf::Outdated | DiscardIf::Always => match strategy.discard_what {
                         DiscardWhat::Nothing => unreachable!(),
                         DiscardWhat::Values => {
-                            memo.value = None;
+                            memo.value.take()?;
+                            memo.discarded = Some(ExecuteReason::Swept);
+                            Some(EventKind::DidSweep {
+                                database_key: self.database_key_index,
+                                memo_removed: false,
+                            })
                         }
                         DiscardWhat::Everything => {
-                            *state = QueryState::NotComputed;
+                            runtime.update_dependents(
+                                self.database_key_index,
+                                memo.revisions.inputs.tracked(),
+                                &[],
+                            );
+                            *state = QueryState::NotComputed(ExecuteReason::Swept);
+                            Some(EventKind::DidSweep {
+                                database_key: self.database_key_index,
+                                memo_removed: true,
+                            })
                         }
                     },
                 }
@@ -625,28 +1155,214 @@ where
         }
     }
 
-    pub(super) fn invalidate(&self) -> Option<Durability> {
+    /// Marks the memo as having untracked inputs, returning its
+    /// durability along with the inputs it used to have.
+    pub(super) fn invalidate(&self) -> Option<(Durability, MemoInputs)> {
         if let QueryState::Memoized(memo) = &mut *self.state.write() {
-            memo.revisions.inputs = MemoInputs::Untracked;
-            Some(memo.revisions.durability)
+            let inputs = std::mem::replace(&mut memo.revisions.inputs, MemoInputs::Untracked);
+            memo.discarded = Some(ExecuteReason::Invalidated);
+            Some((memo.revisions.durability, inputs))
         } else {
             None
         }
     }
 
+    /// Adds the memory used by this slot, apart from the copy of its key
+    /// in the slot map, to `memory`.
+    pub(super) fn add_memory_usage(
+        &self,
+        heap_size: &HeapSizeVtable<Q::Key, Q::Value>,
+        memory: &mut QueryMemory,
+    ) {
+        memory.slots += 1;
+        memory.bytes += ARC_HEADER + std::mem::size_of::<Self>() + (heap_size.key)(&self.key);
+        if let QueryState::Memoized(memo) = &*self.state.read() {
+            if let Some(value) = &memo.value {
+                memory.values += 1;
+                memory.bytes += (heap_size.value)(value);
+            }
+            if let MemoInputs::Tracked { inputs } = &memo.revisions.inputs {
+                memory.edges += inputs.len();
+                memory.bytes += ARC_HEADER + inputs.len() * std::mem::size_of::<DatabaseKeyIndex>();
+            }
+        }
+    }
+
+    /// The estimated size of this slot, as counted by
+    /// `add_memory_usage`. The estimate is cached until the memo is
+    /// replaced.
+    pub(super) fn weight(&self, heap_size: &HeapSizeVtable<Q::Key, Q::Value>) -> usize {
+        cached_weight(&self.weight, || {
+            let mut memory = QueryMemory::new(std::any::type_name::<Q::Group>(), Q::QUERY_NAME);
+            self.add_memory_usage(heap_size, &mut memory);
+            memory.bytes
+        })
+    }
+
+    /// The weight of the memoized value according to `weight`, or zero
+    /// if there is none. The weight is cached until the memo is
+    /// replaced.
+    pub(super) fn value_weight(&self, weight: fn(&Q::Value) -> usize) -> usize {
+        cached_weight(&self.value_weight, || match &*self.state.read() {
+            QueryState::Memoized(Memo {
+                value: Some(value), ..
+            }) => weight(value),
+            _ => 0,
+        })
+    }
+
+    /// Forgets the cached weight of the value, so that `value_weight`
+    /// computes it again.
+    pub(super) fn forget_value_weight(&self) {
+        self.value_weight.store(usize::MAX, Ordering::Release);
+    }
+
+    pub(super) fn graph_node(&self) -> Option<QueryGraphNode> {
+        match &*self.state.read() {
+            QueryState::Memoized(memo) => Some(QueryGraphNode::new(
+                self.database_key_index,
+                memo.revisions.durability,
+                memo.revisions.changed_at,
+                Some(memo.revisions.verified_at),
+                memo.value.is_some(),
+                match &memo.revisions.inputs {
+                    MemoInputs::Tracked { inputs } => Some(inputs.clone()),
+                    MemoInputs::NoInputs => Some(Vec::new().into()),
+                    MemoInputs::Untracked => None,
+                },
+            )),
+            QueryState::NotComputed(_) | QueryState::InProgress { .. } => None,
+        }
+    }
+
+    pub(super) fn explain(&self) -> Option<ExecuteReason> {
+        match &*self.state.read() {
+            QueryState::Memoized(memo) => memo.executed_because,
+            QueryState::NotComputed(_) | QueryState::InProgress { .. } => None,
+        }
+    }
+
+    /// Applies `update` to the provisional value of this query, if
+    /// `runtime` is iterating the cycle it is part of.
+    pub(super) fn update_provisional(&self, runtime: &Runtime, update: &CycleUpdate) {
+        let mut state = self.state.write();
+        let provisional = match &mut *state {
+            QueryState::InProgress {
+                id, provisional, ..
+            } if *id == runtime.id() => provisional,
+            _ => return,
+        };
+        let (new_state, value) = match (provisional.take(), update) {
+            (Some(Provisional::Completed { old_memo, .. }), CycleUpdate::Reset { head }) => {
+                *provisional = Some(Provisional::Stale {
+                    old_memo,
+                    reason: ExecuteReason::InputChanged { input: *head },
+                });
+                return;
+            }
+            (
+                Some(Provisional::Completed {
+                    mut value,
+                    mut memo,
+                    old_memo,
+                }),
+                CycleUpdate::Memoize { inputs, durability },
+            ) => {
+                memo.revisions.inputs = match inputs {
+                    Some(inputs) if inputs.is_empty() => MemoInputs::NoInputs,
+                    Some(inputs) => MemoInputs::Tracked {
+                        inputs: inputs.clone(),
+                    },
+                    None => MemoInputs::Untracked,
+                };
+                memo.revisions.durability = *durability;
+                value.durability = *durability;
+                let old_inputs = match &old_memo {
+                    Some(old_memo) => old_memo.revisions.inputs.tracked(),
+                    None => &[],
+                };
+                runtime.update_dependents(
+                    self.database_key_index,
+                    old_inputs,
+                    memo.revisions.inputs.tracked(),
+                );
+                (QueryState::Memoized(memo), Some(value))
+            }
+            (Some(Provisional::Completed { old_memo, .. }), _)
+            | (Some(Provisional::Stale { old_memo, .. }), _) => (
+                match old_memo {
+                    Some(old_memo) => QueryState::Memoized(old_memo),
+                    None => QueryState::NotComputed(ExecuteReason::NotComputed),
+                },
+                None,
+            ),
+            (head @ Some(Provisional::Head(_)), _) | (head @ None, _) => {
+                *provisional = head;
+                return;
+            }
+        };
+        debug!("{:?}: finished provisional value, {:?}", self, update);
+
+        self.weight.store(usize::MAX, Ordering::Release);
+        self.value_weight.store(usize::MAX, Ordering::Release);
+        let waiting = match std::mem::replace(&mut *state, new_state) {
+            QueryState::InProgress { waiting, .. } => waiting,
+            _ => unreachable!(),
+        };
+        runtime.unblock_queries_blocked_on_self(Some(self.database_key_index));
+        match update {
+            // Drop the promises, so that the panic propagates to the
+            // runtimes waiting for us.
+            CycleUpdate::Discard { panicked: true } => std::mem::drop(waiting),
+            _ => {
+                for promise in waiting.into_inner() {
+                    promise.fulfil(WaitResult {
+                        value: value.clone(),
+                        cycle: Vec::new(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Adds the inputs of the current memo to the reverse dependency
+    /// index.
+    pub(super) fn index_dependents(&self, runtime: &Runtime) {
+        if let QueryState::Memoized(memo) = &*self.state.read() {
+            runtime.update_dependents(
+                self.database_key_index,
+                &[],
+                memo.revisions.inputs.tracked(),
+            );
+            runtime.verify_dependent(self.database_key_index, memo.revisions.verified_at);
+        }
+    }
+
     pub(super) async fn maybe_changed_since(
         &self,
         db: &mut <Q as QueryDb<'_>>::Db,
         revision: Revision,
+        profile: &QueryProfile,
+        evicted_executions: &AtomicU64,
+        fixpoint: Option<Fixpoint<Q>>,
     ) -> bool {
         match self.maybe_changed_since_inner(db, revision) {
             MaybeChangedSinceState::Done(b) => b,
             MaybeChangedSinceState::Wait(future) => {
-                let result = future.await.unwrap_or_else(|| db.on_propagated_panic());
-                !result.cycle.is_empty() || result.value.changed_at > revision
+                let result = future.await.unwrap_or_else(|| {
+                    crate::cancellation::unwind_if_cancelled(&**db);
+                    db.on_propagated_panic()
+                });
+                match result.value {
+                    Some(value) => !result.cycle.is_empty() || value.changed_at > revision,
+                    None => true,
+                }
             }
             MaybeChangedSinceState::Read(revision_now) => {
-                match self.read_upgrade(db, revision_now).await {
+                match self
+                    .read_upgrade(db, revision_now, profile, evicted_executions, fixpoint)
+                    .await
+                {
                     Ok(v) => {
                         debug!(
                                     "maybe_changed_since({:?}: {:?} since (recomputed) value changed at {:?}",
@@ -661,13 +1377,16 @@ where
             }
             MaybeChangedSinceState::Check(inputs, revision_now) => {
                 // Iterate the inputs and see if any have maybe changed.
-                let mut maybe_changed = false;
+                let span = db
+                    .salsa_runtime()
+                    .trace_span("validate", || format!("{}({:?})", Q::QUERY_NAME, self.key));
+                let mut changed_input = None;
                 match db.as_async_db() {
                     Some(db) => {
                         for &input in &inputs[..] {
                             if db.maybe_changed_since_async(input, revision).await {
                                 debug!("{:?}: input `{:?}` may have changed", self, input);
-                                maybe_changed = true;
+                                changed_input = Some(input);
                                 break;
                             }
                         }
@@ -676,14 +1395,15 @@ where
                         for &input in &inputs[..] {
                             if db.maybe_changed_since(input, revision) {
                                 debug!("{:?}: input `{:?}` may have changed", self, input);
-                                maybe_changed = true;
+                                changed_input = Some(input);
                                 break;
                             }
                         }
                     }
                 }
-                self.maybe_changed_since_update(maybe_changed, revision_now);
-                maybe_changed
+                drop(span);
+                self.maybe_changed_since_update(db.salsa_runtime(), changed_input, revision_now);
+                changed_input.is_some()
             }
         }
     }
@@ -710,7 +1430,7 @@ where
             // If somebody depends on us, but we have no map
             // entry, that must mean that it was found to be out
             // of date and removed.
-            QueryState::NotComputed => {
+            QueryState::NotComputed(_) => {
                 debug!("maybe_changed_since({:?}: no value", self);
                 return MaybeChangedSinceState::Done(true);
             }
@@ -718,7 +1438,7 @@ where
             // This value is being actively recomputed. Wait for
             // that thread to finish (assuming it's not dependent
             // on us...) and check its associated revision.
-            QueryState::InProgress { id, waiting } => {
+            QueryState::InProgress { id, waiting, .. } => {
                 let other_id = *id;
                 debug!(
                     "maybe_changed_since({:?}: blocking on thread `{:?}`",
@@ -750,14 +1470,11 @@ where
             return MaybeChangedSinceState::Done(memo.revisions.changed_at > revision);
         }
 
-        let maybe_changed;
-
         // If we only depended on constants, and no constant has been
         // modified since then, we cannot have changed; no need to
         // trace our inputs.
         if memo.revisions.check_durability(runtime) {
             std::mem::drop(state);
-            maybe_changed = false;
         } else {
             match &memo.revisions.inputs {
                 MemoInputs::Untracked => {
@@ -774,7 +1491,6 @@ where
 
                 MemoInputs::NoInputs => {
                     std::mem::drop(state);
-                    maybe_changed = false;
                 }
 
                 MemoInputs::Tracked { inputs } => {
@@ -799,11 +1515,16 @@ where
                 }
             }
         }
-        self.maybe_changed_since_update(maybe_changed, revision_now);
-        MaybeChangedSinceState::Done(maybe_changed)
+        self.maybe_changed_since_update(db.salsa_runtime(), None, revision_now);
+        MaybeChangedSinceState::Done(false)
     }
 
-    fn maybe_changed_since_update(&self, maybe_changed: bool, revision_now: Revision) {
+    fn maybe_changed_since_update(
+        &self,
+        runtime: &Runtime,
+        changed_input: Option<DatabaseKeyIndex>,
+        revision_now: Revision,
+    ) {
         // Either way, we have to update our entry.
         //
         // Keep in mind, though, that we released the lock before checking the ipnuts and a lot
@@ -826,16 +1547,22 @@ where
                     // less efficient? (It may cause some
                     // downstream value to be recomputed that
                     // wouldn't otherwise have to be?)
-                } else if maybe_changed {
+                } else if let Some(input) = changed_input {
                     // We found this entry is out of date and
                     // nobody touch it in the meantime. Just
                     // remove it.
-                    *state = QueryState::NotComputed;
+                    runtime.update_dependents(
+                        self.database_key_index,
+                        memo.revisions.inputs.tracked(),
+                        &[],
+                    );
+                    *state = QueryState::NotComputed(ExecuteReason::InputChanged { input });
                 } else {
                     // We found this entry is valid. Update the
                     // `verified_at` to reflect the current
                     // revision.
                     memo.revisions.verified_at = revision_now;
+                    runtime.verify_dependent(self.database_key_index, revision_now);
                 }
             }
 
@@ -846,7 +1573,7 @@ where
                 // whatever `maybe_changed` value we computed.
             }
 
-            QueryState::NotComputed => {
+            QueryState::NotComputed(_) => {
                 // Since we started verifying inputs, somebody
                 // else has come along and removed this value. The
                 // GC can do this, for example. That's fine.
@@ -902,6 +1629,7 @@ where
         QueryState::InProgress {
             id,
             waiting: Default::default(),
+            provisional: None,
         }
     }
 }
@@ -917,6 +1645,8 @@ where
     slot: &'me Slot<Q, MP>,
     memo: Option<Memo<Q>>,
     db: &'db mut DB,
+    /// Whether the query unwinds with `Cancelled` rather than a panic.
+    cancelled: AtomicBool,
 }
 
 impl<'me, 'db, Q, MP, DB> PanicGuard<'me, 'db, Q, MP, DB>
@@ -937,6 +1667,7 @@ where
             slot,
             memo,
             db,
+            cancelled: AtomicBool::new(false),
         }
     }
 
@@ -953,6 +1684,24 @@ where
         std::mem::forget(self)
     }
 
+    /// Like `proceed`, for a value that depends on the provisional value
+    /// of a cycle. The query stays in progress until the head of the
+    /// cycle memoizes or discards `memo`.
+    fn proceed_provisionally(mut self, value: StampedValue<Q::Value>, memo: Memo<Q>) {
+        let old_memo = self.memo.take();
+        match &mut *self.slot.state.write() {
+            QueryState::InProgress { provisional, .. } => {
+                *provisional = Some(Provisional::Completed {
+                    value,
+                    memo,
+                    old_memo,
+                })
+            }
+            _ => unreachable!(),
+        }
+        std::mem::forget(self)
+    }
+
     /// Overwrites the `InProgress` placeholder for `key` that we
     /// inserted; if others were blocked, waiting for us to finish,
     /// then notify them.
@@ -965,16 +1714,23 @@ where
         let old_value = match self.memo.take() {
             // Replace the `InProgress` marker that we installed with the new
             // memo, thus releasing our unique access to this key.
-            Some(memo) => std::mem::replace(&mut *write, QueryState::Memoized(memo)),
+            Some(memo) => {
+                self.slot.weight.store(usize::MAX, Ordering::Release);
+                self.slot.value_weight.store(usize::MAX, Ordering::Release);
+                std::mem::replace(&mut *write, QueryState::Memoized(memo))
+            }
 
             // We had installed an `InProgress` marker, but we panicked before
             // it could be removed. At this point, we therefore "own" unique
             // access to our slot, so we can just remove the key.
-            None => std::mem::replace(&mut *write, QueryState::NotComputed),
+            None => std::mem::replace(
+                &mut *write,
+                QueryState::NotComputed(ExecuteReason::NotComputed),
+            ),
         };
 
         match old_value {
-            QueryState::InProgress { id, waiting } => {
+            QueryState::InProgress { id, waiting, .. } => {
                 let runtime = self.db.salsa_runtime();
                 assert_eq!(id, runtime.id());
 
@@ -986,12 +1742,23 @@ where
                     Some((new_value, ref cycle)) => {
                         for promise in waiting.into_inner() {
                             promise.fulfil(WaitResult {
-                                value: new_value.clone(),
+                                value: Some(new_value.clone()),
                                 cycle: cycle.clone(),
                             });
                         }
                     }
 
+                    // Our runtime alone was cancelled, so those waiting
+                    // on us have to execute the query themselves.
+                    None if *self.cancelled.get_mut() && runtime.is_token_cancelled() => {
+                        for promise in waiting.into_inner() {
+                            promise.fulfil(WaitResult {
+                                value: None,
+                                cycle: Vec::new(),
+                            });
+                        }
+                    }
+
                     // We have no value to send when we are panicking.
                     // Therefore, we need to drop the sending half of the
                     // channel so that our panic propagates to those waiting
@@ -1018,8 +1785,19 @@ where
 {
     fn drop(&mut self) {
         if std::thread::panicking() {
-            // We panicked before we could proceed and need to remove `key`.
-            self.overwrite_placeholder(None)
+            // We panicked before we could proceed and need to remove `key`,
+            // along with the provisional values that depend on it if it is
+            // the head of a cycle.
+            self.overwrite_placeholder(None);
+            let runtime = self.db.salsa_runtime();
+            let participants = runtime.take_cycle_participants(self.database_key_index);
+            update_participants(
+                &**self.db,
+                &participants,
+                CycleUpdate::Discard {
+                    panicked: !(*self.cancelled.get_mut() && runtime.is_token_cancelled()),
+                },
+            );
         } else {
             // If no panic occurred, then panic guard ought to be
             // "forgotten" and so this Drop code should never run.
@@ -1028,6 +1806,21 @@ where
     }
 }
 
+/// Applies `update` to the provisional values of `participants`.
+fn update_participants<DB>(db: &DB, participants: &[DatabaseKeyIndex], update: CycleUpdate)
+where
+    DB: ?Sized + Database,
+{
+    if participants.is_empty() {
+        return;
+    }
+    let runtime = db.salsa_runtime();
+    let update = ProvisionalUpdate(update);
+    db.for_each_query(&mut |query_storage| {
+        query_storage.update_provisional(runtime, participants, &update)
+    });
+}
+
 impl<Q> Memo<Q>
 where
     for<'f, 'd> Q: QueryFunction<'f, 'd>,
@@ -1036,31 +1829,151 @@ where
         &mut self,
         db: &mut <Q as QueryDb<'_>>::Db,
         revision_now: Revision,
-    ) -> Option<StampedValue<Q::Value>> {
+    ) -> Result<StampedValue<Q::Value>, ExecuteReason> {
         // If we don't have a memoized value, nothing to validate.
+        if let Some(reason) = self.discarded {
+            return Err(reason);
+        }
         let value = match &self.value {
-            None => return None,
+            None => return Err(ExecuteReason::Evicted),
             Some(v) => v,
         };
 
-        if self
-            .revisions
+        self.revisions
             .validate_memoized_value(db, revision_now)
-            .await
-        {
-            Some(StampedValue {
-                durability: self.revisions.durability,
-                changed_at: self.revisions.changed_at,
-                value: value.clone(),
-            })
-        } else {
-            None
+            .await?;
+        Ok(StampedValue {
+            durability: self.revisions.durability,
+            changed_at: self.revisions.changed_at,
+            value: value.clone(),
+        })
+    }
+}
+
+impl<Q> Memo<Q>
+where
+    Q: QueryBase,
+{
+    fn persist(
+        &self,
+        db: &dyn Database,
+        vtable: &PersistVtable<Q::Key, Q::Value>,
+        out: &mut Vec<u8>,
+    ) {
+        match &self.value {
+            None => out.push(0),
+            Some(value) => {
+                out.push(1);
+                (vtable.persist_value)(value, out);
+            }
+        }
+        self.revisions.verified_at.persist(out);
+        self.revisions.changed_at.persist(out);
+        self.revisions.durability.persist(out);
+        self.revisions.inputs.persist(db, out);
+    }
+
+    fn restore(
+        vtable: &PersistVtable<Q::Key, Q::Value>,
+        tables: &PersistedTables,
+        input: &mut &[u8],
+    ) -> io::Result<Self> {
+        let value = match u8::restore(input)? {
+            0 => None,
+            1 => Some((vtable.restore_value)(input)?),
+            _ => return Err(invalid_data("invalid memoized value")),
+        };
+        let verified_at = Revision::restore(input)?;
+        let changed_at = Revision::restore(input)?;
+        let mut durability = Durability::restore(input)?;
+        let inputs = MemoInputs::restore(tables, input)?;
+
+        // The durability of a memo whose inputs can't be validated (say,
+        // because it read interned ids, which are always durable) would
+        // let it be reused without ever looking at them. Making it as
+        // volatile as can be gets it re-executed in the revision started
+        // by `persist::load`.
+        if let MemoInputs::Untracked = inputs {
+            durability = Durability::LOW;
+        }
+        Ok(Memo {
+            value,
+            discarded: None,
+            executed_because: None,
+            revisions: MemoRevisions {
+                verified_at,
+                changed_at,
+                durability,
+                inputs,
+            },
+        })
+    }
+}
+
+impl MemoInputs {
+    /// The inputs that were tracked, if any.
+    pub(super) fn tracked(&self) -> &[DatabaseKeyIndex] {
+        match self {
+            MemoInputs::Tracked { inputs } => inputs,
+            MemoInputs::NoInputs | MemoInputs::Untracked => &[],
+        }
+    }
+
+    fn persist(&self, db: &dyn Database, out: &mut Vec<u8>) {
+        match self {
+            MemoInputs::Untracked => out.push(0),
+            MemoInputs::NoInputs => out.push(1),
+            MemoInputs::Tracked { inputs } => {
+                // An input without a stable key (because its query is
+                // not persisted) can't be validated after loading, so
+                // the memo may as well have been untracked.
+                let inputs: Option<Vec<StableKey>> =
+                    inputs.iter().map(|input| input.stable_key(db)).collect();
+                match inputs {
+                    Some(inputs) => {
+                        out.push(2);
+                        inputs.persist(out);
+                    }
+                    None => out.push(0),
+                }
+            }
+        }
+    }
+
+    fn restore(tables: &PersistedTables, input: &mut &[u8]) -> io::Result<Self> {
+        match u8::restore(input)? {
+            0 => Ok(MemoInputs::Untracked),
+            1 => Ok(MemoInputs::NoInputs),
+            2 => {
+                let inputs = Vec::<StableKey>::restore(input)?;
+                if inputs.is_empty() {
+                    return Err(invalid_data("empty set of tracked inputs"));
+                }
+
+                // We can only validate inputs whose query is being
+                // restored as well; anything else has to be re-executed.
+                let inputs: Option<Vec<DatabaseKeyIndex>> =
+                    inputs.iter().map(|input| tables.resolve(input)).collect();
+                match inputs {
+                    Some(inputs) => Ok(MemoInputs::Tracked {
+                        inputs: inputs.into(),
+                    }),
+                    None => Ok(MemoInputs::Untracked),
+                }
+            }
+            _ => Err(invalid_data("invalid memo inputs")),
         }
     }
 }
 
 impl MemoRevisions {
-    async fn validate_memoized_value<DB>(&mut self, db: &mut DB, revision_now: Revision) -> bool
+    /// Checks whether the memo is still valid, marking it as verified
+    /// if so. Otherwise returns why it has to be re-executed.
+    async fn validate_memoized_value<DB>(
+        &mut self,
+        db: &mut DB,
+        revision_now: Revision,
+    ) -> Result<(), ExecuteReason>
     where
         DB: Deref + AsAsyncDatabase<<DB as Deref>::Target>,
         DB::Target: Database,
@@ -1071,14 +1984,15 @@ impl MemoRevisions {
         debug!("validate_memoized_value: verified_at={:#?}", self.inputs,);
 
         if self.check_durability(db.salsa_runtime()) {
-            return self.mark_value_as_verified(revision_now);
+            self.mark_value_as_verified(revision_now);
+            return Ok(());
         }
 
         match &self.inputs {
             // We can't validate values that had untracked inputs; just have to
             // re-execute.
             MemoInputs::Untracked { .. } => {
-                return false;
+                return Err(ExecuteReason::UntrackedRead);
             }
 
             MemoInputs::NoInputs => {}
@@ -1116,12 +2030,13 @@ impl MemoRevisions {
                 if let Some(input) = changed_input {
                     debug!("validate_memoized_value: `{:?}` may have changed", input);
 
-                    return false;
+                    return Err(ExecuteReason::InputChanged { input });
                 }
             }
         };
 
-        self.mark_value_as_verified(revision_now)
+        self.mark_value_as_verified(revision_now);
+        Ok(())
     }
 
     /// True if this memo is known not to have changed based on its durability.
@@ -1136,9 +2051,8 @@ impl MemoRevisions {
         last_changed <= self.verified_at
     }
 
-    fn mark_value_as_verified(&mut self, revision_now: Revision) -> bool {
+    fn mark_value_as_verified(&mut self, revision_now: Revision) {
         self.verified_at = revision_now;
-        true
     }
 
     fn has_untracked_input(&self) -> bool {
@@ -1171,6 +2085,18 @@ impl std::fmt::Debug for MemoInputs {
     }
 }
 
+/// Returns the weight in `cache`, computing it first if the cache was
+/// cleared by setting it to `usize::MAX`.
+fn cached_weight(cache: &AtomicUsize, compute: impl FnOnce() -> usize) -> usize {
+    let weight = cache.load(Ordering::Acquire);
+    if weight != usize::MAX {
+        return weight;
+    }
+    let weight = compute();
+    cache.store(weight, Ordering::Release);
+    weight
+}
+
 impl<Q, MP> LruNode for Slot<Q, MP>
 where
     Q: QueryFunctionBase,
@@ -1181,6 +2107,29 @@ where
     }
 }
 
+impl<Q, MP> BudgetNode for Slot<Q, MP>
+where
+    for<'f, 'd> Q: QueryFunction<'f, 'd>,
+    MP: MemoizationPolicy<Q>,
+    Self: Send + Sync,
+{
+    fn budget_index(&self) -> &LruIndex {
+        &self.budget_index
+    }
+
+    fn database_key_index(&self) -> DatabaseKeyIndex {
+        self.database_key_index
+    }
+
+    fn evict(&self, runtime: &Runtime) -> Eviction {
+        if self.evict_untracked.load(Ordering::Acquire) {
+            self.evict_memo(runtime)
+        } else {
+            Slot::evict(self)
+        }
+    }
+}
+
 /// Check that `Slot<Q, MP>: Send + Sync` as long as
 /// `DB::DatabaseData: Send + Sync`, which in turn implies that
 /// `Q::Key: Send + Sync`, `Q::Value: Send + Sync`.
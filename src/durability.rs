@@ -46,4 +46,9 @@ impl Durability {
     pub(crate) fn index(self) -> usize {
         self.0 as usize
     }
+
+    pub(crate) fn from_index(index: usize) -> Durability {
+        assert!(index < Self::LEN);
+        Durability(index as u8)
+    }
 }
@@ -0,0 +1,739 @@
+//! The policies that decide which memoized values of a derived query
+//! are evicted once its LRU capacity is reached.
+
+use crate::lru::{Lru, LruNode, LruStats};
+use parking_lot::Mutex;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Selects how the LRU list of a derived query picks the values to
+/// evict, see
+/// [`QueryTableMut::set_eviction_policy`](crate::QueryTableMut::set_eviction_policy).
+/// Other policies can be plugged in with a [`CustomEvictionPolicy`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum EvictionPolicy {
+    /// A randomized approximation of LRU, and the default. The most
+    /// recently used 10% of the values are never evicted and the
+    /// victims are picked at random among the least recently used 70%.
+    /// Using a value that is among the most recently used ones takes no
+    /// lock.
+    #[default]
+    ApproximateLru,
+
+    /// Exact LRU: always evicts the value that was used least recently,
+    /// so which values are kept only depends on the order they were
+    /// used in. Every use takes a lock.
+    Lru,
+
+    /// CLOCK, also known as second chance: the values are kept in a
+    /// ring that a hand sweeps for a value that was not used since the
+    /// hand last passed it. Using a value that is already in the ring
+    /// only sets a flag, which makes this the cheapest policy for large
+    /// tables, at the cost of approximating LRU more coarsely.
+    Clock,
+}
+
+/// A list of nodes that evicts some of them once it is over capacity.
+/// The list is limited either to a number of nodes or to a total
+/// weight; a capacity of zero disables eviction.
+///
+/// Each node is only used with one list, which can keep the position of
+/// the node, or whatever else it needs, in its [`LruIndex`](crate::LruIndex).
+/// Lists other than the built-in ones are installed with
+/// [`QueryTableMut::set_custom_eviction_policy`](crate::QueryTableMut::set_custom_eviction_policy).
+pub trait EvictionList<Node: LruNode + ?Sized>: Debug {
+    /// Limits the list to `len` nodes, forgetting the current members.
+    fn set_lru_capacity(&self, len: usize);
+
+    /// Limits the list to a total weight of `max_weight`, forgetting the
+    /// current members if it was limited to a number of nodes.
+    fn set_weight_capacity(&self, max_weight: usize);
+
+    /// Records that `node` was used, returning the nodes that were
+    /// evicted to make room for it.
+    fn record_use(&self, node: &Arc<Node>) -> Vec<Arc<Node>>;
+
+    /// Records that `node` was used and now weighs `weight`, returning
+    /// the nodes that were evicted to bring the total weight within the
+    /// capacity.
+    fn record_weighted_use(&self, node: &Arc<Node>, weight: usize) -> Vec<Arc<Node>>;
+
+    /// Removes `node` from the list, along with its weight, returning
+    /// whether it was a member. Used when the value of the node is
+    /// discarded without the list evicting it.
+    fn remove(&self, node: &Arc<Node>) -> bool;
+
+    /// Forgets all members without clearing their indices.
+    fn purge(&self);
+
+    /// The bytes allocated for the list.
+    fn heap_size(&self) -> usize;
+
+    /// Statistics about the list. `evicted_executions` is counted by the
+    /// query and can be left at zero.
+    fn stats(&self) -> LruStats;
+}
+
+/// Creates the lists of an eviction policy that is not built into
+/// salsa, see
+/// [`QueryTableMut::set_custom_eviction_policy`](crate::QueryTableMut::set_custom_eviction_policy).
+/// The nodes are internal to salsa, so the list has to work with any
+/// of them.
+pub trait CustomEvictionPolicy {
+    /// Creates a list where eviction is disabled.
+    fn new_list<Node>(&self) -> Box<dyn EvictionList<Node> + Send + Sync>
+    where
+        Node: LruNode + Send + Sync + 'static;
+}
+
+impl<Node> EvictionList<Node> for Lru<Node>
+where
+    Node: LruNode + ?Sized,
+{
+    fn set_lru_capacity(&self, len: usize) {
+        Lru::set_lru_capacity(self, len)
+    }
+
+    fn set_weight_capacity(&self, max_weight: usize) {
+        Lru::set_weight_capacity(self, max_weight)
+    }
+
+    fn record_use(&self, node: &Arc<Node>) -> Vec<Arc<Node>> {
+        Lru::record_use(self, node).into_iter().collect()
+    }
+
+    fn record_weighted_use(&self, node: &Arc<Node>, weight: usize) -> Vec<Arc<Node>> {
+        Lru::record_weighted_use(self, node, weight)
+    }
+
+    fn remove(&self, node: &Arc<Node>) -> bool {
+        Lru::remove(self, node)
+    }
+
+    fn purge(&self) {
+        Lru::purge(self)
+    }
+
+    fn heap_size(&self) -> usize {
+        Lru::heap_size(self)
+    }
+
+    fn stats(&self) -> LruStats {
+        Lru::stats(self)
+    }
+}
+
+/// The list of a derived query, using the policy selected for it.
+#[derive(Debug)]
+pub(crate) enum PolicyList<Node: LruNode + ?Sized> {
+    ApproximateLru(Lru<Node>),
+    Lru(ExactLru<Node>),
+    Clock(Clock<Node>),
+    Custom(Box<dyn EvictionList<Node> + Send + Sync>),
+}
+
+macro_rules! dispatch {
+    ($self:ident.$method:ident($($arg:expr),*)) => {
+        match $self {
+            PolicyList::ApproximateLru(list) => EvictionList::$method(list, $($arg),*),
+            PolicyList::Lru(list) => EvictionList::$method(list, $($arg),*),
+            PolicyList::Clock(list) => EvictionList::$method(list, $($arg),*),
+            PolicyList::Custom(list) => EvictionList::$method(&**list, $($arg),*),
+        }
+    };
+}
+
+impl<Node> PolicyList<Node>
+where
+    Node: LruNode + ?Sized,
+{
+    /// Creates a list where eviction is disabled.
+    pub(crate) fn new(policy: EvictionPolicy) -> Self {
+        match policy {
+            EvictionPolicy::ApproximateLru => PolicyList::ApproximateLru(Lru::new()),
+            EvictionPolicy::Lru => PolicyList::Lru(ExactLru::new()),
+            EvictionPolicy::Clock => PolicyList::Clock(Clock::new()),
+        }
+    }
+
+    /// The built-in policy of the list, or `None` for a custom one.
+    pub(crate) fn policy(&self) -> Option<EvictionPolicy> {
+        match self {
+            PolicyList::ApproximateLru(_) => Some(EvictionPolicy::ApproximateLru),
+            PolicyList::Lru(_) => Some(EvictionPolicy::Lru),
+            PolicyList::Clock(_) => Some(EvictionPolicy::Clock),
+            PolicyList::Custom(_) => None,
+        }
+    }
+}
+
+impl<Node> Default for PolicyList<Node>
+where
+    Node: LruNode + ?Sized,
+{
+    fn default() -> Self {
+        PolicyList::new(EvictionPolicy::default())
+    }
+}
+
+impl<Node> EvictionList<Node> for PolicyList<Node>
+where
+    Node: LruNode + ?Sized,
+{
+    fn set_lru_capacity(&self, len: usize) {
+        dispatch!(self.set_lru_capacity(len))
+    }
+
+    fn set_weight_capacity(&self, max_weight: usize) {
+        dispatch!(self.set_weight_capacity(max_weight))
+    }
+
+    fn record_use(&self, node: &Arc<Node>) -> Vec<Arc<Node>> {
+        dispatch!(self.record_use(node))
+    }
+
+    fn record_weighted_use(&self, node: &Arc<Node>, weight: usize) -> Vec<Arc<Node>> {
+        dispatch!(self.record_weighted_use(node, weight))
+    }
+
+    fn remove(&self, node: &Arc<Node>) -> bool {
+        dispatch!(self.remove(node))
+    }
+
+    fn purge(&self) {
+        dispatch!(self.purge())
+    }
+
+    fn heap_size(&self) -> usize {
+        dispatch!(self.heap_size())
+    }
+
+    fn stats(&self) -> LruStats {
+        dispatch!(self.stats())
+    }
+}
+
+/// The capacity and counters shared by `ExactLru` and `Clock`.
+#[derive(Debug, Default)]
+struct Capacity {
+    max_len: usize,
+    /// The total weight allowed, or zero if the list is limited to a
+    /// number of nodes instead.
+    max_weight: usize,
+    total_weight: usize,
+    evictions: u64,
+}
+
+impl Capacity {
+    fn is_enabled(&self) -> bool {
+        self.max_len != 0 || self.max_weight != 0
+    }
+
+    fn is_weighted(&self) -> bool {
+        self.max_weight != 0
+    }
+
+    fn is_exceeded(&self, len: usize) -> bool {
+        if self.is_weighted() {
+            self.total_weight > self.max_weight
+        } else {
+            len > self.max_len
+        }
+    }
+
+    fn stats(&self, len: usize) -> LruStats {
+        LruStats {
+            capacity: if self.is_weighted() { 0 } else { self.max_len },
+            weight_capacity: self.max_weight,
+            len,
+            weight: self.total_weight,
+            green_zone: 0,
+            yellow_zone: 0,
+            red_zone: len,
+            evictions: self.evictions,
+            // Counted by the query, which executes the values again.
+            ..LruStats::default()
+        }
+    }
+}
+
+/// An exact LRU list: a doubly linked list from the most to the least
+/// recently used node, threaded through a vector of entries.
+#[derive(Debug)]
+pub(crate) struct ExactLru<Node: ?Sized> {
+    enabled: AtomicBool,
+    data: Mutex<ExactLruData<Node>>,
+}
+
+#[derive(Debug)]
+struct ExactLruData<Node: ?Sized> {
+    capacity: Capacity,
+    len: usize,
+    /// The most recently used entry, or `NIL`.
+    head: usize,
+    /// The least recently used entry, or `NIL`.
+    tail: usize,
+    entries: Vec<ExactLruEntry<Node>>,
+    /// The indices of the entries that are not in use.
+    free: Vec<usize>,
+}
+
+#[derive(Debug)]
+struct ExactLruEntry<Node: ?Sized> {
+    node: Option<Arc<Node>>,
+    prev: usize,
+    next: usize,
+}
+
+const NIL: usize = usize::MAX;
+
+impl<Node> ExactLru<Node>
+where
+    Node: LruNode + ?Sized,
+{
+    fn new() -> Self {
+        ExactLru {
+            enabled: AtomicBool::new(false),
+            data: Mutex::new(ExactLruData::new(Capacity::default())),
+        }
+    }
+
+    fn set_capacity(&self, capacity: Capacity) {
+        let mut data = self.data.lock();
+        data.clear();
+        self.enabled.store(capacity.is_enabled(), Ordering::Release);
+        *data = ExactLruData::new(Capacity {
+            evictions: data.capacity.evictions,
+            ..capacity
+        });
+    }
+}
+
+impl<Node> EvictionList<Node> for ExactLru<Node>
+where
+    Node: LruNode + ?Sized,
+{
+    fn set_lru_capacity(&self, len: usize) {
+        self.set_capacity(Capacity {
+            max_len: len,
+            ..Capacity::default()
+        });
+    }
+
+    fn set_weight_capacity(&self, max_weight: usize) {
+        self.set_capacity(Capacity {
+            max_weight,
+            ..Capacity::default()
+        });
+    }
+
+    fn record_use(&self, node: &Arc<Node>) -> Vec<Arc<Node>> {
+        if !self.enabled.load(Ordering::Acquire) {
+            return Vec::new();
+        }
+        self.data.lock().record_use(node, 0)
+    }
+
+    fn record_weighted_use(&self, node: &Arc<Node>, weight: usize) -> Vec<Arc<Node>> {
+        if !self.enabled.load(Ordering::Acquire) {
+            return Vec::new();
+        }
+        self.data.lock().record_use(node, weight)
+    }
+
+    fn remove(&self, node: &Arc<Node>) -> bool {
+        if !node.lru_index().is_in_lru() {
+            return false;
+        }
+        self.data.lock().remove(node)
+    }
+
+    fn purge(&self) {
+        self.enabled.store(false, Ordering::Release);
+        *self.data.lock() = ExactLruData::new(Capacity::default());
+    }
+
+    fn heap_size(&self) -> usize {
+        let data = self.data.lock();
+        data.entries.capacity() * std::mem::size_of::<ExactLruEntry<Node>>()
+            + data.free.capacity() * std::mem::size_of::<usize>()
+    }
+
+    fn stats(&self) -> LruStats {
+        let data = self.data.lock();
+        data.capacity.stats(data.len)
+    }
+}
+
+impl<Node> ExactLruData<Node>
+where
+    Node: LruNode + ?Sized,
+{
+    fn new(capacity: Capacity) -> Self {
+        ExactLruData {
+            capacity,
+            len: 0,
+            head: NIL,
+            tail: NIL,
+            entries: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    /// Clears the indices of all members.
+    fn clear(&mut self) {
+        for entry in &self.entries {
+            if let Some(node) = &entry.node {
+                node.lru_index().clear();
+            }
+        }
+    }
+
+    /// Returns the index of the entry of `node`, if it is a member.
+    fn find(&self, node: &Arc<Node>) -> Option<usize> {
+        let index = node.lru_index().load();
+        match self.entries.get(index)?.node.as_ref() {
+            Some(member) if Arc::ptr_eq(member, node) => Some(index),
+            _ => None,
+        }
+    }
+
+    /// Moves `node` to the front of the list, adding it if needed, and
+    /// then evicts nodes from the back until the list fits. `weight` is
+    /// ignored unless the list is limited by weight.
+    fn record_use(&mut self, node: &Arc<Node>, weight: usize) -> Vec<Arc<Node>> {
+        let index = match self.find(node) {
+            Some(index) => {
+                self.unlink(index);
+                if self.capacity.is_weighted() {
+                    self.capacity.total_weight -= node.lru_index().weight();
+                }
+                index
+            }
+            None => self.insert(node),
+        };
+        self.push_front(index);
+        if self.capacity.is_weighted() {
+            self.capacity.total_weight += weight;
+            node.lru_index().store_weight(weight);
+        }
+
+        let mut evicted = Vec::new();
+        while self.len > 1 && self.capacity.is_exceeded(self.len) {
+            evicted.push(self.pop_back());
+        }
+        evicted
+    }
+
+    fn insert(&mut self, node: &Arc<Node>) -> usize {
+        let entry = ExactLruEntry {
+            node: Some(node.clone()),
+            prev: NIL,
+            next: NIL,
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.entries[index] = entry;
+                index
+            }
+            None => {
+                self.entries.push(entry);
+                self.entries.len() - 1
+            }
+        };
+        node.lru_index().store(index);
+        self.len += 1;
+        index
+    }
+
+    fn pop_back(&mut self) -> Arc<Node> {
+        let index = self.tail;
+        let node = self.take(index);
+        log::debug!("evicting node {:?} from {}", node, index);
+        self.capacity.evictions += 1;
+        node
+    }
+
+    /// Removes `node`, if it is a member.
+    fn remove(&mut self, node: &Arc<Node>) -> bool {
+        match self.find(node) {
+            Some(index) => {
+                self.take(index);
+                log::debug!("removed node {:?} from {}", node, index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Unlinks the entry at `index` and returns its node.
+    fn take(&mut self, index: usize) -> Arc<Node> {
+        self.unlink(index);
+        self.free.push(index);
+        self.len -= 1;
+
+        let node = self.entries[index].node.take().unwrap();
+        node.lru_index().clear();
+        if self.capacity.is_weighted() {
+            self.capacity.total_weight -= node.lru_index().weight();
+        }
+        node
+    }
+
+    fn unlink(&mut self, index: usize) {
+        let ExactLruEntry { prev, next, .. } = self.entries[index];
+        if prev == NIL {
+            self.head = next;
+        } else {
+            self.entries[prev].next = next;
+        }
+        if next == NIL {
+            self.tail = prev;
+        } else {
+            self.entries[next].prev = prev;
+        }
+    }
+
+    fn push_front(&mut self, index: usize) {
+        self.entries[index].prev = NIL;
+        self.entries[index].next = self.head;
+        if self.head == NIL {
+            self.tail = index;
+        } else {
+            self.entries[self.head].prev = index;
+        }
+        self.head = index;
+    }
+}
+
+/// A CLOCK list: the nodes are kept in a ring and each use sets the
+/// referenced bit of the node. To find a node to evict, a hand sweeps
+/// the ring, clearing referenced bits until it finds one that was not
+/// set.
+#[derive(Debug)]
+pub(crate) struct Clock<Node: ?Sized> {
+    enabled: AtomicBool,
+    data: Mutex<ClockData<Node>>,
+}
+
+#[derive(Debug)]
+struct ClockData<Node: ?Sized> {
+    capacity: Capacity,
+    hand: usize,
+    entries: Vec<Arc<Node>>,
+}
+
+impl<Node> Clock<Node>
+where
+    Node: LruNode + ?Sized,
+{
+    fn new() -> Self {
+        Clock {
+            enabled: AtomicBool::new(false),
+            data: Mutex::new(ClockData::new(Capacity::default())),
+        }
+    }
+
+    fn set_capacity(&self, capacity: Capacity) {
+        let mut data = self.data.lock();
+        for entry in &data.entries {
+            entry.lru_index().clear();
+        }
+        self.enabled.store(capacity.is_enabled(), Ordering::Release);
+        *data = ClockData::new(Capacity {
+            evictions: data.capacity.evictions,
+            ..capacity
+        });
+    }
+}
+
+impl<Node> EvictionList<Node> for Clock<Node>
+where
+    Node: LruNode + ?Sized,
+{
+    fn set_lru_capacity(&self, len: usize) {
+        self.set_capacity(Capacity {
+            max_len: len,
+            ..Capacity::default()
+        });
+    }
+
+    fn set_weight_capacity(&self, max_weight: usize) {
+        self.set_capacity(Capacity {
+            max_weight,
+            ..Capacity::default()
+        });
+    }
+
+    fn record_use(&self, node: &Arc<Node>) -> Vec<Arc<Node>> {
+        if !self.enabled.load(Ordering::Acquire) {
+            return Vec::new();
+        }
+
+        // Already a member of the ring -- just mark it as used.
+        let index = node.lru_index();
+        if index.is_in_lru() {
+            index.set_referenced(true);
+            return Vec::new();
+        }
+
+        self.data.lock().record_use(node, 0)
+    }
+
+    fn record_weighted_use(&self, node: &Arc<Node>, weight: usize) -> Vec<Arc<Node>> {
+        if !self.enabled.load(Ordering::Acquire) {
+            return Vec::new();
+        }
+
+        // Already a member of the ring with the same weight -- just mark
+        // it as used.
+        let index = node.lru_index();
+        if index.is_in_lru() && index.weight() == weight {
+            index.set_referenced(true);
+            return Vec::new();
+        }
+
+        self.data.lock().record_use(node, weight)
+    }
+
+    fn remove(&self, node: &Arc<Node>) -> bool {
+        if !node.lru_index().is_in_lru() {
+            return false;
+        }
+        self.data.lock().remove(node)
+    }
+
+    fn purge(&self) {
+        self.enabled.store(false, Ordering::Release);
+        *self.data.lock() = ClockData::new(Capacity::default());
+    }
+
+    fn heap_size(&self) -> usize {
+        self.data.lock().entries.capacity() * std::mem::size_of::<Arc<Node>>()
+    }
+
+    fn stats(&self) -> LruStats {
+        let data = self.data.lock();
+        data.capacity.stats(data.entries.len())
+    }
+}
+
+impl<Node> ClockData<Node>
+where
+    Node: LruNode + ?Sized,
+{
+    fn new(capacity: Capacity) -> Self {
+        ClockData {
+            capacity,
+            hand: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Marks `node` as used, adding it to the ring if needed, and then
+    /// evicts nodes until the ring fits. `weight` is ignored unless the
+    /// ring is limited by weight.
+    fn record_use(&mut self, node: &Arc<Node>, weight: usize) -> Vec<Arc<Node>> {
+        // NB: The membership check before taking the lock may be out of
+        // date, so check again.
+        let index = node.lru_index().load();
+        let is_member = matches!(
+            self.entries.get(index),
+            Some(member) if Arc::ptr_eq(member, node)
+        );
+
+        if !self.capacity.is_weighted() {
+            if is_member {
+                node.lru_index().set_referenced(true);
+                return Vec::new();
+            }
+            if self.entries.len() < self.capacity.max_len {
+                self.push(node);
+                return Vec::new();
+            }
+
+            // The ring is full, so the new node takes the place of the
+            // victim.
+            let victim_index = self.advance_hand(NIL);
+            let victim = std::mem::replace(&mut self.entries[victim_index], node.clone());
+            log::debug!("evicting node {:?} from {}", victim, victim_index);
+            victim.lru_index().clear();
+            node.lru_index().store(victim_index);
+            node.lru_index().set_referenced(false);
+            self.hand = victim_index + 1;
+            self.capacity.evictions += 1;
+            return vec![victim];
+        }
+
+        if is_member {
+            node.lru_index().set_referenced(true);
+            self.capacity.total_weight -= node.lru_index().weight();
+        } else {
+            self.push(node);
+        }
+        self.capacity.total_weight += weight;
+        node.lru_index().store_weight(weight);
+
+        let mut evicted = Vec::new();
+        while self.entries.len() > 1 && self.capacity.is_exceeded(self.entries.len()) {
+            let victim_index = self.advance_hand(node.lru_index().load());
+            let victim = self.take(victim_index);
+            log::debug!("evicting node {:?} from {}", victim, victim_index);
+            self.capacity.evictions += 1;
+            evicted.push(victim);
+        }
+        evicted
+    }
+
+    /// Removes `node`, if it is a member.
+    fn remove(&mut self, node: &Arc<Node>) -> bool {
+        // NB: The membership check before taking the lock may be out of
+        // date, so check again.
+        let index = node.lru_index().load();
+        match self.entries.get(index) {
+            Some(member) if Arc::ptr_eq(member, node) => {}
+            _ => return false,
+        }
+        self.take(index);
+        log::debug!("removed node {:?} from {}", node, index);
+        true
+    }
+
+    /// Removes the node at `index` by moving the last node of the ring
+    /// into its place, and returns it.
+    fn take(&mut self, index: usize) -> Arc<Node> {
+        let node = self.entries.swap_remove(index);
+        if let Some(moved) = self.entries.get(index) {
+            moved.lru_index().store(index);
+        }
+        node.lru_index().clear();
+        if self.capacity.is_weighted() {
+            self.capacity.total_weight -= node.lru_index().weight();
+        }
+        node
+    }
+
+    fn push(&mut self, node: &Arc<Node>) {
+        node.lru_index().store(self.entries.len());
+        node.lru_index().set_referenced(false);
+        self.entries.push(node.clone());
+        log::debug!("inserted node {:?} at {}", node, self.entries.len() - 1);
+    }
+
+    /// Moves the hand to the next node that was not used since the hand
+    /// last passed it, clearing the referenced bits on the way. The node
+    /// at `skip` is never picked.
+    fn advance_hand(&mut self, skip: usize) -> usize {
+        loop {
+            if self.hand >= self.entries.len() {
+                self.hand = 0;
+            }
+            let index = self.hand;
+            if index != skip && !self.entries[index].lru_index().take_referenced() {
+                return index;
+            }
+            self.hand += 1;
+        }
+    }
+}
@@ -1,15 +1,24 @@
-use crate::debug::TableEntry;
+use crate::debug::{QueryGraphNode, TableEntry};
 use crate::durability::Durability;
+use crate::key_table::KeyTable;
+use crate::memory::{HeapSize, HeapSizeVtable, QueryMemory, ARC_HEADER};
+use crate::persist::{
+    invalid_data, restore_exact, Persist, PersistVtable, PersistedQuery, PersistedQueryId,
+    PersistedTables, StableKey,
+};
+use crate::plumbing::HeapSizeQueryStorageOps;
 use crate::plumbing::InputQueryStorageOps;
+use crate::plumbing::PersistentQueryStorageOps;
 use crate::plumbing::QueryStorageMassOps;
 use crate::plumbing::{QueryStorageOps, QueryStorageOpsSync};
-use crate::revision::Revision;
-use crate::runtime::{FxIndexMap, StampedValue};
+#[cfg(feature = "profiling")]
+use crate::profile::QueryStats;
+use crate::revision::{AtomicRevision, Revision};
+use crate::runtime::StampedValue;
 use crate::CycleError;
 use crate::Database;
 use crate::Query;
-use crate::{DatabaseKeyIndex, QueryDb, Runtime, SweepStrategy};
-use indexmap::map::Entry;
+use crate::{DatabaseKeyIndex, Event, EventKind, QueryDb, Runtime, SweepStrategy};
 use log::debug;
 use parking_lot::RwLock;
 use std::convert::TryFrom;
@@ -23,9 +32,17 @@ where
     Q: Query,
 {
     group_index: u16,
-    slots: RwLock<FxIndexMap<Q::Key, Arc<Slot<Q>>>>,
+    slots: RwLock<KeyTable<Q::Key, Arc<Slot<Q>>>>,
+    /// The last revision in which a key was added or removed.
+    keys_changed_at: AtomicRevision,
+    persist: RwLock<Option<PersistVtable<Q::Key, Q::Value>>>,
+    heap_size: RwLock<HeapSizeVtable<Q::Key, Q::Value>>,
 }
 
+/// The key index that stands for the set of keys of the input, read by
+/// `InputQueryStorageOps::keys`.
+const KEYS_INDEX: u32 = u32::MAX;
+
 struct Slot<Q>
 where
     Q: Query,
@@ -50,6 +67,14 @@ where
     fn slot(&self, key: &Q::Key) -> Option<Arc<Slot<Q>>> {
         self.slots.read().get(key).cloned()
     }
+
+    fn keys_index(&self) -> DatabaseKeyIndex {
+        DatabaseKeyIndex {
+            group_index: self.group_index,
+            query_index: Q::QUERY_INDEX,
+            key_index: KEYS_INDEX,
+        }
+    }
 }
 
 impl<Q> QueryStorageOps<Q> for InputStorage<Q>
@@ -60,6 +85,9 @@ where
         InputStorage {
             group_index,
             slots: Default::default(),
+            keys_changed_at: AtomicRevision::start(),
+            persist: RwLock::new(None),
+            heap_size: RwLock::new(HeapSizeVtable::default()),
         }
     }
 
@@ -71,9 +99,13 @@ where
     ) -> std::fmt::Result {
         assert_eq!(index.group_index, self.group_index);
         assert_eq!(index.query_index, Q::QUERY_INDEX);
-        let slot_map = self.slots.read();
-        let key = slot_map.get_index(index.key_index as usize).unwrap().0;
-        write!(fmt, "{}({:?})", Q::QUERY_NAME, key)
+        if index.key_index == KEYS_INDEX {
+            return write!(fmt, "{}(<keys>)", Q::QUERY_NAME);
+        }
+        match self.slots.read().get_index(index.key_index) {
+            Some(slot) => write!(fmt, "{}({:?})", Q::QUERY_NAME, slot.key),
+            None => write!(fmt, "{}(<removed>)", Q::QUERY_NAME),
+        }
     }
 
     fn durability(&self, _db: &<Q as QueryDb<'_>>::DynDb, key: &Q::Key) -> Durability {
@@ -106,6 +138,14 @@ where
 
         Some(value)
     }
+
+    fn database_key_index(
+        &self,
+        _db: &<Q as QueryDb<'_>>::DynDb,
+        key: &Q::Key,
+    ) -> Option<DatabaseKeyIndex> {
+        self.slot(key).map(|slot| slot.database_key_index)
+    }
 }
 
 impl<Q> QueryStorageOpsSync<Q> for InputStorage<Q>
@@ -120,14 +160,16 @@ where
     ) -> bool {
         assert_eq!(input.group_index, self.group_index);
         assert_eq!(input.query_index, Q::QUERY_INDEX);
-        let slot = self
-            .slots
-            .read()
-            .get_index(input.key_index as usize)
-            .unwrap()
-            .1
-            .clone();
-        slot.maybe_changed_since(db, revision)
+        let _span = key_span!("maybe_changed_since", db, input, revision).entered();
+        if input.key_index == KEYS_INDEX {
+            return self.keys_changed_at.load() > revision;
+        }
+        let slot = self.slots.read().get_index(input.key_index).cloned();
+        match slot {
+            Some(slot) => slot.maybe_changed_since(db, revision),
+            // The key was removed, so it can't be the same as it used to be.
+            None => true,
+        }
     }
 
     fn try_fetch(
@@ -174,9 +216,170 @@ impl<Q> QueryStorageMassOps for InputStorage<Q>
 where
     Q: Query,
 {
-    fn sweep(&self, _runtime: &Runtime, _strategy: SweepStrategy) {}
-    fn purge(&self) {
+    fn sweep(&self, _db: &dyn Database, _strategy: SweepStrategy) {}
+    fn purge(&self, runtime: &Runtime) {
         *self.slots.write() = Default::default();
+        self.keys_changed_at.store(runtime.current_revision());
+    }
+
+    // Every input slot has a value.
+    fn compact(&self, _runtime: &Runtime) {}
+
+    fn index_dependents(&self, _runtime: &Runtime) {}
+
+    fn for_each_memo(&self, op: &mut dyn FnMut(QueryGraphNode)) {
+        for slot in self.slots.read().values() {
+            let stamped_value = slot.stamped_value.read();
+            op(QueryGraphNode::new(
+                slot.database_key_index,
+                stamped_value.durability,
+                stamped_value.changed_at,
+                None,
+                true,
+                Some(Vec::new().into()),
+            ));
+        }
+    }
+
+    fn memory_usage(&self) -> Option<QueryMemory> {
+        let heap_size = *self.heap_size.read();
+        let mut memory = QueryMemory::new(std::any::type_name::<Q::Group>(), Q::QUERY_NAME);
+        let slots = self.slots.read();
+        memory.slots = slots.len();
+        memory.values = slots.len();
+        memory.bytes =
+            slots.allocated_bytes() + slots.len() * (ARC_HEADER + std::mem::size_of::<Slot<Q>>());
+        for (key, slot) in slots.iter() {
+            memory.bytes += 2 * (heap_size.key)(key);
+            memory.bytes += (heap_size.value)(&slot.stamped_value.read().value);
+        }
+        Some(memory)
+    }
+
+    #[cfg(feature = "profiling")]
+    fn profile(&self) -> Option<QueryStats> {
+        None
+    }
+
+    #[cfg(feature = "profiling")]
+    fn reset_profile(&self) {}
+
+    fn persisted_query(&self) -> Option<PersistedQueryId> {
+        self.persist
+            .read()
+            .map(|_| PersistedQueryId::of::<Q>(self.group_index))
+    }
+
+    fn persist(&self, _db: &dyn Database) -> Option<PersistedQuery> {
+        let vtable = (*self.persist.read())?;
+        let slots = self.slots.read();
+        let mut keys = Vec::with_capacity(slots.len());
+        let mut data = Vec::new();
+        for slot in slots.values() {
+            let stamped_value = slot.stamped_value.read();
+            keys.push(vtable.key_bytes(&slot.key));
+            (vtable.persist_value)(&stamped_value.value, &mut data);
+            stamped_value.durability.persist(&mut data);
+            stamped_value.changed_at.persist(&mut data);
+        }
+        Some(PersistedQuery::new(
+            &PersistedQueryId::of::<Q>(self.group_index),
+            keys,
+            data,
+        ))
+    }
+
+    fn restore(&self, tables: &PersistedTables) -> std::io::Result<()> {
+        let vtable = match *self.persist.read() {
+            Some(vtable) => vtable,
+            None => return Ok(()),
+        };
+        let id = PersistedQueryId::of::<Q>(self.group_index);
+        let query = match tables.get(&id) {
+            Some(query) => query,
+            None => return Ok(()),
+        };
+
+        let mut slots = self.slots.write();
+        if !slots.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "cannot restore into a query table that is not empty",
+            ));
+        }
+
+        // Slots are restored in the order of the persisted keys, which
+        // is what `PersistedTables::resolve` relies on.
+        let mut data = query.data();
+        for (index, key) in query.keys().enumerate() {
+            let key = restore_exact(vtable.restore_key, key)?;
+            let stamped_value = StampedValue {
+                value: (vtable.restore_value)(&mut data)?,
+                durability: Durability::restore(&mut data)?,
+                changed_at: Revision::restore(&mut data)?,
+            };
+            let slot = Slot {
+                key: key.clone(),
+                database_key_index: id.key(u32::try_from(index).unwrap()),
+                stamped_value: RwLock::new(stamped_value),
+            };
+            if !slots.insert(key, Arc::new(slot)) {
+                return Err(invalid_data("duplicate key in persisted query"));
+            }
+        }
+
+        if !data.is_empty() {
+            return Err(invalid_data("trailing data in persisted query"));
+        }
+        Ok(())
+    }
+
+    fn stable_key(&self, index: DatabaseKeyIndex) -> Option<StableKey> {
+        let vtable = (*self.persist.read())?;
+        let id = PersistedQueryId::of::<Q>(self.group_index);
+        if !id.contains(index) {
+            return None;
+        }
+        let slots = self.slots.read();
+        let slot = slots.get_index(index.key_index)?;
+        Some(StableKey::new(
+            id.group_name(),
+            id.query_name(),
+            vtable.key_bytes(&slot.key),
+        ))
+    }
+
+    fn resolve_stable_key(&self, key: &StableKey) -> Option<DatabaseKeyIndex> {
+        let vtable = (*self.persist.read())?;
+        let id = PersistedQueryId::of::<Q>(self.group_index);
+        if !key.is_query(&id) {
+            return None;
+        }
+        let key = restore_exact(vtable.restore_key, key.key_bytes()).ok()?;
+        let key_index = self.slots.read().get_index_of(&key)?;
+        Some(id.key(key_index))
+    }
+}
+
+impl<Q> HeapSizeQueryStorageOps for InputStorage<Q>
+where
+    Q: Query,
+    Q::Key: HeapSize,
+    Q::Value: HeapSize,
+{
+    fn enable_heap_size(&self) {
+        *self.heap_size.write() = HeapSizeVtable::new();
+    }
+}
+
+impl<Q> PersistentQueryStorageOps for InputStorage<Q>
+where
+    Q: Query,
+    Q::Key: Persist,
+    Q::Value: Persist,
+{
+    fn enable_persistence(&self) {
+        *self.persist.write() = Some(PersistVtable::new());
     }
 }
 
@@ -209,13 +412,14 @@ where
         // the lock on `map` until we also hold the global query write
         // lock.
         //
-        // (*) Technically, since you can't presently access an input
-        // for a non-existent key, and you can't enumerate the set of
-        // keys, we only need a new revision if the key used to
-        // exist. But we may add such methods in the future and this
-        // case doesn't generally seem worth optimizing for.
+        // (*) Even if the key didn't exist before, adding it changes
+        // the set of keys that queries may have read with
+        // `InputQueryStorageOps::keys`.
         let mut value = Some(value);
-        db.salsa_runtime_mut()
+        let mut database_key = None;
+        let high_durability_changed_at = db.salsa_runtime().last_changed_revision(Durability::HIGH);
+        let new_revision = db
+            .salsa_runtime_mut()
             .with_incremented_revision(&mut |next_revision| {
                 let mut slots = self.slots.write();
 
@@ -229,30 +433,154 @@ where
                     changed_at: next_revision,
                 };
 
-                match slots.entry(key.clone()) {
-                    Entry::Occupied(entry) => {
-                        let mut slot_stamped_value = entry.get().stamped_value.write();
-                        let old_durability = slot_stamped_value.durability;
-                        *slot_stamped_value = stamped_value;
-                        Some(old_durability)
-                    }
-
-                    Entry::Vacant(entry) => {
-                        let key_index = u32::try_from(entry.index()).unwrap();
-                        let database_key_index = DatabaseKeyIndex {
-                            group_index: self.group_index,
-                            query_index: Q::QUERY_INDEX,
-                            key_index,
-                        };
-                        entry.insert(Arc::new(Slot {
-                            key: key.clone(),
-                            database_key_index,
-                            stamped_value: RwLock::new(stamped_value),
-                        }));
-                        None
-                    }
+                if let Some(slot) = slots.get(key) {
+                    database_key = Some(slot.database_key_index);
+                    let mut slot_stamped_value = slot.stamped_value.write();
+                    let old_durability = slot_stamped_value.durability;
+                    *slot_stamped_value = stamped_value;
+                    return Some(old_durability);
                 }
+
+                slots.get_or_insert_with(key.clone(), high_durability_changed_at, |key_index| {
+                    let database_key_index = DatabaseKeyIndex {
+                        group_index: self.group_index,
+                        query_index: Q::QUERY_INDEX,
+                        key_index,
+                    };
+                    database_key = Some(database_key_index);
+                    self.keys_changed_at.store(next_revision);
+                    Arc::new(Slot {
+                        key: key.clone(),
+                        database_key_index,
+                        stamped_value: RwLock::new(stamped_value),
+                    })
+                });
+                None
+            });
+
+        let runtime_id = db.salsa_runtime().id();
+        if let Some((revision, old_durability)) = new_revision {
+            db.salsa_event(Event {
+                runtime_id,
+                kind: EventKind::DidIncrementRevision {
+                    revision,
+                    durability: old_durability,
+                },
+            });
+        }
+        db.salsa_event(Event {
+            runtime_id,
+            kind: EventKind::DidSetInput {
+                database_key: database_key.unwrap(),
+                durability,
+            },
+        });
+    }
+
+    fn set_if_changed(
+        &self,
+        db: &mut <Q as QueryDb<'_>>::DynDb,
+        key: &Q::Key,
+        value: Q::Value,
+        durability: Durability,
+    ) -> bool
+    where
+        Q::Value: Eq,
+    {
+        // As in `remove`, only `&mut` handles can modify the table, so
+        // the value can't change between the comparison and the write.
+        if let Some(slot) = self.slot(key) {
+            let stamped_value = slot.stamped_value.read();
+            if stamped_value.durability == durability && stamped_value.value == value {
+                log::debug!("{:?}({:?}) unchanged", Q::default(), key);
+                return false;
+            }
+        }
+
+        self.set(db, key, value, durability);
+        true
+    }
+
+    fn remove(&self, db: &mut <Q as QueryDb<'_>>::DynDb, key: &Q::Key) -> Option<Q::Value> {
+        log::debug!("remove {:?}({:?})", Q::default(), key);
+
+        // Removing a key that isn't there changes nothing, so there is
+        // no need for a new revision. Only `&mut` handles can modify
+        // the table, so the key can't appear before we get the lock.
+        self.slots.read().get(key)?;
+
+        let mut removed = None;
+        let new_revision = db
+            .salsa_runtime_mut()
+            .with_incremented_revision(&mut |next_revision| {
+                let slot = self.slots.write().remove(key, next_revision)?;
+                self.keys_changed_at.store(next_revision);
+                let durability = slot.stamped_value.read().durability;
+                removed = Some(slot);
+                Some(durability)
             });
+
+        let runtime_id = db.salsa_runtime().id();
+        if let Some((revision, old_durability)) = new_revision {
+            db.salsa_event(Event {
+                runtime_id,
+                kind: EventKind::DidIncrementRevision {
+                    revision,
+                    durability: old_durability,
+                },
+            });
+        }
+        let slot = removed?;
+        db.salsa_event(Event {
+            runtime_id,
+            kind: EventKind::DidRemoveInput {
+                database_key: slot.database_key_index,
+            },
+        });
+        let value = slot.stamped_value.read().value.clone();
+        Some(value)
+    }
+
+    fn get_opt(&self, db: &<Q as QueryDb<'_>>::DynDb, key: &Q::Key) -> Option<Q::Value> {
+        let slot = match self.slot(key) {
+            Some(slot) => slot,
+            None => {
+                // The key only gets a value by joining the set of keys,
+                // so depend on that, as `keys` does.
+                db.salsa_runtime().report_query_read(
+                    self.keys_index(),
+                    Durability::LOW,
+                    self.keys_changed_at.load(),
+                );
+                return None;
+            }
+        };
+
+        let StampedValue {
+            value,
+            durability,
+            changed_at,
+        } = slot.stamped_value.read().clone();
+
+        db.salsa_runtime()
+            .report_query_read(slot.database_key_index, durability, changed_at);
+
+        Some(value)
+    }
+
+    fn keys<C>(&self, db: &<Q as QueryDb<'_>>::DynDb) -> C
+    where
+        C: std::iter::FromIterator<Q::Key>,
+    {
+        let slots = self.slots.read();
+        // Keys are added with any durability, and adding one doesn't
+        // update the revisions of the higher durabilities.
+        db.salsa_runtime().report_query_read(
+            self.keys_index(),
+            Durability::LOW,
+            self.keys_changed_at.load(),
+        );
+        slots.values().map(|slot| slot.key.clone()).collect()
     }
 }
 
@@ -1,12 +1,20 @@
-use crate::debug::TableEntry;
+use crate::debug::{QueryGraphNode, TableEntry};
 use crate::durability::Durability;
 use crate::intern_id::InternId;
+use crate::memory::{HeapSize, HeapSizeVtable, QueryMemory, ARC_HEADER};
+use crate::persist::{PersistedQuery, PersistedQueryId, PersistedTables, StableKey};
 use crate::plumbing::HasQueryGroup;
+use crate::plumbing::HeapSizeQueryStorageOps;
 use crate::plumbing::QueryStorageMassOps;
 use crate::plumbing::{QueryStorageOps, QueryStorageOpsSync};
+#[cfg(feature = "profiling")]
+use crate::profile::QueryStats;
 use crate::revision::Revision;
 use crate::Query;
-use crate::{CycleError, Database, DatabaseKeyIndex, DiscardIf, QueryDb, Runtime, SweepStrategy};
+use crate::{
+    CycleError, Database, DatabaseKeyIndex, DiscardIf, Event, EventKind, QueryDb, Runtime,
+    SweepStrategy,
+};
 use crossbeam_utils::atomic::AtomicCell;
 use parking_lot::RwLock;
 use rustc_hash::FxHashMap;
@@ -27,6 +35,7 @@ where
 {
     group_index: u16,
     tables: RwLock<InternTables<Q::Key>>,
+    heap_size: RwLock<HeapSizeVtable<Q::Key, ()>>,
 }
 
 /// Storage for the looking up interned things.
@@ -288,6 +297,7 @@ where
         InternedStorage {
             group_index,
             tables: RwLock::new(InternTables::default()),
+            heap_size: RwLock::new(HeapSizeVtable::default()),
         }
     }
 
@@ -328,6 +338,15 @@ where
             <Q::Value>::from_intern_id(index)
         })
     }
+
+    fn database_key_index(
+        &self,
+        db: &<Q as QueryDb<'_>>::DynDb,
+        key: &Q::Key,
+    ) -> Option<DatabaseKeyIndex> {
+        self.intern_check(db, key)
+            .map(|slot| slot.database_key_index)
+    }
 }
 
 impl<Q> QueryStorageOpsSync<Q> for InternedStorage<Q>
@@ -370,7 +389,9 @@ where
     Q: Query,
     Q::Value: InternKey,
 {
-    fn sweep(&self, runtime: &Runtime, strategy: SweepStrategy) {
+    fn sweep(&self, db: &dyn Database, strategy: SweepStrategy) {
+        let runtime = db.salsa_runtime();
+        let mut collected = Vec::new();
         let mut tables = self.tables.write();
         let last_changed = runtime.last_changed_revision(INTERN_DURABILITY);
         let revision_now = runtime.current_revision();
@@ -398,6 +419,7 @@ where
                 DiscardIf::Always | DiscardIf::Outdated => match &values[intern_index.as_usize()] {
                     InternValue::Present { slot, .. } => {
                         if slot.try_collect(last_changed, revision_now) {
+                            collected.push(slot.database_key_index);
                             values[intern_index.as_usize()] =
                                 InternValue::Free { next: *first_free };
                             *first_free = Some(*intern_index);
@@ -416,10 +438,85 @@ where
                 },
             }
         });
+        drop(tables);
+
+        for database_key in collected {
+            db.salsa_event(Event {
+                runtime_id: runtime.id(),
+                kind: EventKind::DidCollectInterned { database_key },
+            });
+        }
     }
-    fn purge(&self) {
+    fn purge(&self, _runtime: &Runtime) {
         *self.tables.write() = Default::default();
     }
+
+    // Interned values are freed by sweeps, which reuse their slots.
+    fn compact(&self, _runtime: &Runtime) {}
+
+    fn index_dependents(&self, _runtime: &Runtime) {}
+
+    fn for_each_memo(&self, op: &mut dyn FnMut(QueryGraphNode)) {
+        for value in &self.tables.read().values {
+            if let InternValue::Present { slot } = value {
+                op(QueryGraphNode::new(
+                    slot.database_key_index,
+                    INTERN_DURABILITY,
+                    slot.interned_at,
+                    None,
+                    true,
+                    Some(Vec::new().into()),
+                ));
+            }
+        }
+    }
+
+    fn memory_usage(&self) -> Option<QueryMemory> {
+        let heap_size = *self.heap_size.read();
+        let mut memory = QueryMemory::new(std::any::type_name::<Q::Group>(), Q::QUERY_NAME);
+        let tables = self.tables.read();
+        // The hash map keeps one control byte per bucket.
+        memory.bytes = tables.map.capacity() * (std::mem::size_of::<(Q::Key, InternId)>() + 1)
+            + tables.values.capacity() * std::mem::size_of::<InternValue<Q::Key>>();
+        for value in &tables.values {
+            if let InternValue::Present { slot } = value {
+                memory.slots += 1;
+                memory.values += 1;
+                memory.bytes += ARC_HEADER
+                    + std::mem::size_of::<Slot<Q::Key>>()
+                    + 2 * (heap_size.key)(&slot.value);
+            }
+        }
+        Some(memory)
+    }
+
+    #[cfg(feature = "profiling")]
+    fn profile(&self) -> Option<QueryStats> {
+        None
+    }
+
+    #[cfg(feature = "profiling")]
+    fn reset_profile(&self) {}
+
+    fn persisted_query(&self) -> Option<PersistedQueryId> {
+        None
+    }
+
+    fn persist(&self, _db: &dyn Database) -> Option<PersistedQuery> {
+        None
+    }
+
+    fn restore(&self, _tables: &PersistedTables) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn stable_key(&self, _index: DatabaseKeyIndex) -> Option<StableKey> {
+        None
+    }
+
+    fn resolve_stable_key(&self, _key: &StableKey) -> Option<DatabaseKeyIndex> {
+        None
+    }
 }
 
 // Workaround for
@@ -519,6 +616,17 @@ where
         let value = slot.value.clone();
         Some(value)
     }
+
+    fn database_key_index(
+        &self,
+        db: &<Q as QueryDb<'_>>::DynDb,
+        key: &Q::Key,
+    ) -> Option<DatabaseKeyIndex> {
+        let index = key.as_intern_id();
+        let interned_storage = query_storage::<Q, IQ>(db);
+        let slot = interned_storage.lookup_value(Q::convert_dyn_db(db), index);
+        Some(slot.database_key_index)
+    }
 }
 
 fn query_storage<Q, IQ>(db: &<Q as QueryDb<'_>>::DynDb) -> Arc<InternedStorage<IQ>>
@@ -571,6 +679,17 @@ where
     }
 }
 
+impl<Q> HeapSizeQueryStorageOps for InternedStorage<Q>
+where
+    Q: Query,
+    Q::Key: HeapSize,
+    Q::Value: InternKey,
+{
+    fn enable_heap_size(&self) {
+        *self.heap_size.write() = HeapSizeVtable::new();
+    }
+}
+
 impl<Q, IQ> QueryStorageMassOps for LookupInternedStorage<Q, IQ>
 where
     Q: Query,
@@ -578,8 +697,46 @@ where
     Q::Value: Eq + Hash,
     IQ: Query<Key = Q::Value, Value = Q::Key>,
 {
-    fn sweep(&self, _: &Runtime, _strategy: SweepStrategy) {}
-    fn purge(&self) {}
+    fn sweep(&self, _: &dyn Database, _strategy: SweepStrategy) {}
+    fn purge(&self, _runtime: &Runtime) {}
+
+    fn compact(&self, _runtime: &Runtime) {}
+
+    fn index_dependents(&self, _runtime: &Runtime) {}
+
+    fn for_each_memo(&self, _op: &mut dyn FnMut(QueryGraphNode)) {}
+
+    fn memory_usage(&self) -> Option<QueryMemory> {
+        None
+    }
+
+    #[cfg(feature = "profiling")]
+    fn profile(&self) -> Option<QueryStats> {
+        None
+    }
+
+    #[cfg(feature = "profiling")]
+    fn reset_profile(&self) {}
+
+    fn persisted_query(&self) -> Option<PersistedQueryId> {
+        None
+    }
+
+    fn persist(&self, _db: &dyn Database) -> Option<PersistedQuery> {
+        None
+    }
+
+    fn restore(&self, _tables: &PersistedTables) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn stable_key(&self, _index: DatabaseKeyIndex) -> Option<StableKey> {
+        None
+    }
+
+    fn resolve_stable_key(&self, _key: &StableKey) -> Option<DatabaseKeyIndex> {
+        None
+    }
 }
 
 impl<K> Slot<K> {
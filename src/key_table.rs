@@ -0,0 +1,186 @@
+use crate::memory::hash_map_entry;
+use crate::revision::Revision;
+use rustc_hash::FxHashMap;
+use std::collections::hash_map::Entry;
+use std::convert::TryFrom;
+use std::hash::Hash;
+
+/// Maps the keys of a query table to their slots, giving each key the
+/// index it is known by in a `DatabaseKeyIndex`.
+///
+/// Unlike an `IndexMap`, keys can be removed without disturbing the
+/// indices of the other keys: the removed key leaves a tombstone behind.
+/// A `DatabaseKeyIndex` that is still stored somewhere (for example in
+/// the inputs of a memo) thus keeps referring to the removed key, and
+/// looking it up just finds nothing.
+///
+/// The index of a removed key is only handed out again once an input of
+/// `Durability::HIGH` changed after the removal. Every memo that was
+/// verified before then has to check its inputs again, and the key that
+/// gets the index is newer than the memo, so it counts as changed (see
+/// `Slot::created_at`). Memos verified later can't have read the removed
+/// key, so they don't store its index.
+pub(crate) struct KeyTable<K, V> {
+    indices: FxHashMap<K, u32>,
+    /// The slot at each index, or `None` for a removed key.
+    entries: Vec<Option<V>>,
+    /// The indices of removed keys that can be handed out again.
+    free: Vec<u32>,
+    /// The indices of removed keys that may still be stored in memos,
+    /// along with the last revision one of them was removed in.
+    removed: Vec<u32>,
+    removed_at: Option<Revision>,
+}
+
+impl<K, V> Default for KeyTable<K, V> {
+    fn default() -> Self {
+        KeyTable {
+            indices: FxHashMap::default(),
+            entries: Vec::new(),
+            free: Vec::new(),
+            removed: Vec::new(),
+            removed_at: None,
+        }
+    }
+}
+
+impl<K, V> KeyTable<K, V>
+where
+    K: Hash + Eq,
+{
+    /// The number of keys in the table, not counting removed ones.
+    pub(crate) fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    /// Whether the table never had any keys, not even removed ones.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub(crate) fn get(&self, key: &K) -> Option<&V> {
+        self.get_index(*self.indices.get(key)?)
+    }
+
+    pub(crate) fn get_index_of(&self, key: &K) -> Option<u32> {
+        self.indices.get(key).copied()
+    }
+
+    /// Returns the slot at `index`, or `None` if its key was removed.
+    pub(crate) fn get_index(&self, index: u32) -> Option<&V> {
+        self.entries.get(index as usize)?.as_ref()
+    }
+
+    /// Returns the slot of `key`, inserting the one returned by
+    /// `make_slot(index)` if there is none. The index can be one of a
+    /// key removed before `high_durability_changed_at`, the last
+    /// revision an input of `Durability::HIGH` changed in.
+    pub(crate) fn get_or_insert_with(
+        &mut self,
+        key: K,
+        high_durability_changed_at: Revision,
+        make_slot: impl FnOnce(u32) -> V,
+    ) -> &V {
+        if let Some(&index) = self.indices.get(&key) {
+            return self.entries[index as usize].as_ref().unwrap();
+        }
+
+        if matches!(self.removed_at, Some(removed_at) if removed_at < high_durability_changed_at) {
+            self.free.append(&mut self.removed);
+            self.removed_at = None;
+        }
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                let index = next_index(&self.entries);
+                self.entries.push(None);
+                index
+            }
+        };
+        self.indices.insert(key, index);
+        self.entries[index as usize].insert(make_slot(index))
+    }
+
+    /// Inserts `key` with a new index, returning `false` if it is already
+    /// in the table. Removed indices are not handed out again, so that a
+    /// table filled by `insert` alone gives out the indices in order.
+    pub(crate) fn insert(&mut self, key: K, slot: V) -> bool {
+        let index = next_index(&self.entries);
+        match self.indices.entry(key) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(index);
+                self.entries.push(Some(slot));
+                true
+            }
+        }
+    }
+
+    /// Removes `key` in `revision_now`, leaving a tombstone behind, and
+    /// returns its slot.
+    pub(crate) fn remove(&mut self, key: &K, revision_now: Revision) -> Option<V> {
+        let index = self.indices.remove(key)?;
+        self.removed.push(index);
+        self.removed_at = Some(revision_now);
+        self.entries[index as usize].take()
+    }
+
+    /// Removes the keys whose slot does not satisfy `keep` in
+    /// `revision_now`, leaving tombstones behind, and returns how many
+    /// were removed.
+    pub(crate) fn retain(
+        &mut self,
+        revision_now: Revision,
+        mut keep: impl FnMut(&V) -> bool,
+    ) -> usize {
+        let entries = &mut self.entries;
+        let removed = &mut self.removed;
+        let len = self.indices.len();
+        self.indices.retain(|_, index| {
+            let entry = &mut entries[*index as usize];
+            let kept = entry.as_ref().is_some_and(&mut keep);
+            if !kept {
+                *entry = None;
+                removed.push(*index);
+            }
+            kept
+        });
+        let removed = len - self.indices.len();
+        if removed != 0 {
+            self.removed_at = Some(revision_now);
+            self.indices.shrink_to_fit();
+        }
+        removed
+    }
+
+    /// The slots of the keys in the table, in the order of their indices.
+    pub(crate) fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.iter().filter_map(Option::as_ref)
+    }
+
+    /// The keys in the table along with their slots, in no particular
+    /// order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        let entries = &self.entries;
+        self.indices
+            .iter()
+            .map(move |(key, index)| (key, entries[*index as usize].as_ref().unwrap()))
+    }
+
+    /// The bytes allocated by the table, not counting the heap memory
+    /// owned by its keys and slots.
+    pub(crate) fn allocated_bytes(&self) -> usize {
+        self.indices.capacity() * hash_map_entry::<K, u32>()
+            + self.entries.capacity() * std::mem::size_of::<Option<V>>()
+            + (self.free.capacity() + self.removed.capacity()) * std::mem::size_of::<u32>()
+    }
+}
+
+/// The index of the next key. `u32::MAX` is never handed out, so that it
+/// can stand for something other than a key (see `InputStorage::keys`).
+fn next_index<V>(entries: &[Option<V>]) -> u32 {
+    u32::try_from(entries.len())
+        .ok()
+        .filter(|&index| index != u32::MAX)
+        .expect("too many keys in query table")
+}
@@ -7,27 +7,41 @@
 //! re-execute the derived queries and it will try to re-use results
 //! from previous invocations as appropriate.
 
+#[macro_use]
+mod span;
+
 mod blocking_future;
+mod cancellation;
+mod cycle;
 mod derived;
 mod doctest;
 mod durability;
+mod eviction;
 mod input;
 mod intern_id;
 mod interned;
+mod key_table;
 mod lru;
 mod revision;
 mod runtime;
 mod storage;
 
 pub mod debug;
+pub mod memory;
+pub mod persist;
+pub mod profile;
 /// Items in this module are public for implementation reasons,
 /// and are exempt from the SemVer guarantees.
 #[doc(hidden)]
 pub mod plumbing;
 
+use crate::plumbing::CustomEvictionQueryStorageOps;
 use crate::plumbing::DerivedQueryStorageOps;
+use crate::plumbing::HeapSizeQueryStorageOps;
 use crate::plumbing::InputQueryStorageOps;
 use crate::plumbing::LruQueryStorageOps;
+use crate::plumbing::MemoryBudgetQueryStorageOps;
+use crate::plumbing::PersistentQueryStorageOps;
 use crate::plumbing::QueryStorageMassOps;
 use crate::plumbing::QueryStorageOps;
 #[cfg(feature = "async")]
@@ -41,9 +55,13 @@ use std::{
     sync::{Arc, Mutex},
 };
 
+pub use crate::cancellation::{catch_cancellation, CancellationToken, Cancelled};
+pub use crate::cycle::{CycleReport, CycleStep};
 pub use crate::durability::Durability;
+pub use crate::eviction::{CustomEvictionPolicy, EvictionList, EvictionPolicy};
 pub use crate::intern_id::InternId;
 pub use crate::interned::InternKey;
+pub use crate::lru::{LruIndex, LruNode, LruStats};
 pub use crate::runtime::Runtime;
 pub use crate::runtime::RuntimeId;
 pub use crate::storage::Storage;
@@ -65,8 +83,120 @@ pub trait Database: plumbing::DatabaseOps {
         // and there is no need to bring things to a halt. That said,
         // users may wish to guarantee atomicity.
 
+        let _span = debug_span!(
+            "sweep",
+            ?strategy,
+            revision = ?self.salsa_runtime().current_revision(),
+            runtime_id = ?self.salsa_runtime().id(),
+        )
+        .entered();
+        let db = self.ops_database();
+        self.for_each_query(&mut |query_storage| query_storage.sweep(db, strategy));
+    }
+
+    /// Iterates through all query storage and removes the keys that no
+    /// longer have a memoized value, typically because a sweep discarded
+    /// it. A table otherwise keeps every key it has ever seen, so a
+    /// database that computes many short-lived keys grows without bound
+    /// unless it is compacted from time to time.
+    ///
+    /// A `DatabaseKeyIndex` that refers to a removed key (say, as the
+    /// input of some memo) simply counts as changed. Fetching the key
+    /// again gives it a new index. The indices of removed keys are only
+    /// handed out again once an input of [`Durability::HIGH`] changed,
+    /// which makes every memo that may still refer to them check its
+    /// inputs again.
+    fn compact_all(&self) {
+        let _span = debug_span!(
+            "compact",
+            revision = ?self.salsa_runtime().current_revision(),
+            runtime_id = ?self.salsa_runtime().id(),
+        )
+        .entered();
+        let runtime = self.salsa_runtime();
+        self.for_each_query(&mut |query_storage| query_storage.compact(runtime));
+    }
+
+    /// Like [`Runtime::synthetic_write`], but also reports the new
+    /// revision to `salsa_event`.
+    fn synthetic_write(&mut self, durability: Durability) {
+        let new_revision = self
+            .salsa_runtime_mut()
+            .with_incremented_revision(&mut |_next_revision| Some(durability));
+        if let Some((revision, durability)) = new_revision {
+            self.salsa_event(Event {
+                runtime_id: self.salsa_runtime().id(),
+                kind: EventKind::DidIncrementRevision {
+                    revision,
+                    durability,
+                },
+            });
+        }
+    }
+
+    /// Performs all the writes made by `op`, such as setting inputs
+    /// with `QueryTableMut::set`, in a single new revision: the global
+    /// query write lock is acquired once and the current revision is
+    /// canceled once, rather than for every write. The new revision
+    /// counts as changing the highest durability among the values that
+    /// were modified.
+    ///
+    /// `op` can read inputs, which have the values written so far, but
+    /// fetching a derived query panics: its memo would be taken as valid
+    /// for the whole new revision, even though the writes that follow
+    /// may change its result. A transaction started inside another one
+    /// simply becomes part of it.
+    ///
+    /// **WARNING:** Just like an ordinary write, this blocks until every
+    /// snapshot is dropped, so `op` must not use snapshots either.
+    fn transaction<R>(&mut self, op: impl FnOnce(&mut Self) -> R) -> R
+    where
+        Self: Sized,
+    {
+        if !self.salsa_runtime_mut().begin_transaction() {
+            return op(self);
+        }
+
+        // The write lock must be released even if `op` panics.
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| op(self)));
+        let (revision, durability) = self.salsa_runtime_mut().end_transaction();
+        self.salsa_event(Event {
+            runtime_id: self.salsa_runtime().id(),
+            kind: EventKind::DidIncrementRevision {
+                revision,
+                durability,
+            },
+        });
+        match result {
+            Ok(result) => result,
+            Err(payload) => std::panic::resume_unwind(payload),
+        }
+    }
+
+    /// Like [`Runtime::is_current_revision_canceled`], but also reports
+    /// the cancellation to `salsa_event` when the current revision is
+    /// canceled.
+    fn is_current_revision_canceled(&self) -> bool {
         let runtime = self.salsa_runtime();
-        self.for_each_query(&mut |query_storage| query_storage.sweep(runtime, strategy));
+        let canceled = runtime.is_current_revision_canceled();
+        if canceled {
+            self.salsa_event(Event {
+                runtime_id: runtime.id(),
+                kind: EventKind::DidObserveCancellation,
+            });
+        }
+        canceled
+    }
+
+    /// Starts maintaining an index of reverse dependencies, so that
+    /// [`QueryTable::dependents`] can tell which derived queries read a
+    /// given key. The index is off by default since keeping it up to
+    /// date costs some time whenever a memo is stored. Memos that
+    /// already exist are indexed right away.
+    fn enable_dependents_index(&mut self) {
+        self.salsa_runtime_mut().enable_dependents_index();
+        let runtime = self.salsa_runtime();
+        self.for_each_query(&mut |query_storage| query_storage.index_dependents(runtime));
     }
 
     /// This function is invoked at key points in the salsa
@@ -77,7 +207,9 @@ pub trait Database: plumbing::DatabaseOps {
     }
 
     /// This function is invoked when a dependent query is being computed by the
-    /// other thread, and that thread panics.
+    /// other thread, and that thread panics. It is not invoked if the
+    /// current revision is canceled, since the other thread most likely
+    /// unwound with [`Cancelled`]; this thread unwinds with it as well.
     fn on_propagated_panic(&self) -> ! {
         panic!("concurrent salsa query panicked")
     }
@@ -150,6 +282,101 @@ pub enum EventKind {
         /// The database-key for the affected value. Implements `Debug`.
         database_key: DatabaseKeyIndex,
     },
+
+    /// Says why the function for this query will be executed.
+    ///
+    /// Executes right after the corresponding `WillExecute` event.
+    WillExecuteBecause {
+        /// The database-key for the affected value. Implements `Debug`.
+        database_key: DatabaseKeyIndex,
+
+        /// Why the previous result, if any, could not be reused.
+        reason: ExecuteReason,
+    },
+
+    /// Indicates that a new revision was created by setting an input,
+    /// invalidating a query, a synthetic write or a
+    /// [transaction](Database::transaction).
+    ///
+    /// Executes after the write, once the global query write lock has
+    /// been released.
+    DidIncrementRevision {
+        /// The new revision.
+        revision: Revision,
+
+        /// The durability of the value that was modified, or `None` if
+        /// no pre-existing value was modified (for example when an input
+        /// is set for the first time).
+        durability: Option<Durability>,
+    },
+
+    /// Indicates that an input was set.
+    ///
+    /// Executes right after the corresponding `DidIncrementRevision`
+    /// event, or before it for an input set in a transaction.
+    DidSetInput {
+        /// The database-key for the affected value. Implements `Debug`.
+        database_key: DatabaseKeyIndex,
+
+        /// The durability the input was set with.
+        durability: Durability,
+    },
+
+    /// Indicates that an input was removed.
+    ///
+    /// Executes right after the corresponding `DidIncrementRevision`
+    /// event, or before it for an input removed in a transaction.
+    DidRemoveInput {
+        /// The database-key for the removed input. Since the key is
+        /// gone, [`DatabaseKeyIndex::debug`] can only show the name of
+        /// its query.
+        database_key: DatabaseKeyIndex,
+    },
+
+    /// Indicates that the memoized value for this query was evicted
+    /// because the query is over its LRU capacity. Its dependencies are
+    /// kept, so it can still be validated by queries that depend on it.
+    DidEvictValue {
+        /// The database-key for the affected value. Implements `Debug`.
+        database_key: DatabaseKeyIndex,
+    },
+
+    /// Indicates that a sweep discarded the memoized value for this
+    /// query.
+    DidSweep {
+        /// The database-key for the affected value. Implements `Debug`.
+        database_key: DatabaseKeyIndex,
+
+        /// Whether the whole memo was removed, rather than just its
+        /// value.
+        memo_removed: bool,
+    },
+
+    /// Indicates that a sweep collected this interned value, so that its
+    /// slot can be reused for another value.
+    DidCollectInterned {
+        /// The database-key for the affected value. Since the value is
+        /// already gone, it cannot be formatted with
+        /// [`DatabaseKeyIndex::debug`].
+        database_key: DatabaseKeyIndex,
+    },
+
+    /// Indicates that this query depends on itself. `cycle` lists the
+    /// queries that take part in the cycle.
+    ///
+    /// Executes before the query's cycle recovery, if any, is invoked.
+    DidDetectCycle {
+        /// The database-key for the affected value. Implements `Debug`.
+        database_key: DatabaseKeyIndex,
+
+        /// The participants of the cycle.
+        cycle: Vec<DatabaseKeyIndex>,
+    },
+
+    /// Indicates that [`Database::is_current_revision_canceled`] found
+    /// the current revision canceled, or that fetching a query unwound
+    /// with [`Cancelled`] since it was.
+    DidObserveCancellation,
 }
 
 impl fmt::Debug for EventKind {
@@ -171,10 +398,100 @@ impl fmt::Debug for EventKind {
                 .debug_struct("WillExecute")
                 .field("database_key", database_key)
                 .finish(),
+            EventKind::WillExecuteBecause {
+                database_key,
+                reason,
+            } => fmt
+                .debug_struct("WillExecuteBecause")
+                .field("database_key", database_key)
+                .field("reason", reason)
+                .finish(),
+            EventKind::DidIncrementRevision {
+                revision,
+                durability,
+            } => fmt
+                .debug_struct("DidIncrementRevision")
+                .field("revision", revision)
+                .field("durability", durability)
+                .finish(),
+            EventKind::DidSetInput {
+                database_key,
+                durability,
+            } => fmt
+                .debug_struct("DidSetInput")
+                .field("database_key", database_key)
+                .field("durability", durability)
+                .finish(),
+            EventKind::DidRemoveInput { database_key } => fmt
+                .debug_struct("DidRemoveInput")
+                .field("database_key", database_key)
+                .finish(),
+            EventKind::DidEvictValue { database_key } => fmt
+                .debug_struct("DidEvictValue")
+                .field("database_key", database_key)
+                .finish(),
+            EventKind::DidSweep {
+                database_key,
+                memo_removed,
+            } => fmt
+                .debug_struct("DidSweep")
+                .field("database_key", database_key)
+                .field("memo_removed", memo_removed)
+                .finish(),
+            EventKind::DidCollectInterned { database_key } => fmt
+                .debug_struct("DidCollectInterned")
+                .field("database_key", database_key)
+                .finish(),
+            EventKind::DidDetectCycle {
+                database_key,
+                cycle,
+            } => fmt
+                .debug_struct("DidDetectCycle")
+                .field("database_key", database_key)
+                .field("cycle", cycle)
+                .finish(),
+            EventKind::DidObserveCancellation => {
+                fmt.debug_struct("DidObserveCancellation").finish()
+            }
         }
     }
 }
 
+/// The reason a derived query had to be executed instead of reusing
+/// its memoized value. Reported by [`EventKind::WillExecuteBecause`]
+/// and [`QueryTable::explain`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ExecuteReason {
+    /// There was no memo for the key: the query was never executed
+    /// for it, or its last execution did not complete.
+    NotComputed,
+
+    /// The memo was validated and `input` was the first of its inputs
+    /// that may have changed since.
+    InputChanged {
+        /// The input that may have changed. Implements `Debug`.
+        input: DatabaseKeyIndex,
+    },
+
+    /// The memo read an untracked input, so it can't be validated once
+    /// a new revision starts.
+    UntrackedRead,
+
+    /// The memo was explicitly invalidated with
+    /// [`QueryTableMut::invalidate`].
+    Invalidated,
+
+    /// The memoized value was evicted by the LRU.
+    Evicted,
+
+    /// The memoized value, or the whole memo, was discarded by a sweep.
+    Swept,
+
+    /// The query only memoizes its dependencies and never its value,
+    /// so it is executed whenever the value is needed again.
+    NotMemoized,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 enum DiscardIf {
     Never,
@@ -504,6 +821,22 @@ impl DatabaseKeyIndex {
     {
         DatabaseKeyIndexDebug { index: self, db }
     }
+
+    /// Returns an identity for this key that stays the same across runs,
+    /// unlike the index itself. Returns `None` unless the query has
+    /// persistence enabled; see [`persist::StableKey`].
+    pub fn stable_key<D>(self, db: &D) -> Option<persist::StableKey>
+    where
+        D: ?Sized + plumbing::DatabaseOps,
+    {
+        let mut result = None;
+        db.for_each_query(&mut |query_storage| {
+            if result.is_none() {
+                result = query_storage.stable_key(self);
+            }
+        });
+        result
+    }
 }
 
 /// Helper type for `DatabaseKeyIndex::debug`
@@ -652,7 +985,17 @@ where
     where
         Q::Storage: plumbing::QueryStorageMassOps,
     {
-        self.storage.sweep(self.db.salsa_runtime(), strategy);
+        self.storage
+            .sweep(plumbing::DatabaseOps::ops_database(self.db), strategy);
+    }
+
+    /// Remove the keys of this query that no longer have a memoized
+    /// value. See [`Database::compact_all`].
+    pub fn compact(&self)
+    where
+        Q::Storage: plumbing::QueryStorageMassOps,
+    {
+        self.storage.compact(self.db.salsa_runtime());
     }
 
     /// Peeks at the value at `Q::Key`. If it is currently in cache then it returns
@@ -660,6 +1003,84 @@ where
     pub fn peek(&self, key: &Q::Key) -> Option<Q::Value> {
         self.storage.peek(self.db, key)
     }
+
+    /// Returns the index that `key` has in the database, if the table
+    /// contains it. The index can be passed to the functions in
+    /// [`debug`], or formatted with [`DatabaseKeyIndex::debug`].
+    pub fn database_key_index(&self, key: &Q::Key) -> Option<DatabaseKeyIndex> {
+        self.storage.database_key_index(self.db, key)
+    }
+
+    /// Returns the derived queries that read `key` in the current
+    /// revision, that is, whose memo read it and was executed or
+    /// validated in this revision. Memos that read untracked inputs are
+    /// not included, and neither are memos that were discarded by a
+    /// sweep or found to be out of date, or that have not been
+    /// validated again since an earlier revision.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the reverse dependency index was not enabled with
+    /// [`Database::enable_dependents_index`].
+    pub fn dependents(&self, key: &Q::Key) -> Vec<DatabaseKeyIndex> {
+        let runtime = self.db.salsa_runtime();
+        if !runtime.tracks_dependents() {
+            panic!("`dependents` requires `Database::enable_dependents_index`");
+        }
+        match self.database_key_index(key) {
+            Some(index) => runtime.dependents(index),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns why the query was last executed for `key`, or `None` if
+    /// it has not been executed for it. Memos restored with
+    /// [`persist::load`] don't remember why they were executed.
+    pub fn explain(&self, key: &Q::Key) -> Option<ExecuteReason>
+    where
+        Q::Storage: plumbing::DerivedQueryStorageOps<Q>,
+    {
+        self.storage.explain(key)
+    }
+
+    /// Returns statistics about the LRU list of this query, to help
+    /// tune the capacity set with [`QueryTableMut::set_lru_capacity`].
+    pub fn lru_stats(&self) -> LruStats
+    where
+        Q::Storage: plumbing::DerivedQueryStorageOps<Q>,
+    {
+        self.storage.lru_stats()
+    }
+
+    /// Returns the value of `key` in this input query, or `None` if it
+    /// was never set or was [removed](QueryTableMut::remove).
+    ///
+    /// Like [`get`](Self::get), this is a tracked read. A query that
+    /// finds no value is executed again once the key is set.
+    pub fn get_opt(&self, key: Q::Key) -> Option<Q::Value>
+    where
+        Q::Storage: plumbing::InputQueryStorageOps<Q>,
+    {
+        self.storage.get_opt(self.db, &key)
+    }
+
+    /// Returns the keys that have a value in this input query, in the
+    /// order they were first set. A key that is set once others were
+    /// [removed](QueryTableMut::remove) may take the place of one of them
+    /// in that order.
+    ///
+    /// Unlike [`crate::debug::DebugQueryTable::entries`], this is a
+    /// tracked read: a query that calls it depends on the set of keys,
+    /// and is only executed again once a key has been added or
+    /// [removed](QueryTableMut::remove). Setting the value of an
+    /// existing key doesn't count as a change.
+    pub fn keys<C>(&self) -> C
+    where
+        Q::Storage: plumbing::InputQueryStorageOps<Q>,
+        C: std::iter::FromIterator<Q::Key>,
+    {
+        self.storage.keys(self.db)
+    }
 }
 
 impl<'me, Q> QueryTable<'me, Q, <Q as QueryDb<'me>>::Db>
@@ -672,11 +1093,51 @@ where
     /// queries (those with no inputs, or those with more than one
     /// input) the key will be a tuple.
     pub fn get(&mut self, key: Q::Key) -> Q::Value {
-        self.try_get(key).unwrap_or_else(|err| panic!("{}", err))
+        match self.try_get(key) {
+            Ok(value) => value,
+            Err(err) => err.throw(&*self.db),
+        }
     }
 
-    fn try_get(&mut self, key: Q::Key) -> Result<Q::Value, CycleError<DatabaseKeyIndex>> {
-        self.storage.try_fetch(&mut self.db, &key)
+    /// Like [`get`](Self::get), but returns the [`CycleError`] instead
+    /// of panicking if the query runs into a cycle that it does not
+    /// recover from, including one that is detected by a query that
+    /// this one depends on.
+    pub fn try_get(&mut self, key: Q::Key) -> Result<Q::Value, CycleError<DatabaseKeyIndex>> {
+        cancellation::unwind_if_cancelled(&*self.db);
+        let (db, storage) = (&mut self.db, &self.storage);
+        let result =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| storage.try_fetch(db, &key)))
+                .unwrap_or_else(|payload| Err(CycleError::from_panic(payload)));
+        if result.is_err() {
+            self.report_cycle_error_read(&key);
+        }
+        result
+    }
+}
+
+impl<'me, Q> QueryTable<'me, Q, <Q as QueryDb<'me>>::Db>
+where
+    Q: Query,
+    Q::Storage: QueryStorageOps<Q>,
+{
+    /// Records that the active query read `key`, which failed with a
+    /// cycle error. The queries that unwound with the error took their
+    /// reads with them, so this is all that makes the active query
+    /// depend on the cycle, and execute again once the cycle may be
+    /// gone. The durability of the error only covers the reads of the
+    /// query that detected the cycle, so the read is taken to be of low
+    /// durability instead.
+    fn report_cycle_error_read(&self, key: &Q::Key) {
+        let runtime = self.db.salsa_runtime();
+        match self.storage.database_key_index(&*self.db, key) {
+            Some(database_key_index) => runtime.report_query_read(
+                database_key_index,
+                Durability::LOW,
+                runtime.current_revision(),
+            ),
+            None => runtime.report_untracked_read(),
+        }
     }
 }
 
@@ -694,16 +1155,34 @@ where
     /// queries (those with no inputs, or those with more than one
     /// input) the key will be a tuple.
     pub async fn get_async(&mut self, key: Q::Key) -> Q::Value {
-        self.try_get_async(key)
-            .await
-            .unwrap_or_else(|err| panic!("{}", err))
+        match self.try_get_async(key).await {
+            Ok(value) => value,
+            Err(err) => err.throw(&*self.db),
+        }
     }
 
-    async fn try_get_async(
+    /// Like [`get_async`](Self::get_async), but returns the
+    /// [`CycleError`] instead of panicking if the query runs into a
+    /// cycle that it does not recover from, including one that is
+    /// detected by a query that this one depends on.
+    pub async fn try_get_async(
         &mut self,
         key: Q::Key,
     ) -> Result<Q::Value, CycleError<DatabaseKeyIndex>> {
-        self.storage.try_fetch_async(&mut self.db, &key).await
+        cancellation::unwind_if_cancelled(&*self.db);
+        let mut fetch = self.storage.try_fetch_async(&mut self.db, &key);
+        let result = futures_util::future::poll_fn(|cx| {
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| fetch.as_mut().poll(cx)))
+                .unwrap_or_else(|payload| {
+                    std::task::Poll::Ready(Err(CycleError::from_panic(payload)))
+                })
+        })
+        .await;
+        drop(fetch);
+        if result.is_err() {
+            self.report_cycle_error_read(&key);
+        }
+        result
     }
     /// Completely clears the storage for this query.
     ///
@@ -715,7 +1194,7 @@ where
     where
         Q::Storage: plumbing::QueryStorageMassOps,
     {
-        self.storage.purge();
+        self.storage.purge(self.db.salsa_runtime());
     }
 }
 
@@ -770,6 +1249,58 @@ where
         self.storage.set(self.db, &key, value, durability);
     }
 
+    /// Like [`QueryTableMut::set`], but only if `value` differs from the
+    /// current value of the input. Setting an equal value does nothing:
+    /// no new revision is created, so queries are not canceled and
+    /// memos that read the input stay valid without being checked again.
+    ///
+    /// Returns whether the value was set.
+    pub fn set_if_changed(&mut self, key: Q::Key, value: Q::Value) -> bool
+    where
+        Q::Storage: plumbing::InputQueryStorageOps<Q>,
+        Q::Value: Eq,
+    {
+        self.set_with_durability_if_changed(key, value, Durability::LOW)
+    }
+
+    /// Like [`QueryTableMut::set_with_durability`], but only if `value`
+    /// or `durability` differ from those of the input. See
+    /// [`QueryTableMut::set_if_changed`].
+    pub fn set_with_durability_if_changed(
+        &mut self,
+        key: Q::Key,
+        value: Q::Value,
+        durability: Durability,
+    ) -> bool
+    where
+        Q::Storage: plumbing::InputQueryStorageOps<Q>,
+        Q::Value: Eq,
+    {
+        self.storage
+            .set_if_changed(self.db, &key, value, durability)
+    }
+
+    /// Removes the value of an "input query", returning it, or `None`
+    /// if no value was set. Must be used outside of an active query
+    /// computation.
+    ///
+    /// This creates a new revision in which every query that read the
+    /// key counts as changed. Reading the key again panics, as for a
+    /// key that was never set, until it is given a new value. Queries
+    /// that need to handle the removal can read the key with
+    /// [`QueryTable::get_opt`] instead.
+    ///
+    /// If you are using `snapshot`, see the notes on blocking
+    /// and cancellation on [the `query_mut` method].
+    ///
+    /// [the `query_mut` method]: trait.Database.html#method.query_mut
+    pub fn remove(&mut self, key: &Q::Key) -> Option<Q::Value>
+    where
+        Q::Storage: plumbing::InputQueryStorageOps<Q>,
+    {
+        self.storage.remove(self.db, key)
+    }
+
     /// Sets the size of LRU cache of values for this query table.
     ///
     /// That is, at most `cap` values will be preset in the table at the same
@@ -784,6 +1315,116 @@ where
         self.storage.set_lru_capacity(cap);
     }
 
+    /// Sets the size of the LRU cache of values for this query table as
+    /// a total weight, where each value weighs `weight(value)`.
+    ///
+    /// Values are evicted until the total weight of the values in the
+    /// table is at most `cap`, so a cap can express the actual memory
+    /// cost of the values rather than their number. The most recently
+    /// used value is always kept, even if it weighs more than `cap`.
+    ///
+    /// If `cap` is zero, all values are preserved. Calling
+    /// [`QueryTableMut::set_lru_capacity`] goes back to counting values.
+    /// Calling this again with another `weight` weighs each value again
+    /// the next time it is used.
+    pub fn set_lru_weight_capacity(&self, cap: usize, weight: fn(&Q::Value) -> usize)
+    where
+        Q::Storage: plumbing::DerivedQueryStorageOps<Q>,
+    {
+        self.storage.set_lru_weight_capacity(cap, weight);
+    }
+
+    /// Lets the LRU cache of this query table evict values whose query
+    /// read an untracked input.
+    ///
+    /// By default such values are never evicted, since executing the
+    /// query again could produce a different value, so a volatile query
+    /// can hold more values than its LRU capacity. With `evict` set, the
+    /// whole memo is discarded instead, once a new revision has started
+    /// since the value was last used; the query is then executed again
+    /// the next time the value is needed. The same goes for values
+    /// evicted by the memory budget.
+    pub fn set_lru_evict_untracked(&self, evict: bool)
+    where
+        Q::Storage: plumbing::DerivedQueryStorageOps<Q>,
+    {
+        self.storage.set_lru_evict_untracked(evict);
+    }
+
+    /// Selects how the LRU cache of this query table picks the values
+    /// to evict, see [`EvictionPolicy`]. The capacity is kept, but the
+    /// values in the table start out as unused under the new policy.
+    pub fn set_eviction_policy(&self, policy: EvictionPolicy)
+    where
+        Q::Storage: plumbing::DerivedQueryStorageOps<Q>,
+    {
+        self.storage.set_eviction_policy(policy);
+    }
+
+    /// Like [`set_eviction_policy`](Self::set_eviction_policy), but with
+    /// a policy that is not built into salsa: `policy` creates the
+    /// [`EvictionList`] that picks the values to evict.
+    pub fn set_custom_eviction_policy(&self, policy: impl CustomEvictionPolicy)
+    where
+        Q::Storage: plumbing::CustomEvictionQueryStorageOps,
+    {
+        self.storage.set_custom_eviction_policy(&policy);
+    }
+
+    /// Iterates cycles that this query runs into to a fixpoint, instead
+    /// of failing with a [`CycleError`] or calling the cycle recovery
+    /// function.
+    ///
+    /// When the query is entered again while it is executing, the inner
+    /// read returns `initial(key)`. Once the outer execution completes,
+    /// the cycle is executed again with the value it computed, until
+    /// the value stops changing, as compared with `Eq`; the values of
+    /// all queries in the cycle are then memoized. If the value still
+    /// changes after `max_iterations` executions, the query fails with
+    /// a `CycleError` instead. The query function has to be monotone
+    /// for the iteration to converge.
+    ///
+    /// Since any query of a cycle can be the one that is entered first,
+    /// this has to be set for each of them: running into a cycle where
+    /// only some of the queries iterate to a fixpoint panics. Cycles
+    /// that span several runtimes are not iterated.
+    pub fn set_cycle_fixpoint(&self, initial: fn(&Q::Key) -> Q::Value, max_iterations: usize)
+    where
+        Q::Storage: plumbing::DerivedQueryStorageOps<Q>,
+    {
+        self.storage.set_cycle_fixpoint(initial, max_iterations);
+    }
+
+    /// Opts this query into persistence, so that its contents are
+    /// written by [`persist::save`] and restored by [`persist::load`].
+    /// Queries that do not opt in are skipped.
+    pub fn enable_persistence(&self)
+    where
+        Q::Storage: plumbing::PersistentQueryStorageOps,
+    {
+        self.storage.enable_persistence();
+    }
+
+    /// Makes this query take part in the database-wide memory budget
+    /// set with [`Runtime::set_memory_budget`]. Its values are then
+    /// evicted when they are among the least recently used values of
+    /// all such queries and the budget is exceeded.
+    pub fn enable_memory_budget(&self)
+    where
+        Q::Storage: plumbing::MemoryBudgetQueryStorageOps,
+    {
+        self.storage.enable_memory_budget();
+    }
+
+    /// Makes [`memory::report`] count the heap allocations of this
+    /// query's keys and values, rather than just their inline size.
+    pub fn enable_heap_size(&self)
+    where
+        Q::Storage: plumbing::HeapSizeQueryStorageOps,
+    {
+        self.storage.enable_heap_size();
+    }
+
     /// Marks the computed value as outdated.
     ///
     /// This causes salsa to re-execute the query function on the next access to
@@ -804,23 +1445,72 @@ where
 pub struct CycleError<K> {
     /// The queries that were part of the cycle
     cycle: Vec<K>,
+    /// The runtime that was executing each query of `cycle`, or empty if
+    /// that is not known.
+    runtimes: Vec<RuntimeId>,
     changed_at: Revision,
     durability: Durability,
 }
 
+impl<K> CycleError<K> {
+    /// The queries that were part of the cycle. See
+    /// [`report`](Self::report) for their names and keys.
+    pub fn participants(&self) -> &[K] {
+        &self.cycle
+    }
+
+    /// The revision that the error applies to, as if it were the value
+    /// of the query.
+    pub fn changed_at(&self) -> Revision {
+        self.changed_at
+    }
+
+    /// The durability of the inputs that were read before the cycle was
+    /// detected.
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+}
+
 impl<K> fmt::Display for CycleError<K>
 where
     K: fmt::Debug,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "Internal error, cycle detected:\n")?;
+        writeln!(f, "cycle detected:")?;
         for i in &self.cycle {
-            writeln!(f, "{:?}", i)?;
+            writeln!(f, "    {:?}", i)?;
         }
         Ok(())
     }
 }
 
+impl<K> std::error::Error for CycleError<K> where K: fmt::Debug {}
+
+impl CycleError<DatabaseKeyIndex> {
+    /// Fails the query that is being fetched with this error. Inside of
+    /// another query, this unwinds to the closest `try_get`, so that the
+    /// error can still be returned by it; otherwise it panics.
+    fn throw<D>(self, db: &D) -> !
+    where
+        D: ?Sized + Database,
+    {
+        if db.salsa_runtime().active_query().is_some() {
+            std::panic::resume_unwind(Box::new(self))
+        }
+        panic!("{}", self.report(db))
+    }
+
+    /// Takes the error out of a panic `payload` thrown by `throw`, or
+    /// resumes the panic if it is some other panic.
+    fn from_panic(payload: Box<dyn std::any::Any + Send>) -> Self {
+        match payload.downcast::<Self>() {
+            Ok(err) => *err,
+            Err(payload) => std::panic::resume_unwind(payload),
+        }
+    }
+}
+
 /// A boxed future used in the salsa traits
 pub type BoxFuture<'a, T> = std::pin::Pin<Box<dyn std::future::Future<Output = T> + Send + 'a>>;
 
@@ -931,6 +1621,10 @@ where
         self.db.sweep_all(strategy)
     }
 
+    fn compact_all(&self) {
+        self.db.compact_all()
+    }
+
     fn salsa_event(&self, event_fn: Event) {
         self.db.salsa_event(event_fn)
     }
@@ -1,7 +1,8 @@
 use parking_lot::Mutex;
 use oorandom::Rand64;
+use crate::{DatabaseKeyIndex, Runtime};
 use std::fmt::Debug;
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
@@ -15,38 +16,126 @@ use std::sync::Arc;
 /// `LruNode`, which is a trait that gives access to a field that
 /// stores the index in the list. This index gives us a rough idea of
 /// how recently the node has been used.
+///
+/// The list is limited either to a number of nodes (see
+/// `set_lru_capacity`) or to a total weight (see
+/// `set_weight_capacity`). In the latter case the zones are not a fixed
+/// size but a fixed fraction of the nodes currently in the list.
 #[derive(Debug)]
 pub(crate) struct Lru<Node>
 where
-    Node: LruNode,
+    Node: LruNode + ?Sized,
 {
     green_zone: AtomicUsize,
     data: Mutex<LruData<Node>>,
 }
 
+/// Statistics about the LRU list of a derived query, as returned by
+/// [`QueryTable::lru_stats`](crate::QueryTable::lru_stats).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LruStats {
+    /// The number of values the list may hold, or zero if the list is
+    /// disabled or limited by weight.
+    pub capacity: usize,
+    /// The total weight the list may hold, or zero if it is not limited
+    /// by weight.
+    pub weight_capacity: usize,
+    /// The number of values currently in the list.
+    pub len: usize,
+    /// The total weight of the values currently in the list, or zero if
+    /// it is not limited by weight.
+    pub weight: usize,
+    /// The number of values in the green zone, the most recently used
+    /// 10% of the list. These are never evicted. Only the
+    /// [`ApproximateLru`](crate::EvictionPolicy::ApproximateLru) policy
+    /// has zones; the others count all their values in the red zone.
+    pub green_zone: usize,
+    /// The number of values in the yellow zone, the next 20% of the list.
+    pub yellow_zone: usize,
+    /// The number of values in the red zone, the rest of the list. Values
+    /// are evicted at random from this zone.
+    pub red_zone: usize,
+    /// The number of values evicted to make room for others.
+    pub evictions: u64,
+    /// The number of times the query was executed again because its
+    /// value had been evicted, either by the list or by the memory
+    /// budget. Unlike the other counters, this one belongs to the query
+    /// rather than to its list, so it is kept when the eviction policy
+    /// or the capacity changes.
+    pub evicted_executions: u64,
+}
+
 #[derive(Debug)]
-struct LruData<Node> {
+struct LruData<Node: ?Sized> {
     end_red_zone: usize,
     end_yellow_zone: usize,
     end_green_zone: usize,
+    /// The total weight allowed, or zero if the list is limited to a
+    /// number of nodes instead.
+    max_weight: usize,
+    total_weight: usize,
+    evictions: u64,
     rng: Rand64,
     entries: Vec<Arc<Node>>,
 }
 
-pub(crate) trait LruNode: Sized + Debug {
+/// A node of an eviction list, such as the slot of a derived query.
+/// The list keeps its own state about the node in the `LruIndex`.
+pub trait LruNode: Debug {
+    /// The state of the node in its list.
     fn lru_index(&self) -> &LruIndex;
 }
 
+/// A node of the database-wide list that enforces the memory budget
+/// set with `Runtime::set_memory_budget`. Nodes of different queries
+/// share the list, so they are kept as trait objects.
+pub(crate) trait BudgetNode: Debug + Send + Sync {
+    fn budget_index(&self) -> &LruIndex;
+
+    fn database_key_index(&self) -> DatabaseKeyIndex;
+
+    /// Discards the value of the node.
+    fn evict(&self, runtime: &Runtime) -> Eviction;
+}
+
+/// What evicting a node did to its value.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Eviction {
+    /// The value, or the whole memo, was discarded.
+    Evicted,
+    /// There was no value to discard.
+    Empty,
+    /// The value was kept, because the memo read an untracked input and
+    /// executing it again in the revision it was verified in could
+    /// produce a different value.
+    Kept,
+}
+
+impl LruNode for dyn BudgetNode {
+    fn lru_index(&self) -> &LruIndex {
+        self.budget_index()
+    }
+}
+
+/// The state that an eviction list keeps about each of its nodes, see
+/// [`EvictionList`](crate::EvictionList).
 #[derive(Debug)]
-pub(crate) struct LruIndex {
+pub struct LruIndex {
     /// Index in the approprate LRU list, or std::usize::MAX if not a
     /// member.
     index: AtomicUsize,
+
+    /// The weight the node was added to the list with.
+    weight: AtomicUsize,
+
+    /// Whether the node was used since the hand of a `Clock` list last
+    /// passed it.
+    referenced: AtomicBool,
 }
 
 impl<Node> Default for Lru<Node>
 where
-    Node: LruNode,
+    Node: LruNode + ?Sized,
 {
     fn default() -> Self {
         Lru::new()
@@ -59,7 +148,7 @@ const LRU_SEED: &str = "Hello, Rustaceans";
 
 impl<Node> Lru<Node>
 where
-    Node: LruNode,
+    Node: LruNode + ?Sized,
 {
     /// Creates a new LRU list where LRU caching is disabled.
     pub fn new() -> Self {
@@ -78,6 +167,7 @@ where
     /// once.  If `len` is zero, this disables LRU caching completely.
     pub fn set_lru_capacity(&self, len: usize) {
         let mut data = self.data.lock();
+        data.max_weight = 0;
 
         // We require each zone to have at least 1 slot. Therefore,
         // the length cannot be just 1 or 2.
@@ -128,15 +218,107 @@ where
         self.data.lock().record_use(node)
     }
 
+    /// Adjust the total weight of the nodes permitted to have a value at
+    /// once. If `max_weight` is zero, this disables LRU caching
+    /// completely.
+    pub fn set_weight_capacity(&self, max_weight: usize) {
+        let mut data = self.data.lock();
+        if max_weight == 0 {
+            self.green_zone.store(0, Ordering::Release);
+            data.max_weight = 0;
+            data.resize(0, 0, 0);
+        } else {
+            if data.max_weight == 0 {
+                data.resize(0, 0, 0);
+            }
+            data.max_weight = max_weight;
+            self.green_zone.store(data.end_green_zone.max(1), Ordering::Release);
+        }
+    }
+
+    /// Records that `node` was used and now has the given `weight`. This
+    /// may displace old nodes, until the total weight is within the
+    /// capacity set with `set_weight_capacity`.
+    pub fn record_weighted_use(&self, node: &Arc<Node>, weight: usize) -> Vec<Arc<Node>> {
+        let green_zone = self.green_zone.load(Ordering::Acquire);
+        if green_zone == 0 {
+            return Vec::new();
+        }
+
+        // Already in the green zone with the same weight -- nothing to do!
+        let index = node.lru_index();
+        if index.load() < green_zone && index.weight() == weight {
+            return Vec::new();
+        }
+
+        let mut data = self.data.lock();
+        let evicted = data.record_weighted_use(node, weight);
+        if data.max_weight != 0 {
+            self.green_zone.store(data.end_green_zone.max(1), Ordering::Release);
+        }
+        evicted
+    }
+
+    /// Removes `node` from the list, along with its weight, returning
+    /// whether it was a member.
+    pub fn remove(&self, node: &Arc<Node>) -> bool {
+        if !node.lru_index().is_in_lru() {
+            return false;
+        }
+
+        let mut data = self.data.lock();
+        let removed = data.remove(node);
+        if removed && data.max_weight != 0 {
+            self.green_zone.store(data.end_green_zone.max(1), Ordering::Release);
+        }
+        removed
+    }
+
+    /// Adds back a node that `record_weighted_use` evicted, but that
+    /// kept its value anyway, with the weight it had. The node goes to
+    /// the end of the list and nothing is evicted to make room for it.
+    pub fn reinsert(&self, node: &Arc<Node>) {
+        let mut data = self.data.lock();
+        if data.max_weight == 0 || node.lru_index().is_in_lru() {
+            return;
+        }
+        data.reinsert(node);
+        self.green_zone.store(data.end_green_zone.max(1), Ordering::Release);
+    }
+
     pub fn purge(&self) {
         self.green_zone.store(0, Ordering::SeqCst);
         *self.data.lock() = LruData::with_seed(LRU_SEED);
     }
+
+    pub fn stats(&self) -> LruStats {
+        let data = self.data.lock();
+        let len = data.entries.len();
+        let end_green_zone = std::cmp::min(data.end_green_zone, len);
+        let end_yellow_zone = std::cmp::min(data.end_yellow_zone, len);
+        LruStats {
+            capacity: if data.max_weight == 0 { data.end_red_zone } else { 0 },
+            weight_capacity: data.max_weight,
+            len,
+            weight: data.total_weight,
+            green_zone: end_green_zone,
+            yellow_zone: end_yellow_zone - end_green_zone,
+            red_zone: len - end_yellow_zone,
+            evictions: data.evictions,
+            // Counted by the query, which executes the values again.
+            ..LruStats::default()
+        }
+    }
+
+    /// The bytes allocated for the list of nodes.
+    pub fn heap_size(&self) -> usize {
+        self.data.lock().entries.capacity() * std::mem::size_of::<Arc<Node>>()
+    }
 }
 
 impl<Node> LruData<Node>
 where
-    Node: LruNode,
+    Node: LruNode + ?Sized,
 {
     fn with_seed(seed_str: &str) -> Self {
         Self::with_rng(rng_with_seed(seed_str))
@@ -147,6 +329,9 @@ where
             end_yellow_zone: 0,
             end_green_zone: 0,
             end_red_zone: 0,
+            max_weight: 0,
+            total_weight: 0,
+            evictions: 0,
             entries: Vec::new(),
             rng,
         }
@@ -169,6 +354,7 @@ where
         self.end_yellow_zone = self.end_green_zone + len_yellow_zone;
         self.end_red_zone = self.end_yellow_zone + len_red_zone;
         let entries = std::mem::replace(&mut self.entries, Vec::with_capacity(self.end_red_zone));
+        self.total_weight = 0;
 
         log::debug!("green_zone = {:?}", self.green_zone());
         log::debug!("yellow_zone = {:?}", self.yellow_zone());
@@ -209,6 +395,101 @@ where
         }
     }
 
+    /// Sizes the zones for the nodes currently in a list that is limited
+    /// by weight: the top 10% is the green zone, the next 20% is the
+    /// yellow zone and the remainder is the red zone.
+    fn rezone(&mut self) {
+        let len = self.entries.len();
+        self.end_green_zone = std::cmp::min(std::cmp::max(len / 10, 1), len);
+        self.end_yellow_zone = std::cmp::min(self.end_green_zone + std::cmp::max(len / 5, 1), len);
+        self.end_red_zone = len;
+    }
+
+    /// Records that a node was used with the given weight, adding it to
+    /// the list if needed and promoting it to the green zone. Then
+    /// evicts nodes from the red zone (or the yellow zone, if the red
+    /// zone is empty) until the total weight fits.
+    fn record_weighted_use(&mut self, node: &Arc<Node>, weight: usize) -> Vec<Arc<Node>> {
+        if self.max_weight == 0 {
+            return self.record_use(node).into_iter().collect();
+        }
+
+        let index = node.lru_index().load();
+        if index < self.entries.len() {
+            self.total_weight -= node.lru_index().weight();
+        } else {
+            let len = self.entries.len();
+            self.entries.push(node.clone());
+            node.lru_index().store(len);
+            self.rezone();
+            log::debug!("inserted node {:?} at {}", node, len);
+        }
+        self.total_weight += weight;
+        node.lru_index().store_weight(weight);
+
+        let index = node.lru_index().load();
+        if self.yellow_zone().contains(&index) {
+            self.promote_yellow_to_green(node, index);
+        } else if self.red_zone().contains(&index) {
+            self.promote_red_to_green(node, index);
+        }
+
+        let mut evicted = Vec::new();
+        while self.total_weight > self.max_weight && self.entries.len() > 1 {
+            let zone = if self.red_zone().is_empty() {
+                self.yellow_zone()
+            } else {
+                self.red_zone()
+            };
+            let victim_index = self.pick_index(zone);
+            let victim_node = self.entries.swap_remove(victim_index);
+            if let Some(moved_node) = self.entries.get(victim_index) {
+                moved_node.lru_index().store(victim_index);
+            }
+            log::debug!("evicting node {:?} from {}", victim_node, victim_index);
+            victim_node.lru_index().clear();
+            self.total_weight -= victim_node.lru_index().weight();
+            self.rezone();
+            self.evictions += 1;
+            evicted.push(victim_node);
+        }
+        evicted
+    }
+
+    /// Removes `node`, if it is a member, by moving the last node of the
+    /// list into its place.
+    fn remove(&mut self, node: &Arc<Node>) -> bool {
+        // NB: The membership check before taking the lock may be out of
+        // date, so check again.
+        let index = node.lru_index().load();
+        match self.entries.get(index) {
+            Some(member) if Arc::ptr_eq(member, node) => {}
+            _ => return false,
+        }
+
+        self.entries.swap_remove(index);
+        if let Some(moved_node) = self.entries.get(index) {
+            moved_node.lru_index().store(index);
+        }
+        log::debug!("removed node {:?} from {}", node, index);
+        node.lru_index().clear();
+        if self.max_weight != 0 {
+            self.total_weight -= node.lru_index().weight();
+            self.rezone();
+        }
+        true
+    }
+
+    fn reinsert(&mut self, node: &Arc<Node>) {
+        let len = self.entries.len();
+        self.entries.push(node.clone());
+        node.lru_index().store(len);
+        self.total_weight += node.lru_index().weight();
+        self.evictions = self.evictions.saturating_sub(1);
+        self.rezone();
+        log::debug!("reinserted node {:?} at {}", node, len);
+    }
+
     /// Inserts a node that is not yet a member of the LRU list. If
     /// the list is at capacity, this can displace an existing member.
     fn insert_new(&mut self, node: &Arc<Node>) -> Option<Arc<Node>> {
@@ -230,6 +511,7 @@ where
         let victim_node = std::mem::replace(&mut self.entries[victim_index], node.clone());
         log::debug!("evicting red node {:?} from {}", victim_node, victim_index);
         victim_node.lru_index().clear();
+        self.evictions += 1;
         self.promote_red_to_green(node, victim_index);
         Some(victim_node)
     }
@@ -296,26 +578,54 @@ impl Default for LruIndex {
     fn default() -> Self {
         Self {
             index: AtomicUsize::new(std::usize::MAX),
+            weight: AtomicUsize::new(0),
+            referenced: AtomicBool::new(false),
         }
     }
 }
 
 impl LruIndex {
-    fn load(&self) -> usize {
+    /// The position of the node in its list, or `usize::MAX` if it is
+    /// not a member.
+    pub fn load(&self) -> usize {
         self.index.load(Ordering::Acquire) // see note on ordering below
     }
 
-    fn store(&self, value: usize) {
+    /// Sets the position of the node in its list.
+    pub fn store(&self, value: usize) {
         self.index.store(value, Ordering::Release) // see note on ordering below
     }
 
-    fn clear(&self) {
+    /// Marks the node as not being a member of its list.
+    pub fn clear(&self) {
         self.store(std::usize::MAX);
     }
 
-    fn is_in_lru(&self) -> bool {
+    /// Whether the node is a member of its list.
+    pub fn is_in_lru(&self) -> bool {
         self.load() != std::usize::MAX
     }
+
+    /// The weight the node was added to its list with.
+    pub fn weight(&self) -> usize {
+        self.weight.load(Ordering::Acquire)
+    }
+
+    /// Sets the weight the node was added to its list with.
+    pub fn store_weight(&self, weight: usize) {
+        self.weight.store(weight, Ordering::Release)
+    }
+
+    /// Sets a bit that lists may use to tell whether the node was used
+    /// recently, like the `Clock` policy does.
+    pub fn set_referenced(&self, referenced: bool) {
+        self.referenced.store(referenced, Ordering::Release)
+    }
+
+    /// Clears the referenced bit, returning whether it was set.
+    pub fn take_referenced(&self) -> bool {
+        self.referenced.swap(false, Ordering::AcqRel)
+    }
 }
 
 fn rng_with_seed(seed_str: &str) -> Rand64 {
@@ -0,0 +1,268 @@
+//! Estimates of how much memory each query table uses.
+//!
+//! [`report`] walks every query in a database and counts its slots,
+//! memoized values and dependency edges, along with an estimate of the
+//! bytes they take up. By default the estimate only covers the inline
+//! size of keys and values; queries whose key and value types implement
+//! [`HeapSize`] can opt into counting their heap allocations as well
+//! with [`QueryTableMut::enable_heap_size`](crate::QueryTableMut::enable_heap_size).
+
+use crate::{Database, DatabaseKeyIndex, InternId, Revision};
+use std::fmt;
+use std::mem::size_of;
+use std::sync::Arc;
+
+/// Types that can report how many bytes they own on the heap, not
+/// counting their own inline size.
+///
+/// Shared pointers such as `Arc` count the whole allocation they point
+/// to, so data shared between several keys or values is counted once
+/// for each of them.
+pub trait HeapSize {
+    /// The number of heap bytes owned by `self`.
+    fn heap_size(&self) -> usize;
+}
+
+macro_rules! heap_size_inline {
+    ($($t:ty),*) => {
+        $(
+            impl HeapSize for $t {
+                fn heap_size(&self) -> usize {
+                    0
+                }
+            }
+        )*
+    };
+}
+
+heap_size_inline!(
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    bool,
+    char,
+    (),
+    &'static str,
+    InternId,
+    Revision,
+    DatabaseKeyIndex
+);
+
+impl HeapSize for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl HeapSize for Box<str> {
+    fn heap_size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl HeapSize for Arc<str> {
+    fn heap_size(&self) -> usize {
+        ARC_HEADER + self.len()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Vec<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * size_of::<T>() + self.iter().map(T::heap_size).sum::<usize>()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Box<[T]> {
+    fn heap_size(&self) -> usize {
+        self.len() * size_of::<T>() + self.iter().map(T::heap_size).sum::<usize>()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Arc<[T]> {
+    fn heap_size(&self) -> usize {
+        ARC_HEADER + self.len() * size_of::<T>() + self.iter().map(T::heap_size).sum::<usize>()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Option<T> {
+    fn heap_size(&self) -> usize {
+        self.as_ref().map_or(0, T::heap_size)
+    }
+}
+
+impl<T: HeapSize> HeapSize for Box<T> {
+    fn heap_size(&self) -> usize {
+        size_of::<T>() + (**self).heap_size()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Arc<T> {
+    fn heap_size(&self) -> usize {
+        ARC_HEADER + size_of::<T>() + (**self).heap_size()
+    }
+}
+
+macro_rules! heap_size_tuple {
+    ($($t:ident),*) => {
+        impl<$($t: HeapSize),*> HeapSize for ($($t,)*) {
+            #[allow(non_snake_case)]
+            fn heap_size(&self) -> usize {
+                let ($($t,)*) = self;
+                0 $(+ $t.heap_size())*
+            }
+        }
+    };
+}
+
+heap_size_tuple!(A);
+heap_size_tuple!(A, B);
+heap_size_tuple!(A, B, C);
+heap_size_tuple!(A, B, C, D);
+heap_size_tuple!(A, B, C, D, E);
+heap_size_tuple!(A, B, C, D, E, F);
+
+/// The reference counts stored in front of the data of an `Arc`.
+pub(crate) const ARC_HEADER: usize = 2 * size_of::<usize>();
+
+/// The bytes taken up by each entry of an `FxHashMap<K, V>`: the entry
+/// itself and its control byte.
+pub(crate) fn hash_map_entry<K, V>() -> usize {
+    size_of::<K>() + size_of::<V>() + 1
+}
+
+/// The heap size functions of a query's key and value type. Queries
+/// that have not opted in count nothing on the heap.
+pub(crate) struct HeapSizeVtable<K, V> {
+    pub(crate) key: fn(&K) -> usize,
+    pub(crate) value: fn(&V) -> usize,
+}
+
+impl<K, V> HeapSizeVtable<K, V>
+where
+    K: HeapSize,
+    V: HeapSize,
+{
+    pub(crate) fn new() -> Self {
+        HeapSizeVtable {
+            key: K::heap_size,
+            value: V::heap_size,
+        }
+    }
+}
+
+impl<K, V> Default for HeapSizeVtable<K, V> {
+    fn default() -> Self {
+        HeapSizeVtable {
+            key: |_| 0,
+            value: |_| 0,
+        }
+    }
+}
+
+impl<K, V> Clone for HeapSizeVtable<K, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K, V> Copy for HeapSizeVtable<K, V> {}
+
+/// The memory used by a single query table, as returned by [`report`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryMemory {
+    /// The type name of the query group the query belongs to.
+    pub group_name: &'static str,
+    /// The name of the query.
+    pub query_name: &'static str,
+    /// The number of keys in the table.
+    pub slots: usize,
+    /// The number of keys that currently have a value.
+    pub values: usize,
+    /// The number of dependencies recorded by the memos in the table.
+    pub edges: usize,
+    /// The estimated number of bytes used by the table, including its
+    /// keys, values, dependency lists and LRU bookkeeping.
+    pub bytes: usize,
+}
+
+impl QueryMemory {
+    pub(crate) fn new(group_name: &'static str, query_name: &'static str) -> Self {
+        QueryMemory {
+            group_name,
+            query_name,
+            slots: 0,
+            values: 0,
+            edges: 0,
+            bytes: 0,
+        }
+    }
+}
+
+/// The memory used by the query tables of a database, largest first.
+/// Displays as a table.
+#[derive(Clone, Debug)]
+pub struct MemoryReport {
+    queries: Vec<QueryMemory>,
+}
+
+impl MemoryReport {
+    /// The memory used by every query, largest first.
+    pub fn queries(&self) -> &[QueryMemory] {
+        &self.queries
+    }
+
+    /// Returns the memory used by the query named `query_name`, if any.
+    pub fn query(&self, query_name: &str) -> Option<&QueryMemory> {
+        self.queries
+            .iter()
+            .find(|memory| memory.query_name == query_name)
+    }
+
+    /// The estimated number of bytes used by all queries together.
+    pub fn total_bytes(&self) -> usize {
+        self.queries.iter().map(|memory| memory.bytes).sum()
+    }
+}
+
+impl fmt::Display for MemoryReport {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            fmt,
+            "{:<32} {:>10} {:>10} {:>10} {:>12}",
+            "query", "slots", "values", "edges", "bytes",
+        )?;
+        for memory in &self.queries {
+            writeln!(
+                fmt,
+                "{:<32} {:>10} {:>10} {:>10} {:>12}",
+                memory.query_name, memory.slots, memory.values, memory.edges, memory.bytes,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Estimates the memory used by every query table in `db`, largest
+/// first.
+pub fn report<DB>(db: &DB) -> MemoryReport
+where
+    DB: ?Sized + Database,
+{
+    let mut queries = Vec::new();
+    db.for_each_query(&mut |query_storage| {
+        if let Some(memory) = query_storage.memory_usage() {
+            queries.push(memory);
+        }
+    });
+    queries.sort_by_key(|memory| std::cmp::Reverse(memory.bytes));
+    MemoryReport { queries }
+}
@@ -0,0 +1,652 @@
+//! Persisting memoized query results across process restarts.
+//!
+//! Persistence is opt-in on a per-query basis: call
+//! [`enable_persistence`](crate::QueryTableMut::enable_persistence) for
+//! every input and derived query whose key and value implement
+//! [`Persist`]. Afterwards, [`save`] writes the revision counters along
+//! with the state of each enabled table, and [`load`] restores them into
+//! a freshly created database. Queries that did not opt in are skipped
+//! when saving and simply start out empty after loading.
+//!
+//! Loading leaves the database one revision ahead of the saved one, as
+//! if some low-durability input had changed. The restored memos are
+//! therefore re-validated against their inputs the first time they are
+//! needed instead of being re-executed. Memos that read from a query
+//! which was not persisted (including interned queries, whose ids are
+//! not stable across processes) are treated as having untracked inputs
+//! and will be re-executed.
+//!
+//! Dependency edges are saved as [`StableKey`]s rather than as
+//! [`DatabaseKeyIndex`]es, since the latter only describe where a key
+//! happened to be stored in the database that saved them.
+//!
+//! Query groups are identified by the [`type_name`](std::any::type_name)
+//! of their group struct, which is not guaranteed to be the same across
+//! compilers. The header therefore records the version of `rustc` that
+//! built the saving program, and [`load`] rejects databases saved by a
+//! different one.
+
+use crate::durability::Durability;
+use crate::revision::Revision;
+use crate::runtime::FxIndexSet;
+use crate::{Database, DatabaseKeyIndex, QueryBase};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+const MAGIC: &[u8; 8] = b"salsa-db";
+const VERSION: u32 = 3;
+const RUSTC_VERSION: &str = env!("SALSA_RUSTC_VERSION");
+
+/// A type that can be written to, and read back from, a persisted
+/// database.
+///
+/// The encoding only has to round-trip between runs of the same
+/// program; it is not meant to be a stable interchange format.
+pub trait Persist: Sized {
+    /// Appends the encoding of `self` to `out`.
+    fn persist(&self, out: &mut Vec<u8>);
+
+    /// Decodes a value from the front of `input`, advancing `input`
+    /// past the bytes that were consumed.
+    fn restore(input: &mut &[u8]) -> io::Result<Self>;
+}
+
+pub(crate) fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if input.len() < len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "persisted data ended unexpectedly",
+        ));
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    Ok(bytes)
+}
+
+fn restore_len(input: &mut &[u8]) -> io::Result<usize> {
+    usize::try_from(u64::restore(input)?).map_err(|_| invalid_data("length out of range"))
+}
+
+macro_rules! persist_int {
+    ($($t:ty),*) => {
+        $(
+            impl Persist for $t {
+                fn persist(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn restore(input: &mut &[u8]) -> io::Result<Self> {
+                    let mut bytes = [0; std::mem::size_of::<$t>()];
+                    bytes.copy_from_slice(take(input, std::mem::size_of::<$t>())?);
+                    Ok(<$t>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+persist_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl Persist for usize {
+    fn persist(&self, out: &mut Vec<u8>) {
+        (*self as u64).persist(out)
+    }
+
+    fn restore(input: &mut &[u8]) -> io::Result<Self> {
+        restore_len(input)
+    }
+}
+
+impl Persist for isize {
+    fn persist(&self, out: &mut Vec<u8>) {
+        (*self as i64).persist(out)
+    }
+
+    fn restore(input: &mut &[u8]) -> io::Result<Self> {
+        isize::try_from(i64::restore(input)?).map_err(|_| invalid_data("isize out of range"))
+    }
+}
+
+impl Persist for bool {
+    fn persist(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8)
+    }
+
+    fn restore(input: &mut &[u8]) -> io::Result<Self> {
+        match u8::restore(input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid_data("invalid bool")),
+        }
+    }
+}
+
+impl Persist for char {
+    fn persist(&self, out: &mut Vec<u8>) {
+        u32::from(*self).persist(out)
+    }
+
+    fn restore(input: &mut &[u8]) -> io::Result<Self> {
+        char::try_from(u32::restore(input)?).map_err(|_| invalid_data("invalid char"))
+    }
+}
+
+impl Persist for () {
+    fn persist(&self, _out: &mut Vec<u8>) {}
+
+    fn restore(_input: &mut &[u8]) -> io::Result<Self> {
+        Ok(())
+    }
+}
+
+impl Persist for String {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.len().persist(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn restore(input: &mut &[u8]) -> io::Result<Self> {
+        let len = restore_len(input)?;
+        let bytes = take(input, len)?;
+        String::from_utf8(bytes.to_owned()).map_err(|_| invalid_data("invalid utf-8"))
+    }
+}
+
+impl Persist for Arc<str> {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.len().persist(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn restore(input: &mut &[u8]) -> io::Result<Self> {
+        String::restore(input).map(Arc::from)
+    }
+}
+
+impl<T: Persist> Persist for Vec<T> {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.len().persist(out);
+        for item in self {
+            item.persist(out);
+        }
+    }
+
+    fn restore(input: &mut &[u8]) -> io::Result<Self> {
+        let len = restore_len(input)?;
+        // Don't trust `len` for the allocation: a corrupt file could
+        // otherwise make us reserve an absurd amount of memory.
+        let mut vec = Vec::with_capacity(len.min(input.len()));
+        for _ in 0..len {
+            vec.push(T::restore(input)?);
+        }
+        Ok(vec)
+    }
+}
+
+impl<T: Persist> Persist for Option<T> {
+    fn persist(&self, out: &mut Vec<u8>) {
+        match self {
+            None => out.push(0),
+            Some(value) => {
+                out.push(1);
+                value.persist(out);
+            }
+        }
+    }
+
+    fn restore(input: &mut &[u8]) -> io::Result<Self> {
+        match u8::restore(input)? {
+            0 => Ok(None),
+            1 => T::restore(input).map(Some),
+            _ => Err(invalid_data("invalid option tag")),
+        }
+    }
+}
+
+impl<T: Persist> Persist for Box<T> {
+    fn persist(&self, out: &mut Vec<u8>) {
+        (**self).persist(out)
+    }
+
+    fn restore(input: &mut &[u8]) -> io::Result<Self> {
+        T::restore(input).map(Box::new)
+    }
+}
+
+impl<T: Persist> Persist for Arc<T> {
+    fn persist(&self, out: &mut Vec<u8>) {
+        (**self).persist(out)
+    }
+
+    fn restore(input: &mut &[u8]) -> io::Result<Self> {
+        T::restore(input).map(Arc::new)
+    }
+}
+
+macro_rules! persist_tuple {
+    ($($t:ident),*) => {
+        impl<$($t: Persist),*> Persist for ($($t,)*) {
+            #[allow(non_snake_case)]
+            fn persist(&self, out: &mut Vec<u8>) {
+                let ($($t,)*) = self;
+                $($t.persist(out);)*
+            }
+
+            fn restore(input: &mut &[u8]) -> io::Result<Self> {
+                Ok(($($t::restore(input)?,)*))
+            }
+        }
+    };
+}
+
+persist_tuple!(A);
+persist_tuple!(A, B);
+persist_tuple!(A, B, C);
+persist_tuple!(A, B, C, D);
+persist_tuple!(A, B, C, D, E);
+persist_tuple!(A, B, C, D, E, F);
+
+impl Persist for Revision {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.as_usize().persist(out)
+    }
+
+    fn restore(input: &mut &[u8]) -> io::Result<Self> {
+        match usize::restore(input)? {
+            0 => Err(invalid_data("invalid revision")),
+            g => Ok(Revision::from(g)),
+        }
+    }
+}
+
+impl Persist for Durability {
+    fn persist(&self, out: &mut Vec<u8>) {
+        (self.index() as u8).persist(out)
+    }
+
+    fn restore(input: &mut &[u8]) -> io::Result<Self> {
+        let index = u8::restore(input)? as usize;
+        if index < Durability::LEN {
+            Ok(Durability::from_index(index))
+        } else {
+            Err(invalid_data("invalid durability"))
+        }
+    }
+}
+
+/// Encoding functions for the key and value of a query that opted
+/// into persistence. Storing these as function pointers lets the
+/// storage persist itself without requiring `Persist` on every query.
+pub(crate) struct PersistVtable<K, V> {
+    pub(crate) persist_key: fn(&K, &mut Vec<u8>),
+    pub(crate) restore_key: fn(&mut &[u8]) -> io::Result<K>,
+    pub(crate) persist_value: fn(&V, &mut Vec<u8>),
+    pub(crate) restore_value: fn(&mut &[u8]) -> io::Result<V>,
+}
+
+impl<K, V> PersistVtable<K, V>
+where
+    K: Persist,
+    V: Persist,
+{
+    pub(crate) fn new() -> Self {
+        PersistVtable {
+            persist_key: K::persist,
+            restore_key: K::restore,
+            persist_value: V::persist,
+            restore_value: V::restore,
+        }
+    }
+}
+
+impl<K, V> PersistVtable<K, V> {
+    pub(crate) fn key_bytes(&self, key: &K) -> Vec<u8> {
+        let mut out = Vec::new();
+        (self.persist_key)(key, &mut out);
+        out
+    }
+}
+
+impl<K, V> Clone for PersistVtable<K, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K, V> Copy for PersistVtable<K, V> {}
+
+/// A stable identity for a query key, made up of the name of its query
+/// group, the name of its query and the persisted encoding of the key.
+///
+/// Unlike a [`DatabaseKeyIndex`], which only records where the key
+/// happens to live in the current database, a `StableKey` means the
+/// same thing in every run of the same program. It can therefore be
+/// saved, compared across runs and later mapped back with
+/// [`StableKey::resolve`].
+///
+/// Stable keys are only available for queries that have persistence
+/// enabled, since those are the ones that know how to encode their keys.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StableKey {
+    group_name: String,
+    query_name: String,
+    key: Vec<u8>,
+}
+
+impl StableKey {
+    pub(crate) fn new(group_name: &str, query_name: &str, key: Vec<u8>) -> Self {
+        StableKey {
+            group_name: group_name.to_owned(),
+            query_name: query_name.to_owned(),
+            key,
+        }
+    }
+
+    /// Returns the name of the query group containing this key.
+    pub fn group_name(&self) -> &str {
+        &self.group_name
+    }
+
+    /// Returns the name of the query this key belongs to.
+    pub fn query_name(&self) -> &str {
+        &self.query_name
+    }
+
+    /// Returns the persisted encoding of the key itself.
+    pub fn key_bytes(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Finds the `DatabaseKeyIndex` that this key currently has in `db`.
+    /// Returns `None` if the query does not have persistence enabled or
+    /// if the key is not present in its table.
+    pub fn resolve<DB>(&self, db: &DB) -> Option<DatabaseKeyIndex>
+    where
+        DB: ?Sized + Database,
+    {
+        let mut result = None;
+        db.for_each_query(&mut |query_storage| {
+            if result.is_none() {
+                result = query_storage.resolve_stable_key(self);
+            }
+        });
+        result
+    }
+
+    pub(crate) fn is_query(&self, id: &PersistedQueryId) -> bool {
+        self.group_name == id.group_name && self.query_name == id.query_name
+    }
+}
+
+impl Persist for StableKey {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.group_name.persist(out);
+        self.query_name.persist(out);
+        self.key.len().persist(out);
+        out.extend_from_slice(&self.key);
+    }
+
+    fn restore(input: &mut &[u8]) -> io::Result<Self> {
+        let group_name = String::restore(input)?;
+        let query_name = String::restore(input)?;
+        let len = restore_len(input)?;
+        let key = take(input, len)?.to_owned();
+        Ok(StableKey {
+            group_name,
+            query_name,
+            key,
+        })
+    }
+}
+
+/// Decodes a value that must make up all of `bytes`.
+pub(crate) fn restore_exact<T>(
+    restore: fn(&mut &[u8]) -> io::Result<T>,
+    mut bytes: &[u8],
+) -> io::Result<T> {
+    let value = restore(&mut bytes)?;
+    if !bytes.is_empty() {
+        return Err(invalid_data("trailing data in persisted value"));
+    }
+    Ok(value)
+}
+
+/// Identifies a query table that has persistence enabled. Opaque to users.
+#[derive(Copy, Clone, Debug)]
+pub struct PersistedQueryId {
+    group_index: u16,
+    query_index: u16,
+    group_name: &'static str,
+    query_name: &'static str,
+}
+
+impl PersistedQueryId {
+    pub(crate) fn of<Q: QueryBase>(group_index: u16) -> Self {
+        PersistedQueryId {
+            group_index,
+            query_index: Q::QUERY_INDEX,
+            group_name: std::any::type_name::<Q::Group>(),
+            query_name: Q::QUERY_NAME,
+        }
+    }
+
+    pub(crate) fn group_name(&self) -> &'static str {
+        self.group_name
+    }
+
+    pub(crate) fn query_name(&self) -> &'static str {
+        self.query_name
+    }
+
+    /// Returns the `DatabaseKeyIndex` of the key at `key_index` in this
+    /// query.
+    pub(crate) fn key(&self, key_index: u32) -> DatabaseKeyIndex {
+        DatabaseKeyIndex {
+            group_index: self.group_index,
+            query_index: self.query_index,
+            key_index,
+        }
+    }
+
+    pub(crate) fn contains(&self, index: DatabaseKeyIndex) -> bool {
+        index.group_index == self.group_index && index.query_index == self.query_index
+    }
+}
+
+/// The persisted state of a single query table. Opaque to users.
+///
+/// Keys are stored separately from the rest of the table so that the
+/// dependency edges, which are recorded as [`StableKey`]s, can be mapped
+/// to the position of their key before any table is restored.
+pub struct PersistedQuery {
+    group_name: String,
+    query_name: String,
+    keys: FxIndexSet<Vec<u8>>,
+    data: Vec<u8>,
+}
+
+impl PersistedQuery {
+    pub(crate) fn new(id: &PersistedQueryId, keys: Vec<Vec<u8>>, data: Vec<u8>) -> Self {
+        PersistedQuery {
+            group_name: id.group_name.to_owned(),
+            query_name: id.query_name.to_owned(),
+            keys: keys.into_iter().collect(),
+            data,
+        }
+    }
+
+    /// The encoded keys of the table, in the order they are restored in.
+    pub(crate) fn keys(&self) -> impl ExactSizeIterator<Item = &[u8]> {
+        self.keys.iter().map(|key| &key[..])
+    }
+
+    pub(crate) fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn is_query(&self, id: &PersistedQueryId) -> bool {
+        self.group_name == id.group_name && self.query_name == id.query_name
+    }
+}
+
+impl Persist for PersistedQuery {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.group_name.persist(out);
+        self.query_name.persist(out);
+        self.keys.len().persist(out);
+        for key in &self.keys {
+            key.len().persist(out);
+            out.extend_from_slice(key);
+        }
+        self.data.len().persist(out);
+        out.extend_from_slice(&self.data);
+    }
+
+    fn restore(input: &mut &[u8]) -> io::Result<Self> {
+        let group_name = String::restore(input)?;
+        let query_name = String::restore(input)?;
+        let len = restore_len(input)?;
+        let mut keys = FxIndexSet::default();
+        for _ in 0..len {
+            let key_len = restore_len(input)?;
+            if !keys.insert(take(input, key_len)?.to_owned()) {
+                return Err(invalid_data("duplicate key in persisted query"));
+            }
+        }
+        let len = restore_len(input)?;
+        let data = take(input, len)?.to_owned();
+        Ok(PersistedQuery {
+            group_name,
+            query_name,
+            keys,
+            data,
+        })
+    }
+}
+
+/// All the query tables read from a persisted database that are being
+/// restored, along with the query they are being restored into.
+/// Opaque to users.
+pub struct PersistedTables {
+    queries: Vec<(PersistedQueryId, PersistedQuery)>,
+}
+
+impl PersistedTables {
+    /// Returns the persisted state of the given query, if it was saved.
+    pub(crate) fn get(&self, id: &PersistedQueryId) -> Option<&PersistedQuery> {
+        self.queries
+            .iter()
+            .find(|(_, query)| query.is_query(id))
+            .map(|(_, query)| query)
+    }
+
+    /// Maps a dependency edge to the `DatabaseKeyIndex` that its key
+    /// will have once the tables are restored. Edges into queries that
+    /// are not being restored cannot be validated and yield `None`.
+    pub(crate) fn resolve(&self, key: &StableKey) -> Option<DatabaseKeyIndex> {
+        let (id, query) = self.queries.iter().find(|(id, _)| key.is_query(id))?;
+        let key_index = query.keys.get_index_of(&key.key)?;
+        Some(id.key(u32::try_from(key_index).ok()?))
+    }
+}
+
+/// Writes every query that has persistence enabled, along with the
+/// current revision counters, to `writer`.
+pub fn save<DB>(db: &DB, mut writer: impl Write) -> io::Result<()>
+where
+    DB: ?Sized + Database,
+{
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    VERSION.persist(&mut out);
+    RUSTC_VERSION.to_owned().persist(&mut out);
+    db.salsa_runtime().revisions().persist(&mut out);
+
+    let mut queries = Vec::new();
+    db.for_each_query(&mut |query_storage| {
+        queries.extend(query_storage.persist(db.ops_database()))
+    });
+    queries.persist(&mut out);
+
+    writer.write_all(&out)
+}
+
+/// Restores a database previously written by [`save`] into `db`,
+/// which must be freshly created: no revision may have been created
+/// yet and the tables that have persistence enabled must be empty.
+///
+/// Tables are matched up by the names of their query group and query,
+/// so queries may be added, removed or reordered between the run that
+/// saved the database and the one that loads it. Since the names of
+/// query groups may differ between compilers, databases saved by a
+/// program built with a different version of `rustc` are rejected.
+///
+/// If an error is returned, `db` may have been partially restored and
+/// should be discarded.
+pub fn load<DB>(db: &mut DB, mut reader: impl Read) -> io::Result<()>
+where
+    DB: ?Sized + Database,
+{
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let mut input = &bytes[..];
+
+    if take(&mut input, MAGIC.len())? != MAGIC {
+        return Err(invalid_data("not a persisted salsa database"));
+    }
+    if u32::restore(&mut input)? != VERSION {
+        return Err(invalid_data("unsupported persisted database version"));
+    }
+    if String::restore(&mut input)? != RUSTC_VERSION {
+        return Err(invalid_data(
+            "persisted database was saved by a different compiler",
+        ));
+    }
+    let revisions = Vec::<Revision>::restore(&mut input)?;
+    let mut queries = Vec::<PersistedQuery>::restore(&mut input)?;
+    if !input.is_empty() {
+        return Err(invalid_data("trailing data after persisted database"));
+    }
+
+    // Only the queries that have persistence enabled in *this*
+    // database get restored; dependencies on anything else must be
+    // treated as untracked.
+    let mut enabled = Vec::new();
+    db.for_each_query(&mut |query_storage| enabled.extend(query_storage.persisted_query()));
+    let tables = PersistedTables {
+        queries: enabled
+            .into_iter()
+            .filter_map(|id| {
+                let index = queries.iter().position(|query| query.is_query(&id))?;
+                Some((id, queries.swap_remove(index)))
+            })
+            .collect(),
+    };
+
+    db.salsa_runtime_mut().restore_revisions(&revisions)?;
+
+    let mut result = Ok(());
+    db.for_each_query(&mut |query_storage| {
+        if result.is_ok() {
+            result = query_storage.restore(&tables);
+        }
+    });
+    result?;
+
+    if db.salsa_runtime().tracks_dependents() {
+        let runtime = db.salsa_runtime();
+        db.for_each_query(&mut |query_storage| query_storage.index_dependents(runtime));
+    }
+
+    // Start a new revision so that every restored memo gets
+    // re-validated before it is used. Memos with untracked inputs were
+    // restored with low durability, so they get re-executed.
+    db.synthetic_write(Durability::LOW);
+
+    Ok(())
+}
@@ -1,10 +1,18 @@
 #![allow(missing_docs)]
 
-use crate::debug::TableEntry;
+use crate::debug::{QueryGraphNode, TableEntry};
 use crate::durability::Durability;
+use crate::memory::QueryMemory;
+use crate::persist::{PersistedQuery, PersistedQueryId, PersistedTables, StableKey};
+#[cfg(feature = "profiling")]
+use crate::profile::QueryStats;
 use crate::AsAsyncDatabase;
 use crate::CycleError;
 use crate::Database;
+use crate::CustomEvictionPolicy;
+use crate::EvictionPolicy;
+use crate::ExecuteReason;
+use crate::LruStats;
 use crate::Query;
 use crate::QueryTable;
 use crate::QueryTableMut;
@@ -38,6 +46,31 @@ pub struct CycleDetected {
     pub(crate) to: RuntimeId,
 }
 
+/// An update to the queries that completed with a provisional value
+/// while the head of their cycle was iterated to a fixpoint, see
+/// [`QueryStorageMassOps::update_provisional`]. Opaque to users.
+#[derive(Debug)]
+pub struct ProvisionalUpdate(pub(crate) CycleUpdate);
+
+/// What becomes of the queries that completed with a provisional value.
+#[derive(Clone, Debug)]
+pub(crate) enum CycleUpdate {
+    /// `head` is executed again, so they must be too.
+    Reset { head: DatabaseKeyIndex },
+
+    /// The cycle converged, so their values are final. They are
+    /// memoized with the inputs of the cycle as a whole (`None` if one
+    /// was untracked) and its durability.
+    Memoize {
+        inputs: Option<Arc<[DatabaseKeyIndex]>>,
+        durability: Durability,
+    },
+
+    /// The cycle did not converge, or `panicked`, so they get their old
+    /// memo back.
+    Discard { panicked: bool },
+}
+
 /// Defines various associated types. An impl of this
 /// should be generated for your query-context type automatically by
 /// the `database_storage` macro, so you shouldn't need to mess
@@ -85,8 +118,57 @@ pub trait DatabaseOps {
 /// query, unlike `QueryStorageOps`).
 pub trait QueryStorageMassOps {
     /// Discards memoized values that are not up to date with the current revision.
-    fn sweep(&self, runtime: &Runtime, strategy: SweepStrategy);
-    fn purge(&self);
+    fn sweep(&self, db: &dyn Database, strategy: SweepStrategy);
+    fn purge(&self, runtime: &Runtime);
+
+    /// Removes the keys that no longer have anything memoized.
+    fn compact(&self, runtime: &Runtime);
+
+    /// Adds the inputs of every memo to the reverse dependency index.
+    fn index_dependents(&self, runtime: &Runtime);
+
+    /// Applies `update` to those of `participants` that belong to this
+    /// query, if `runtime` is iterating their cycle. Only derived
+    /// queries can have provisional values.
+    fn update_provisional(
+        &self,
+        _runtime: &Runtime,
+        _participants: &[DatabaseKeyIndex],
+        _update: &ProvisionalUpdate,
+    ) {
+    }
+
+    /// Reports every memoized value (or input value) in this query.
+    fn for_each_memo(&self, op: &mut dyn FnMut(QueryGraphNode));
+
+    /// Returns the profiling counters of this query, if it has any.
+    #[cfg(feature = "profiling")]
+    fn profile(&self) -> Option<QueryStats>;
+
+    /// Resets the profiling counters of this query.
+    #[cfg(feature = "profiling")]
+    fn reset_profile(&self);
+
+    /// Estimates the memory used by this query, if it stores anything.
+    fn memory_usage(&self) -> Option<QueryMemory>;
+
+    /// Identifies this query if it has persistence enabled.
+    fn persisted_query(&self) -> Option<PersistedQueryId>;
+
+    /// Encodes the contents of this query, if it has persistence enabled.
+    fn persist(&self, db: &dyn Database) -> Option<PersistedQuery>;
+
+    /// Restores the contents of this query from `tables`, if it has
+    /// persistence enabled and was saved.
+    fn restore(&self, tables: &PersistedTables) -> std::io::Result<()>;
+
+    /// Returns the stable identity of `index`, if it belongs to this
+    /// query and persistence is enabled.
+    fn stable_key(&self, index: DatabaseKeyIndex) -> Option<StableKey>;
+
+    /// Maps `key` back to its index, if it belongs to this query and is
+    /// present in the table.
+    fn resolve_stable_key(&self, key: &StableKey) -> Option<DatabaseKeyIndex>;
 }
 
 pub trait DatabaseKey: Clone + Debug + Eq + Hash {}
@@ -210,6 +292,13 @@ where
         C: std::iter::FromIterator<TableEntry<Q::Key, Q::Value>>;
 
     fn peek(&self, db: &<Q as QueryDb<'_>>::DynDb, _key: &Q::Key) -> Option<Q::Value>;
+
+    /// Returns the index of `key`, if it is present in the table.
+    fn database_key_index(
+        &self,
+        db: &<Q as QueryDb<'_>>::DynDb,
+        key: &Q::Key,
+    ) -> Option<DatabaseKeyIndex>;
 }
 
 pub trait QueryStorageOpsSync<Q>: QueryStorageOps<Q>
@@ -285,6 +374,29 @@ where
         new_value: Q::Value,
         durability: Durability,
     );
+
+    /// Like `set`, but does nothing and returns `false` if the key
+    /// already has an equal value with the same durability.
+    fn set_if_changed(
+        &self,
+        db: &mut <Q as QueryDb<'_>>::DynDb,
+        key: &Q::Key,
+        new_value: Q::Value,
+        durability: Durability,
+    ) -> bool
+    where
+        Q::Value: Eq;
+
+    fn remove(&self, db: &mut <Q as QueryDb<'_>>::DynDb, key: &Q::Key) -> Option<Q::Value>;
+
+    /// Reads the value of `key`, or `None` if it has none, as a
+    /// dependency of the active query.
+    fn get_opt(&self, db: &<Q as QueryDb<'_>>::DynDb, key: &Q::Key) -> Option<Q::Value>;
+
+    /// Reads the keys of the input, as a dependency of the active query.
+    fn keys<C>(&self, db: &<Q as QueryDb<'_>>::DynDb) -> C
+    where
+        C: std::iter::FromIterator<Q::Key>;
 }
 
 /// An optional trait that is implemented for "user mutable" storage:
@@ -294,11 +406,55 @@ pub trait LruQueryStorageOps {
     fn set_lru_capacity(&self, new_capacity: usize);
 }
 
+/// An optional trait that is implemented for storage that can be
+/// written out by `persist::save` and read back by `persist::load`.
+pub trait PersistentQueryStorageOps {
+    fn enable_persistence(&self);
+}
+
+/// An optional trait that is implemented for derived storage whose keys
+/// and values can be shared between threads.
+pub trait MemoryBudgetQueryStorageOps {
+    fn enable_memory_budget(&self);
+}
+
+/// An optional trait that is implemented for derived storage whose keys
+/// and values can be shared between threads, like the nodes of a
+/// custom eviction list have to be.
+pub trait CustomEvictionQueryStorageOps {
+    fn set_custom_eviction_policy(&self, policy: &impl CustomEvictionPolicy);
+}
+
+/// An optional trait that is implemented for storage whose keys and
+/// values implement `HeapSize`.
+pub trait HeapSizeQueryStorageOps {
+    fn enable_heap_size(&self);
+}
+
 pub trait DerivedQueryStorageOps<Q>
 where
     Q: Query,
 {
     fn invalidate(&self, db: &mut <Q as QueryDb<'_>>::DynDb, key: &Q::Key);
+
+    /// Limits the LRU list to a total `capacity`, where each value
+    /// weighs `weight(value)`.
+    fn set_lru_weight_capacity(&self, capacity: usize, weight: fn(&Q::Value) -> usize);
+
+    /// Returns why the memo for `key` was last executed, if known.
+    fn explain(&self, key: &Q::Key) -> Option<ExecuteReason>;
+
+    /// Lets the LRU list evict memos that read untracked inputs by
+    /// discarding them entirely.
+    fn set_lru_evict_untracked(&self, evict: bool);
+
+    fn set_eviction_policy(&self, policy: EvictionPolicy);
+
+    fn lru_stats(&self) -> LruStats;
+
+    /// Iterates cycles that this query is the head of until its value
+    /// stops changing, starting from `initial(key)`.
+    fn set_cycle_fixpoint(&self, initial: fn(&Q::Key) -> Q::Value, max_iterations: usize);
 }
 
 /// Calls a future synchronously without an actual way to resume to future.
@@ -0,0 +1,304 @@
+//! Per-query execution profiling.
+//!
+//! Counters are only collected when the `profiling` cargo feature is
+//! enabled; without it this module is empty and profiling costs
+//! nothing. Use [`report`] to get a snapshot of the counters of every
+//! derived query in a database.
+
+#[cfg(feature = "profiling")]
+use crate::Database;
+#[cfg(feature = "profiling")]
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// The counters of one derived query. A zero-sized no-op unless the
+/// `profiling` feature is enabled.
+#[cfg(feature = "profiling")]
+pub(crate) struct QueryProfile {
+    group_name: &'static str,
+    query_name: &'static str,
+    fetches: AtomicU64,
+    hits: AtomicU64,
+    validations: AtomicU64,
+    executions: AtomicU64,
+    total_nanos: AtomicU64,
+    self_nanos: AtomicU64,
+}
+
+#[cfg(not(feature = "profiling"))]
+pub(crate) struct QueryProfile;
+
+/// Measures the time spent executing a query, and how much of it was
+/// spent executing the queries it called.
+#[cfg(feature = "profiling")]
+pub(crate) struct ExecutionTimer {
+    started: Instant,
+    total: Duration,
+    children: Duration,
+}
+
+#[cfg(not(feature = "profiling"))]
+pub(crate) struct ExecutionTimer;
+
+#[cfg(feature = "profiling")]
+impl QueryProfile {
+    pub(crate) fn new(group_name: &'static str, query_name: &'static str) -> Self {
+        QueryProfile {
+            group_name,
+            query_name,
+            fetches: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            validations: AtomicU64::new(0),
+            executions: AtomicU64::new(0),
+            total_nanos: AtomicU64::new(0),
+            self_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record_fetch(&self) {
+        self.fetches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_validation(&self) {
+        self.validations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_execution(&self, timer: &ExecutionTimer) {
+        self.executions.fetch_add(1, Ordering::Relaxed);
+        self.total_nanos
+            .fetch_add(timer.total.as_nanos() as u64, Ordering::Relaxed);
+        self.self_nanos.fetch_add(
+            timer.total.saturating_sub(timer.children).as_nanos() as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    pub(crate) fn stats(&self) -> QueryStats {
+        QueryStats {
+            group_name: self.group_name,
+            query_name: self.query_name,
+            fetches: self.fetches.load(Ordering::Relaxed),
+            hits: self.hits.load(Ordering::Relaxed),
+            validations: self.validations.load(Ordering::Relaxed),
+            executions: self.executions.load(Ordering::Relaxed),
+            total_time: Duration::from_nanos(self.total_nanos.load(Ordering::Relaxed)),
+            self_time: Duration::from_nanos(self.self_nanos.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        for counter in &[
+            &self.fetches,
+            &self.hits,
+            &self.validations,
+            &self.executions,
+            &self.total_nanos,
+            &self.self_nanos,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(not(feature = "profiling"))]
+impl QueryProfile {
+    #[inline]
+    pub(crate) fn new(_group_name: &'static str, _query_name: &'static str) -> Self {
+        QueryProfile
+    }
+
+    #[inline]
+    pub(crate) fn record_fetch(&self) {}
+
+    #[inline]
+    pub(crate) fn record_hit(&self) {}
+
+    #[inline]
+    pub(crate) fn record_validation(&self) {}
+
+    #[inline]
+    pub(crate) fn record_execution(&self, _timer: &ExecutionTimer) {}
+}
+
+#[cfg(feature = "profiling")]
+impl ExecutionTimer {
+    pub(crate) fn start() -> Self {
+        ExecutionTimer {
+            started: Instant::now(),
+            total: Duration::default(),
+            children: Duration::default(),
+        }
+    }
+
+    pub(crate) fn stop(&mut self) {
+        self.total = self.started.elapsed();
+    }
+
+    /// Records that `child`, which has been stopped, was executed as
+    /// part of this query.
+    pub(crate) fn add_child(&mut self, child: &ExecutionTimer) {
+        self.children += child.total;
+    }
+}
+
+#[cfg(not(feature = "profiling"))]
+impl ExecutionTimer {
+    #[inline]
+    pub(crate) fn start() -> Self {
+        ExecutionTimer
+    }
+
+    #[inline]
+    pub(crate) fn stop(&mut self) {}
+}
+
+/// The profiling counters of a single derived query, as returned by
+/// [`report`].
+#[cfg(feature = "profiling")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryStats {
+    /// The type name of the query group the query belongs to.
+    pub group_name: &'static str,
+    /// The name of the query.
+    pub query_name: &'static str,
+    /// How many times the query was fetched.
+    pub fetches: u64,
+    /// How many fetches found a memo that was already verified in the
+    /// current revision.
+    pub hits: u64,
+    /// How many times an older memo was validated and reused, see
+    /// [`EventKind::DidValidateMemoizedValue`](crate::EventKind::DidValidateMemoizedValue).
+    pub validations: u64,
+    /// How many times the query function was executed.
+    pub executions: u64,
+    /// Wall-clock time spent executing the query function, including
+    /// the queries it called.
+    pub total_time: Duration,
+    /// Wall-clock time spent executing the query function, excluding
+    /// the time spent executing the queries it called.
+    pub self_time: Duration,
+}
+
+/// A column that a [`ProfileReport`] can be sorted by.
+#[cfg(feature = "profiling")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SortBy {
+    /// Sort by group and query name.
+    Name,
+    /// Sort by [`QueryStats::fetches`].
+    Fetches,
+    /// Sort by [`QueryStats::hits`].
+    Hits,
+    /// Sort by [`QueryStats::validations`].
+    Validations,
+    /// Sort by [`QueryStats::executions`].
+    Executions,
+    /// Sort by [`QueryStats::total_time`].
+    TotalTime,
+    /// Sort by [`QueryStats::self_time`].
+    SelfTime,
+}
+
+/// A snapshot of the profiling counters of the derived queries in a
+/// database. Displays as a table.
+#[cfg(feature = "profiling")]
+#[derive(Clone, Debug)]
+pub struct ProfileReport {
+    queries: Vec<QueryStats>,
+}
+
+#[cfg(feature = "profiling")]
+impl ProfileReport {
+    /// The counters of every derived query, in the current order.
+    pub fn queries(&self) -> &[QueryStats] {
+        &self.queries
+    }
+
+    /// Returns the counters of the query named `query_name`, if any.
+    pub fn query(&self, query_name: &str) -> Option<&QueryStats> {
+        self.queries
+            .iter()
+            .find(|stats| stats.query_name == query_name)
+    }
+
+    /// Sorts the queries by `column`. Names sort in ascending order and
+    /// everything else in descending order, so the most expensive
+    /// queries come first.
+    pub fn sort_by(&mut self, column: SortBy) {
+        let name = |stats: &QueryStats| (stats.group_name, stats.query_name);
+        match column {
+            SortBy::Name => self.queries.sort_by_key(name),
+            SortBy::Fetches => self.queries.sort_by_key(|s| std::cmp::Reverse(s.fetches)),
+            SortBy::Hits => self.queries.sort_by_key(|s| std::cmp::Reverse(s.hits)),
+            SortBy::Validations => self
+                .queries
+                .sort_by_key(|s| std::cmp::Reverse(s.validations)),
+            SortBy::Executions => self
+                .queries
+                .sort_by_key(|s| std::cmp::Reverse(s.executions)),
+            SortBy::TotalTime => self
+                .queries
+                .sort_by_key(|s| std::cmp::Reverse(s.total_time)),
+            SortBy::SelfTime => self.queries.sort_by_key(|s| std::cmp::Reverse(s.self_time)),
+        }
+    }
+}
+
+#[cfg(feature = "profiling")]
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            fmt,
+            "{:<32} {:>10} {:>10} {:>10} {:>10} {:>12} {:>12}",
+            "query", "fetches", "hits", "validated", "executed", "total", "self",
+        )?;
+        for stats in &self.queries {
+            writeln!(
+                fmt,
+                "{:<32} {:>10} {:>10} {:>10} {:>10} {:>12} {:>12}",
+                stats.query_name,
+                stats.fetches,
+                stats.hits,
+                stats.validations,
+                stats.executions,
+                format!("{:.3?}", stats.total_time),
+                format!("{:.3?}", stats.self_time),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects the profiling counters of every derived query in `db`,
+/// sorted by name.
+#[cfg(feature = "profiling")]
+pub fn report<DB>(db: &DB) -> ProfileReport
+where
+    DB: ?Sized + Database,
+{
+    let mut queries = Vec::new();
+    db.for_each_query(&mut |query_storage| {
+        if let Some(stats) = query_storage.profile() {
+            queries.push(stats);
+        }
+    });
+    let mut report = ProfileReport { queries };
+    report.sort_by(SortBy::Name);
+    report
+}
+
+/// Resets the profiling counters of every query in `db` to zero.
+#[cfg(feature = "profiling")]
+pub fn reset<DB>(db: &DB)
+where
+    DB: ?Sized + Database,
+{
+    db.for_each_query(&mut |query_storage| query_storage.reset_profile());
+}
@@ -1,21 +1,30 @@
+use crate::cancellation::{CancellationToken, Cancelled};
 use crate::durability::Durability;
+use crate::lru::{BudgetNode, Eviction, Lru};
 use crate::plumbing::CycleDetected;
+use crate::profile::ExecutionTimer;
 use crate::revision::{AtomicRevision, Revision};
-use crate::{Database, DatabaseKeyIndex, Event, EventKind, ForkState};
+use crate::{Database, DatabaseKeyIndex, Event, EventKind, ExecuteReason, ForkState};
 use log::debug;
 use parking_lot::lock_api::{RawRwLock, RawRwLockRecursive};
 use parking_lot::{Mutex, RwLock};
 use rustc_hash::{FxHashMap, FxHasher};
 use smallvec::SmallVec;
 use std::hash::{BuildHasherDefault, Hash};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 pub(crate) type FxIndexSet<K> = indexmap::IndexSet<K, BuildHasherDefault<FxHasher>>;
 pub(crate) type FxIndexMap<K, V> = indexmap::IndexMap<K, V, BuildHasherDefault<FxHasher>>;
 
+mod dependents;
 mod local_state;
+mod trace;
+use dependents::DependentsIndex;
 use local_state::{ActiveQueryGuard, LocalState};
+use trace::TraceRecorder;
+
+pub use trace::QueryTrace;
 
 /// The salsa runtime stores the storage for all queries as well as
 /// tracking the query stack and dependencies between cycles.
@@ -38,6 +47,13 @@ pub struct Runtime {
 
     pub(super) parent: Option<ForkState>,
 
+    /// The transaction started by `begin_transaction`, if any. Only the
+    /// runtime of the database itself (not of a snapshot) can have one.
+    transaction: Option<Transaction>,
+
+    /// Cancels the queries of this runtime alone.
+    cancellation_token: CancellationToken,
+
     /// Shared state that is accessible via all runtimes.
     shared_state: Arc<SharedState>,
 }
@@ -58,6 +74,8 @@ impl Default for Runtime {
             shared_state: Default::default(),
             local_state: Default::default(),
             parent: Default::default(),
+            transaction: None,
+            cancellation_token: Default::default(),
         }
     }
 }
@@ -84,6 +102,9 @@ impl Runtime {
         if self.local_state.query_in_progress() {
             panic!("it is not legal to `snapshot` during a query (see salsa-rs/salsa#80)");
         }
+        if self.transaction.is_some() {
+            panic!("it is not legal to `snapshot` during a transaction");
+        }
 
         let revision_guard = RevisionGuard::new(&self.shared_state);
 
@@ -97,11 +118,17 @@ impl Runtime {
             shared_state: self.shared_state.clone(),
             local_state: Default::default(),
             parent: self.parent.clone(),
+            transaction: None,
+            cancellation_token: Default::default(),
         }
     }
 
     /// Returns a "forked" runtime, suitable to call concurrent queries.
     pub fn fork(&self, state: ForkState) -> Self {
+        if self.transaction.is_some() {
+            panic!("it is not legal to `fork` during a transaction");
+        }
+
         let revision_guard = RevisionGuard::new(&self.shared_state);
 
         let id = RuntimeId {
@@ -116,6 +143,8 @@ impl Runtime {
             shared_state: self.shared_state.clone(),
             local_state: Default::default(),
             parent: Some(state),
+            transaction: None,
+            cancellation_token: self.cancellation_token.child(),
         }
     }
 
@@ -146,6 +175,10 @@ impl Runtime {
     /// cancellation. If you invoke it while a snapshot exists, it
     /// will block until that snapshot is dropped -- if that snapshot
     /// is owned by the current thread, this could trigger deadlock.
+    ///
+    /// The new revision is not reported to `salsa_event`, since the
+    /// runtime cannot reach the database; use
+    /// [`Database::synthetic_write`] for that.
     pub fn synthetic_write(&mut self, durability: Durability) {
         self.with_incremented_revision(&mut |_next_revision| Some(durability));
     }
@@ -166,6 +199,107 @@ impl Runtime {
             .chain(Some(self.id()))
     }
 
+    /// Starts recording a trace of every query execution and
+    /// validation, in this runtime and in every snapshot of the same
+    /// database. A trace that was already being recorded is discarded.
+    pub fn start_trace(&self) {
+        *self.shared_state.trace.lock() = Some(TraceRecorder::new());
+        self.shared_state.tracing.store(true, Ordering::SeqCst);
+    }
+
+    /// Stops recording the trace started with [`Runtime::start_trace`]
+    /// and returns it. Returns an empty trace if none was started.
+    pub fn finish_trace(&self) -> QueryTrace {
+        self.shared_state.tracing.store(false, Ordering::SeqCst);
+        self.shared_state
+            .trace
+            .lock()
+            .take()
+            .unwrap_or_else(TraceRecorder::new)
+            .finish()
+    }
+
+    /// Opens a span in the trace, if one is being recorded. `name` is
+    /// only invoked when it is.
+    pub(crate) fn trace_begin(&self, category: &'static str, name: impl FnOnce() -> String) {
+        if self.shared_state.tracing.load(Ordering::Relaxed) {
+            if let Some(trace) = &mut *self.shared_state.trace.lock() {
+                trace.begin(self.id, category, name());
+            }
+        }
+    }
+
+    /// Closes the span opened by the matching `trace_begin`.
+    pub(crate) fn trace_end(&self, category: &'static str) {
+        if self.shared_state.tracing.load(Ordering::Relaxed) {
+            if let Some(trace) = &mut *self.shared_state.trace.lock() {
+                trace.end(self.id, category);
+            }
+        }
+    }
+
+    /// Like `trace_begin`, but the span is closed when the returned
+    /// guard is dropped, so that it is closed even if the caller
+    /// unwinds.
+    pub(crate) fn trace_span(
+        &self,
+        category: &'static str,
+        name: impl FnOnce() -> String,
+    ) -> TraceSpan {
+        if self.shared_state.tracing.load(Ordering::Relaxed) {
+            if let Some(trace) = &mut *self.shared_state.trace.lock() {
+                trace.begin(self.id, category, name());
+                return TraceSpan {
+                    open: Some((self.shared_state.clone(), self.id, category)),
+                };
+            }
+        }
+        TraceSpan { open: None }
+    }
+
+    /// Limits the estimated memory used by the values of derived queries
+    /// that opted in with
+    /// [`QueryTableMut::enable_memory_budget`](crate::QueryTableMut::enable_memory_budget)
+    /// to `bytes`, shared by this runtime and every snapshot of the same
+    /// database. When a fetch takes the total over the budget, the least
+    /// recently used values of any of those queries are evicted, just as
+    /// with [`QueryTableMut::set_lru_capacity`](crate::QueryTableMut::set_lru_capacity).
+    /// A budget of zero disables the limit.
+    ///
+    /// The memory used by a value is estimated like in
+    /// [`memory::report`](crate::memory::report), so queries should also
+    /// enable heap sizes if their values own large allocations.
+    pub fn set_memory_budget(&self, bytes: usize) {
+        self.shared_state.memory_budget.set_weight_capacity(bytes);
+    }
+
+    /// Records that `node` was used and now weighs `weight` bytes,
+    /// evicting the values of other nodes to stay within the memory
+    /// budget. Returns the keys of the evicted values; nodes that keep
+    /// their value also keep counting towards the budget.
+    pub(crate) fn record_budget_use(
+        &self,
+        node: &Arc<dyn BudgetNode>,
+        weight: usize,
+    ) -> Vec<DatabaseKeyIndex> {
+        let memory_budget = &self.shared_state.memory_budget;
+        let mut evicted = Vec::new();
+        for victim in memory_budget.record_weighted_use(node, weight) {
+            match victim.evict(self) {
+                Eviction::Evicted => evicted.push(victim.database_key_index()),
+                Eviction::Empty => {}
+                Eviction::Kept => memory_budget.reinsert(&victim),
+            }
+        }
+        evicted
+    }
+
+    /// Stops counting `node` towards the memory budget, because its
+    /// value was discarded or its slot removed.
+    pub(crate) fn remove_budget_node(&self, node: &Arc<dyn BudgetNode>) {
+        self.shared_state.memory_budget.remove(node);
+    }
+
     /// Returns the database-key for the query that this thread is
     /// actively executing (if any).
     pub fn active_query(&self) -> Option<DatabaseKeyIndex> {
@@ -232,7 +366,12 @@ impl Runtime {
     ///     panic if downcast fails).
     ///
     /// Note that salsa is explicitly designed to be panic-safe, so cancellation
-    /// via unwinding is 100% valid approach to cancellation.
+    /// via unwinding is 100% valid approach to cancellation. It is also
+    /// built in: fetching a query unwinds with [`Cancelled`] once the
+    /// revision is canceled, see [`unwind_if_cancelled`](Self::unwind_if_cancelled).
+    ///
+    /// [`Database::is_current_revision_canceled`] does the same, and
+    /// also reports the cancellation to `salsa_event`.
     #[inline]
     pub fn is_current_revision_canceled(&self) -> bool {
         let current_revision = self.current_revision();
@@ -281,6 +420,70 @@ impl Runtime {
         }
     }
 
+    /// Unwinds with [`Cancelled`] if the current revision is canceled,
+    /// that is, if a write is waiting for the queries to finish, or if
+    /// the [`cancellation_token`](Self::cancellation_token) of this
+    /// runtime was cancelled.
+    ///
+    /// Salsa does this itself whenever a query is fetched, so a query
+    /// only needs to call this during long computations that do not
+    /// fetch other queries. Since the query unwinds instead of
+    /// returning, nothing is memoized and there is no need to report
+    /// an untracked read as [`is_current_revision_canceled`] does. Use
+    /// [`catch_cancellation`](crate::catch_cancellation) to turn the
+    /// unwinding into a `Result` around top-level calls.
+    ///
+    /// [`is_current_revision_canceled`]: Self::is_current_revision_canceled
+    pub fn unwind_if_cancelled(&self) {
+        if self.is_cancelled() {
+            Cancelled::throw()
+        }
+    }
+
+    /// Whether [`unwind_if_cancelled`](Self::unwind_if_cancelled) would
+    /// unwind.
+    ///
+    /// Unlike [`is_current_revision_canceled`], this also covers the
+    /// [`cancellation_token`](Self::cancellation_token), which does not
+    /// create a new revision. A query must therefore unwind rather than
+    /// return a special value when it finds itself cancelled, since that
+    /// value would be memoized for the rest of the revision.
+    ///
+    /// [`is_current_revision_canceled`]: Self::is_current_revision_canceled
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.pending_revision() > self.current_revision() || self.cancellation_token.is_cancelled()
+    }
+
+    /// Returns the token that cancels the queries of this runtime only,
+    /// for example once the request that a snapshot was taken for is
+    /// abandoned. Every snapshot and fork has a token of its own; see
+    /// [`CancellationToken`].
+    ///
+    /// The database itself gets a new token with each new revision, so
+    /// cancelling its token only cancels the queries of the current
+    /// revision.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation_token.clone()
+    }
+
+    /// Replaces a cancelled token of the database when a new revision
+    /// starts, so that the database does not stay cancelled for good.
+    /// The runtimes forked from it are gone by then, since they hold
+    /// the query lock.
+    fn reset_cancellation_token(&mut self) {
+        if self.cancellation_token.is_cancelled() {
+            self.cancellation_token = CancellationToken::default();
+        }
+    }
+
+    /// Whether the queries of this runtime unwind because its
+    /// cancellation token was cancelled, rather than because of a
+    /// pending write or a panic.
+    pub(crate) fn is_token_cancelled(&self) -> bool {
+        self.cancellation_token.is_cancelled()
+    }
+
     /// Acquires the **global query write lock** (ensuring that no queries are
     /// executing) and then increments the current revision counter; invokes
     /// `op` with the global query write lock still held.
@@ -301,13 +504,37 @@ impl Runtime {
     ///   and it had the durability `d`. This will update the records for when
     ///   values with each durability were modified.
     ///
+    /// Returns the new revision along with the durability returned by `op`,
+    /// so that the caller can report them to `salsa_event`. Inside a
+    /// transaction, `op` is invoked with the revision of the transaction
+    /// instead and `None` is returned: the revision is reported once, by
+    /// `end_transaction`.
+    ///
     /// Note that, given our writer model, we can assume that only one thread is
     /// attempting to increment the global revision at a time.
     pub(crate) fn with_incremented_revision(
         &mut self,
         op: &mut dyn FnMut(Revision) -> Option<Durability>,
-    ) {
+    ) -> Option<(Revision, Option<Durability>)> {
+        if let Some(transaction) = &mut self.transaction {
+            // We already hold the lock.
+            let durability = op(transaction.revision);
+            if let Some(d) = durability {
+                for rev in &self.shared_state.revisions[1..=d.index()] {
+                    rev.store(transaction.revision);
+                }
+                transaction.durability = transaction.durability.max(durability);
+            }
+            return None;
+        }
+
         log::debug!("increment_revision()");
+        let _span = debug_span!(
+            "with_incremented_revision",
+            revision = ?self.current_revision(),
+            runtime_id = ?self.id(),
+        )
+        .entered();
 
         if !self.permits_increment() {
             panic!("increment_revision invoked during a query computation");
@@ -327,12 +554,169 @@ impl Runtime {
         let new_revision = current_revision.next();
 
         debug!("increment_revision: incremented to {:?}", new_revision);
+        self.reset_cancellation_token();
 
-        if let Some(d) = op(new_revision) {
+        let durability = op(new_revision);
+        if let Some(d) = durability {
             for rev in &self.shared_state.revisions[1..=d.index()] {
                 rev.store(new_revision);
             }
         }
+        Some((new_revision, durability))
+    }
+
+    /// Like `with_incremented_revision`, but keeps holding the global
+    /// query write lock after incrementing the revision, until
+    /// `end_transaction` is called. Every write in between is part of
+    /// the new revision.
+    ///
+    /// Returns `false`, doing nothing, if a transaction was already
+    /// started.
+    pub(crate) fn begin_transaction(&mut self) -> bool {
+        if self.transaction.is_some() {
+            return false;
+        }
+
+        if !self.permits_increment() {
+            panic!("transaction started during a query computation");
+        }
+
+        let current_revision = self.shared_state.pending_revision.fetch_then_increment();
+
+        // Released by `end_transaction`, hence the raw lock.
+        unsafe {
+            self.shared_state.query_lock.raw().lock_exclusive();
+        }
+
+        let old_revision = self.shared_state.revisions[0].fetch_then_increment();
+        assert_eq!(current_revision, old_revision);
+
+        let revision = current_revision.next();
+        debug!("begin_transaction: incremented to {:?}", revision);
+        self.reset_cancellation_token();
+        self.transaction = Some(Transaction {
+            revision,
+            durability: None,
+        });
+        true
+    }
+
+    pub(crate) fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// Ends the transaction started by `begin_transaction`, returning its
+    /// revision along with the highest durability it modified.
+    pub(crate) fn end_transaction(&mut self) -> (Revision, Option<Durability>) {
+        let transaction = self.transaction.take().expect("no transaction to end");
+        unsafe {
+            self.shared_state.query_lock.raw().unlock_exclusive();
+        }
+        (transaction.revision, transaction.durability)
+    }
+
+    /// The "last changed" revision for each durability level, starting
+    /// with the current revision.
+    pub(crate) fn revisions(&self) -> Vec<Revision> {
+        self.shared_state
+            .revisions
+            .iter()
+            .map(|revision| revision.load())
+            .collect()
+    }
+
+    /// Resets the revision counters of a freshly created runtime to
+    /// those saved by `persist::save`.
+    pub(crate) fn restore_revisions(&mut self, revisions: &[Revision]) -> std::io::Result<()> {
+        if !self.permits_increment() {
+            panic!("restore_revisions invoked during a query computation");
+        }
+        if self.transaction.is_some() {
+            panic!("restore_revisions invoked during a transaction");
+        }
+
+        if self.current_revision() != Revision::start() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "can only restore into a freshly created database",
+            ));
+        }
+
+        if revisions.len() != self.shared_state.revisions.len()
+            || revisions.windows(2).any(|pair| pair[0] < pair[1])
+        {
+            return Err(crate::persist::invalid_data("invalid revision counters"));
+        }
+
+        let _lock = self.shared_state.query_lock.write();
+        for (rev, &saved) in self.shared_state.revisions.iter().zip(revisions) {
+            rev.store(saved);
+        }
+        self.shared_state.pending_revision.store(revisions[0]);
+        Ok(())
+    }
+
+    /// Starts maintaining the reverse dependency index. The caller is
+    /// responsible for indexing the memos that already exist.
+    pub(crate) fn enable_dependents_index(&mut self) {
+        self.shared_state
+            .track_dependents
+            .store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn tracks_dependents(&self) -> bool {
+        self.shared_state.track_dependents.load(Ordering::Relaxed)
+    }
+
+    /// Records that the memo of `dependent`, which used to read
+    /// `old_inputs`, now reads `new_inputs` instead, as of the current
+    /// revision.
+    pub(crate) fn update_dependents(
+        &self,
+        dependent: DatabaseKeyIndex,
+        old_inputs: &[DatabaseKeyIndex],
+        new_inputs: &[DatabaseKeyIndex],
+    ) {
+        if self.tracks_dependents() && (!old_inputs.is_empty() || !new_inputs.is_empty()) {
+            self.shared_state.dependents.lock().update(
+                dependent,
+                old_inputs,
+                new_inputs,
+                self.current_revision(),
+            );
+        }
+    }
+
+    /// Records that the memo of `dependent` was validated in `revision`.
+    pub(crate) fn verify_dependent(&self, dependent: DatabaseKeyIndex, revision: Revision) {
+        if self.tracks_dependents() {
+            self.shared_state
+                .dependents
+                .lock()
+                .verify(dependent, revision);
+        }
+    }
+
+    /// Forgets the inputs of every memo of the given query.
+    pub(crate) fn remove_dependents_in_query(&self, group_index: u16, query_index: u16) {
+        if self.tracks_dependents() {
+            self.shared_state
+                .dependents
+                .lock()
+                .retain_dependents(|dependent| {
+                    dependent.group_index != group_index || dependent.query_index != query_index
+                });
+        }
+    }
+
+    /// Returns the derived queries whose memo read `input` in the
+    /// current revision.
+    pub(crate) fn dependents(&self, input: DatabaseKeyIndex) -> Vec<DatabaseKeyIndex> {
+        assert!(self.tracks_dependents());
+        self.shared_state
+            .dependents
+            .lock()
+            .dependents(input, self.current_revision())
     }
 
     pub(crate) fn permits_increment(&self) -> bool {
@@ -342,6 +726,8 @@ impl Runtime {
     pub(crate) fn prepare_query_implementation<DB>(
         db: &mut DB,
         database_key_index: DatabaseKeyIndex,
+        reason: ExecuteReason,
+        iterates_cycles: bool,
     ) -> ActiveQueryGuard<'_, DB>
     where
         DB: std::ops::Deref,
@@ -359,10 +745,17 @@ impl Runtime {
                 database_key: database_key_index,
             },
         });
+        db.salsa_event(Event {
+            runtime_id: runtime.id(),
+            kind: EventKind::WillExecuteBecause {
+                database_key: database_key_index,
+                reason,
+            },
+        });
 
         // Push the active query onto the stack.
         let max_durability = Durability::MAX;
-        LocalState::push_query(db, database_key_index, max_durability)
+        LocalState::push_query(db, database_key_index, max_durability, iterates_cycles)
     }
 
     pub(crate) fn complete_query<DB, V>(
@@ -378,6 +771,9 @@ impl Runtime {
             changed_at,
             durability,
             cycle,
+            provisional_heads,
+            cycle_inputs,
+            timer,
             ..
         } = active_query.complete();
 
@@ -387,6 +783,9 @@ impl Runtime {
             changed_at,
             dependencies,
             cycle,
+            provisional_heads,
+            cycle_inputs,
+            timer,
         }
     }
 
@@ -469,6 +868,7 @@ impl Runtime {
             }
 
             crate::CycleError {
+                runtimes: vec![self.id(); cycle.len()],
                 cycle,
                 changed_at,
                 durability: Durability::MAX,
@@ -479,6 +879,7 @@ impl Runtime {
             let dependency_graph = self.shared_state.dependency_graph.lock();
 
             let mut cycle = Vec::new();
+            let mut runtimes = Vec::new();
             {
                 let cycle_iter = dependency_graph
                     .get_cycle_path(
@@ -487,9 +888,12 @@ impl Runtime {
                         error.to,
                         query_stack.iter().map(|query| &query.database_key_index),
                     )
-                    .chain(Some(&database_key_index));
+                    .chain(Some((error.to, &database_key_index)));
 
-                cycle.extend(cycle_iter.cloned());
+                for (runtime_id, key) in cycle_iter {
+                    runtimes.push(runtime_id);
+                    cycle.push(*key);
+                }
             }
 
             assert!(!cycle.is_empty());
@@ -503,12 +907,65 @@ impl Runtime {
 
             crate::CycleError {
                 cycle,
+                runtimes,
                 changed_at,
                 durability: Durability::MAX,
             }
         }
     }
 
+    /// Returns a query of the cycle from `database_key_index` up to the
+    /// active query that is not iterated to a fixpoint, if some of the
+    /// others are.
+    pub(crate) fn cycle_query_without_fixpoint(
+        &self,
+        database_key_index: DatabaseKeyIndex,
+    ) -> Option<DatabaseKeyIndex> {
+        self.local_state
+            .cycle_query_without_fixpoint(database_key_index)
+    }
+
+    /// Reports that the active query read the provisional value of
+    /// `head`, a query that is iterated to a fixpoint. Returns false if
+    /// `head` is not on the stack, in which case it can't be iterated.
+    pub(crate) fn report_provisional_read(&self, head: DatabaseKeyIndex) -> bool {
+        self.local_state.report_provisional_read(head)
+    }
+
+    /// Reports that the active query read the provisional value of a
+    /// query that completed inside a cycle that is iterated to a
+    /// fixpoint.
+    pub(crate) fn report_participant_read(&self, participant: DatabaseKeyIndex) {
+        self.local_state.report_participant_read(participant)
+    }
+
+    /// Records that `participant` completed with a value that depends on
+    /// the provisional values of `heads`, after reading `inputs`.
+    pub(crate) fn complete_provisional(
+        &self,
+        participant: DatabaseKeyIndex,
+        heads: Vec<DatabaseKeyIndex>,
+        inputs: Option<&FxIndexSet<DatabaseKeyIndex>>,
+    ) {
+        self.local_state
+            .complete_provisional(participant, heads, inputs)
+    }
+
+    /// The queries that completed with a value depending on the
+    /// provisional value of `head`.
+    pub(crate) fn cycle_participants(&self, head: DatabaseKeyIndex) -> Vec<DatabaseKeyIndex> {
+        self.local_state.participants(head)
+    }
+
+    /// Like `cycle_participants`, for a head that is done iterating.
+    pub(crate) fn take_cycle_participants(&self, head: DatabaseKeyIndex) -> Vec<DatabaseKeyIndex> {
+        self.local_state.take_participants(head)
+    }
+
+    pub(crate) fn forget_cycle_participant(&self, participant: DatabaseKeyIndex) {
+        self.local_state.forget_participant(participant)
+    }
+
     pub(crate) fn mark_cycle_participants(&self, cycle: &[DatabaseKeyIndex]) {
         for active_query in self
             .local_state
@@ -562,6 +1019,31 @@ impl Runtime {
     }
 }
 
+/// A batch of writes that share one revision.
+struct Transaction {
+    revision: Revision,
+
+    /// The highest durability of the values modified so far.
+    durability: Option<Durability>,
+}
+
+/// Closes a span opened by [`Runtime::trace_span`] when dropped.
+pub(crate) struct TraceSpan {
+    open: Option<(Arc<SharedState>, RuntimeId, &'static str)>,
+}
+
+impl Drop for TraceSpan {
+    fn drop(&mut self) {
+        if let Some((shared_state, runtime_id, category)) = &self.open {
+            if shared_state.tracing.load(Ordering::Relaxed) {
+                if let Some(trace) = &mut *shared_state.trace.lock() {
+                    trace.end(*runtime_id, category);
+                }
+            }
+        }
+    }
+}
+
 /// State that will be common to all threads (when we support multiple threads)
 struct SharedState {
     /// Stores the next id to use for a snapshotted runtime (starts at 1).
@@ -596,6 +1078,22 @@ struct SharedState {
     /// The dependency graph tracks which runtimes are blocked on one
     /// another, waiting for queries to terminate.
     dependency_graph: Mutex<DependencyGraph<DatabaseKeyIndex>>,
+
+    /// True once the reverse dependency index has been enabled.
+    track_dependents: AtomicBool,
+
+    /// For each key, the derived queries that read it. Only
+    /// maintained when `track_dependents` is set.
+    dependents: Mutex<DependentsIndex>,
+
+    /// True while a trace is being recorded into `trace`.
+    tracing: AtomicBool,
+
+    trace: Mutex<Option<TraceRecorder>>,
+
+    /// The values of the queries that take part in the memory budget,
+    /// weighted by their estimated size.
+    memory_budget: Lru<dyn BudgetNode>,
 }
 
 impl SharedState {
@@ -606,6 +1104,11 @@ impl SharedState {
             revisions: (0..durabilities).map(|_| AtomicRevision::start()).collect(),
             pending_revision: AtomicRevision::start(),
             dependency_graph: Default::default(),
+            track_dependents: AtomicBool::new(false),
+            dependents: Default::default(),
+            tracing: AtomicBool::new(false),
+            trace: Default::default(),
+            memory_budget: Default::default(),
         }
     }
 }
@@ -652,6 +1155,22 @@ struct ActiveQuery {
 
     /// Stores the entire cycle, if one is found and this query is part of it.
     cycle: Vec<DatabaseKeyIndex>,
+
+    /// Whether cycles that this query is the head of are iterated to a
+    /// fixpoint.
+    iterates_cycles: bool,
+
+    /// The heads of the cycles being iterated to a fixpoint whose
+    /// provisional values this query depends on.
+    provisional_heads: Vec<DatabaseKeyIndex>,
+
+    /// If this query is the head of such a cycle, the inputs of the
+    /// queries that completed with a provisional value depending on it,
+    /// or `None` if one of them had an untracked read.
+    cycle_inputs: Option<FxIndexSet<DatabaseKeyIndex>>,
+
+    /// Measures the time spent executing this query.
+    timer: ExecutionTimer,
 }
 
 pub(crate) struct ComputedQueryResult<V> {
@@ -671,16 +1190,35 @@ pub(crate) struct ComputedQueryResult<V> {
 
     /// The cycle if one occured while computing this value
     pub(crate) cycle: Vec<DatabaseKeyIndex>,
+
+    /// The heads of the cycles being iterated to a fixpoint whose
+    /// provisional values this value depends on.
+    pub(crate) provisional_heads: Vec<DatabaseKeyIndex>,
+
+    /// The inputs of the queries whose provisional values depend on this
+    /// one, see `ActiveQuery::cycle_inputs`.
+    pub(crate) cycle_inputs: Option<FxIndexSet<DatabaseKeyIndex>>,
+
+    /// The time spent computing this value.
+    pub(crate) timer: ExecutionTimer,
 }
 
 impl ActiveQuery {
-    fn new(database_key_index: DatabaseKeyIndex, max_durability: Durability) -> Self {
+    fn new(
+        database_key_index: DatabaseKeyIndex,
+        max_durability: Durability,
+        iterates_cycles: bool,
+    ) -> Self {
         ActiveQuery {
             database_key_index,
             durability: max_durability,
             changed_at: Revision::start(),
             dependencies: Some(FxIndexSet::default()),
             cycle: Vec::new(),
+            iterates_cycles,
+            provisional_heads: Vec::new(),
+            cycle_inputs: Some(FxIndexSet::default()),
+            timer: ExecutionTimer::start(),
         }
     }
 
@@ -706,6 +1244,20 @@ impl ActiveQuery {
     fn add_anon_read(&mut self, changed_at: Revision) {
         self.changed_at = self.changed_at.max(changed_at);
     }
+
+    fn add_provisional_head(&mut self, head: DatabaseKeyIndex) {
+        if !self.provisional_heads.contains(&head) {
+            self.provisional_heads.push(head);
+        }
+    }
+
+    fn add_cycle_inputs(&mut self, inputs: Option<&FxIndexSet<DatabaseKeyIndex>>) {
+        match (&mut self.cycle_inputs, inputs) {
+            (Some(cycle_inputs), Some(inputs)) => cycle_inputs.extend(inputs.iter().copied()),
+            (cycle_inputs, None) => *cycle_inputs = None,
+            (None, Some(_)) => {}
+        }
+    }
 }
 
 /// A unique identifier for a particular runtime. Each time you create
@@ -844,7 +1396,7 @@ where
         from: RuntimeId,
         to: RuntimeId,
         local_path: impl IntoIterator<Item = &'a K>,
-    ) -> impl Iterator<Item = &'a K>
+    ) -> impl Iterator<Item = (RuntimeId, &'a K)>
     where
         K: std::fmt::Debug,
     {
@@ -852,26 +1404,31 @@ where
         assert!(self.find_edge(from, to, &mut |id| vec.push(id)));
         vec.push(to);
 
-        let mut current = Some(std::slice::from_ref(database_key));
+        // Each path is a part of the stack of the first runtime, except
+        // for the last key, which that runtime is blocked on and which is
+        // executing in the second runtime.
+        let mut current = Some((std::slice::from_ref(database_key), to, to));
         let mut last = None;
         let mut local_path = Some(local_path);
         let mut vec_iter = vec.into_iter().rev().peekable();
         std::iter::from_fn(move || match current.take() {
-            Some(path) => {
-                let id = vec_iter.next()?;
+            Some((path, id, last_id)) => {
                 let link_key = path.last().unwrap();
 
-                current = self.edges.get(&id).and_then(|out_edges| {
+                let id_now = vec_iter.next()?;
+                current = self.edges.get(&id_now).and_then(|out_edges| {
                     let next_id = vec_iter.peek()?;
                     let edge = out_edges.iter().find(|edge| edge.id == *next_id)?;
 
-                    Some(
+                    Some((
                         edge.path
                             .iter()
                             .rposition(|p| p == link_key)
                             .map(|i| &edge.path[i + 1..])
                             .unwrap_or_else(|| &edge.path[..]),
-                    )
+                        id_now,
+                        *next_id,
+                    ))
                 });
 
                 if current.is_none() {
@@ -883,14 +1440,21 @@ where
                     });
                 }
 
-                Some(path)
+                Some((path, id, last_id))
             }
             None => match &mut last {
-                Some(iter) => iter.next().map(std::slice::from_ref),
+                Some(iter) => iter
+                    .next()
+                    .map(|key| (std::slice::from_ref(key), from, from)),
                 None => None,
             },
         })
-        .flat_map(|x| x)
+        .flat_map(|(path, id, last_id)| {
+            let len = path.len();
+            path.iter()
+                .enumerate()
+                .map(move |(i, key)| (if i + 1 == len { last_id } else { id }, key))
+        })
     }
 }
 
@@ -950,9 +1514,9 @@ mod tests {
         assert_eq!(
             graph
                 .get_cycle_path(&1, b, a, &[3, 2][..])
-                .cloned()
-                .collect::<Vec<i32>>(),
-            vec![1, 2]
+                .map(|(id, key)| (id, *key))
+                .collect::<Vec<_>>(),
+            vec![(a, 1), (b, 2)]
         );
     }
 
@@ -968,9 +1532,9 @@ mod tests {
         assert_eq!(
             graph
                 .get_cycle_path(&1, c, a, &[5, 6, 4, 7][..])
-                .cloned()
-                .collect::<Vec<i32>>(),
-            vec![1, 3, 4, 7]
+                .map(|(id, key)| (id, *key))
+                .collect::<Vec<_>>(),
+            vec![(a, 1), (b, 3), (c, 4), (c, 7)]
         );
     }
 }
@@ -0,0 +1,80 @@
+use crate::revision::Revision;
+use crate::runtime::FxIndexSet;
+use crate::DatabaseKeyIndex;
+use rustc_hash::FxHashMap;
+
+/// Reverse dependency edges: for each key, the set of derived queries
+/// whose current memo recorded it as an input.
+///
+/// Only tracked inputs are indexed. A memo with untracked inputs may
+/// depend on anything at all and does not show up here.
+#[derive(Default)]
+pub(super) struct DependentsIndex {
+    edges: FxHashMap<DatabaseKeyIndex, FxIndexSet<DatabaseKeyIndex>>,
+
+    /// The revision that the memo of each indexed dependent was last
+    /// executed or validated in.
+    verified_at: FxHashMap<DatabaseKeyIndex, Revision>,
+}
+
+impl DependentsIndex {
+    pub(super) fn update(
+        &mut self,
+        dependent: DatabaseKeyIndex,
+        old_inputs: &[DatabaseKeyIndex],
+        new_inputs: &[DatabaseKeyIndex],
+        revision_now: Revision,
+    ) {
+        for input in old_inputs {
+            if let Some(dependents) = self.edges.get_mut(input) {
+                dependents.shift_remove(&dependent);
+                if dependents.is_empty() {
+                    self.edges.remove(input);
+                }
+            }
+        }
+        for &input in new_inputs {
+            self.edges.entry(input).or_default().insert(dependent);
+        }
+        if new_inputs.is_empty() {
+            self.verified_at.remove(&dependent);
+        } else {
+            self.verified_at.insert(dependent, revision_now);
+        }
+    }
+
+    /// Records that the memo of `dependent` was validated in `revision`.
+    pub(super) fn verify(&mut self, dependent: DatabaseKeyIndex, revision: Revision) {
+        if let Some(verified_at) = self.verified_at.get_mut(&dependent) {
+            *verified_at = revision;
+        }
+    }
+
+    /// Keeps only the edges whose dependent satisfies `keep`.
+    pub(super) fn retain_dependents(&mut self, mut keep: impl FnMut(DatabaseKeyIndex) -> bool) {
+        self.edges.retain(|_, dependents| {
+            dependents.retain(|&dependent| keep(dependent));
+            !dependents.is_empty()
+        });
+        self.verified_at.retain(|&dependent, _| keep(dependent));
+    }
+
+    /// The dependents of `input` whose memo was executed or validated in
+    /// `revision_now`.
+    pub(super) fn dependents(
+        &self,
+        input: DatabaseKeyIndex,
+        revision_now: Revision,
+    ) -> Vec<DatabaseKeyIndex> {
+        self.edges
+            .get(&input)
+            .map(|dependents| {
+                dependents
+                    .iter()
+                    .copied()
+                    .filter(|dependent| self.verified_at.get(dependent) == Some(&revision_now))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
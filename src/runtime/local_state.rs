@@ -1,5 +1,7 @@
 use crate::durability::Durability;
 use crate::runtime::ActiveQuery;
+use crate::runtime::FxIndexMap;
+use crate::runtime::FxIndexSet;
 use crate::runtime::Revision;
 use crate::{Database, DatabaseKeyIndex};
 use std::cell::{Ref, RefCell, RefMut};
@@ -16,12 +18,21 @@ pub(super) struct LocalState {
     /// Unwinding note: pushes onto this vector must be popped -- even
     /// during unwinding.
     query_stack: RefCell<Vec<ActiveQuery>>,
+
+    /// The queries that completed with a provisional value while a
+    /// cycle is iterated to a fixpoint, along with the heads of the
+    /// cycles whose values they depend on. They stay in progress until
+    /// those heads are done iterating.
+    ///
+    /// Unwinding note: a head that unwinds discards its participants.
+    participants: RefCell<FxIndexMap<DatabaseKeyIndex, Vec<DatabaseKeyIndex>>>,
 }
 
 impl Default for LocalState {
     fn default() -> Self {
         LocalState {
             query_stack: Default::default(),
+            participants: Default::default(),
         }
     }
 }
@@ -31,6 +42,7 @@ impl LocalState {
         db: &mut DB,
         database_key_index: DatabaseKeyIndex,
         max_durability: Durability,
+        iterates_cycles: bool,
     ) -> ActiveQueryGuard<'_, DB>
     where
         DB: std::ops::Deref,
@@ -38,9 +50,16 @@ impl LocalState {
     {
         let push_len = {
             let mut query_stack = db.salsa_runtime().local_state.query_stack.borrow_mut();
-            query_stack.push(ActiveQuery::new(database_key_index, max_durability));
+            query_stack.push(ActiveQuery::new(
+                database_key_index,
+                max_durability,
+                iterates_cycles,
+            ));
             query_stack.len()
         };
+        db.salsa_runtime().trace_begin("execute", || {
+            format!("{:?}", database_key_index.debug(&**db))
+        });
         ActiveQueryGuard { db, push_len }
     }
 
@@ -96,6 +115,124 @@ impl LocalState {
             top_query.add_anon_read(revision);
         }
     }
+
+    /// Returns a query from the last `database_key_index` on the stack up
+    /// to the active one that does not iterate cycles to a fixpoint, if
+    /// some of the others do.
+    pub(super) fn cycle_query_without_fixpoint(
+        &self,
+        database_key_index: DatabaseKeyIndex,
+    ) -> Option<DatabaseKeyIndex> {
+        let query_stack = self.query_stack.borrow();
+        let start_index = query_stack
+            .iter()
+            .rposition(|active_query| active_query.database_key_index == database_key_index)?;
+        let cycle = &query_stack[start_index..];
+        if !cycle
+            .iter()
+            .any(|active_query| active_query.iterates_cycles)
+        {
+            return None;
+        }
+        cycle
+            .iter()
+            .find(|active_query| !active_query.iterates_cycles)
+            .map(|active_query| active_query.database_key_index)
+    }
+
+    /// Marks every query from `head` up to the active one as depending
+    /// on the provisional value of `head`. Returns false if `head` is not
+    /// on the stack.
+    pub(super) fn report_provisional_read(&self, head: DatabaseKeyIndex) -> bool {
+        let mut query_stack = self.query_stack.borrow_mut();
+        let start_index = match query_stack
+            .iter()
+            .rposition(|active_query| active_query.database_key_index == head)
+        {
+            Some(start_index) => start_index,
+            None => return false,
+        };
+        for active_query in &mut query_stack[start_index..] {
+            active_query.add_provisional_head(head);
+        }
+        true
+    }
+
+    /// Like `report_provisional_read`, for the heads that the
+    /// provisional value of `participant` depends on.
+    pub(super) fn report_participant_read(&self, participant: DatabaseKeyIndex) {
+        let heads = self
+            .participants
+            .borrow()
+            .get(&participant)
+            .cloned()
+            .unwrap_or_default();
+        for head in heads {
+            let on_stack = self.report_provisional_read(head);
+            debug_assert!(on_stack, "head {:?} is not on the stack", head);
+        }
+    }
+
+    /// Records that `participant` completed with a value depending on
+    /// `heads`, after reading `inputs` (`None` if one was untracked).
+    /// Queries whose value depended on `participant` as a head now
+    /// depend on `heads` instead.
+    pub(super) fn complete_provisional(
+        &self,
+        participant: DatabaseKeyIndex,
+        heads: Vec<DatabaseKeyIndex>,
+        inputs: Option<&FxIndexSet<DatabaseKeyIndex>>,
+    ) {
+        let mut participants = self.participants.borrow_mut();
+        for participant_heads in participants.values_mut() {
+            if let Some(index) = participant_heads.iter().position(|&h| h == participant) {
+                participant_heads.swap_remove(index);
+                for &head in &heads {
+                    if !participant_heads.contains(&head) {
+                        participant_heads.push(head);
+                    }
+                }
+            }
+        }
+
+        for active_query in self
+            .query_stack
+            .borrow_mut()
+            .iter_mut()
+            .filter(|active_query| heads.contains(&active_query.database_key_index))
+        {
+            active_query.add_cycle_inputs(inputs);
+        }
+        participants.insert(participant, heads);
+    }
+
+    /// The queries whose provisional value depends on `head`.
+    pub(super) fn participants(&self, head: DatabaseKeyIndex) -> Vec<DatabaseKeyIndex> {
+        self.participants
+            .borrow()
+            .iter()
+            .filter(|(_, heads)| heads.contains(&head))
+            .map(|(&participant, _)| participant)
+            .collect()
+    }
+
+    /// Like `participants`, but forgets them as well.
+    pub(super) fn take_participants(&self, head: DatabaseKeyIndex) -> Vec<DatabaseKeyIndex> {
+        let participants = self.participants(head);
+        let mut map = self.participants.borrow_mut();
+        for participant in &participants {
+            map.shift_remove(participant);
+        }
+        participants
+    }
+
+    /// Forgets `participant` once it completed with a final value.
+    pub(super) fn forget_participant(&self, participant: DatabaseKeyIndex) {
+        let mut participants = self.participants.borrow_mut();
+        if !participants.is_empty() {
+            participants.shift_remove(&participant);
+        }
+    }
 }
 
 impl std::panic::RefUnwindSafe for LocalState {}
@@ -124,12 +261,27 @@ where
         // Sanity check: pushes and pops should be balanced.
         assert_eq!(query_stack.len(), self.push_len);
 
-        query_stack.pop().unwrap()
+        let query = query_stack.pop().unwrap();
+        std::mem::drop(query_stack);
+        self.db.salsa_runtime().trace_end("execute");
+        query
     }
 
     /// Invoked when the query has successfully completed execution.
     pub(super) fn complete(self) -> ActiveQuery {
-        let query = self.pop_helper();
+        let mut query = self.pop_helper();
+        query.timer.stop();
+
+        // The time spent in this query is not part of the time the
+        // query that called it spent by itself.
+        #[cfg(feature = "profiling")]
+        {
+            let query_stack = &self.db.salsa_runtime().local_state.query_stack;
+            if let Some(parent) = query_stack.borrow_mut().last_mut() {
+                parent.timer.add_child(&query.timer);
+            }
+        }
+
         std::mem::forget(self);
         query
     }
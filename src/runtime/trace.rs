@@ -0,0 +1,103 @@
+use crate::debug::json_string;
+use crate::runtime::RuntimeId;
+use std::io::{self, Write};
+use std::time::Instant;
+
+/// Records the begin and end of query executions and validations,
+/// across all runtimes that share the same storage.
+pub(super) struct TraceRecorder {
+    started: Instant,
+    events: Vec<TraceEvent>,
+}
+
+impl TraceRecorder {
+    pub(super) fn new() -> Self {
+        TraceRecorder {
+            started: Instant::now(),
+            events: Vec::new(),
+        }
+    }
+
+    pub(super) fn begin(&mut self, runtime_id: RuntimeId, category: &'static str, name: String) {
+        self.push(runtime_id, category, Some(name));
+    }
+
+    pub(super) fn end(&mut self, runtime_id: RuntimeId, category: &'static str) {
+        self.push(runtime_id, category, None);
+    }
+
+    fn push(&mut self, runtime_id: RuntimeId, category: &'static str, name: Option<String>) {
+        let timestamp = self.started.elapsed().as_nanos() as u64;
+        self.events.push(TraceEvent {
+            runtime_id,
+            category,
+            name,
+            timestamp,
+        });
+    }
+
+    pub(super) fn finish(self) -> QueryTrace {
+        QueryTrace {
+            events: self.events,
+        }
+    }
+}
+
+struct TraceEvent {
+    runtime_id: RuntimeId,
+    category: &'static str,
+    /// The key being executed or validated; `None` for the end of a span.
+    name: Option<String>,
+    /// Nanoseconds since the recording started.
+    timestamp: u64,
+}
+
+/// A trace of query executions and validations, recorded between
+/// [`Runtime::start_trace`](crate::Runtime::start_trace) and
+/// [`Runtime::finish_trace`](crate::Runtime::finish_trace).
+///
+/// Every execution and validation is a span, and spans nest when one
+/// query calls another. Each runtime (that is, each snapshot or forked
+/// runtime) shows up as a separate thread.
+pub struct QueryTrace {
+    events: Vec<TraceEvent>,
+}
+
+impl QueryTrace {
+    /// The number of spans in the trace, including spans whose end was
+    /// not recorded.
+    pub fn span_count(&self) -> usize {
+        self.events
+            .iter()
+            .filter(|event| event.name.is_some())
+            .count()
+    }
+
+    /// Writes the trace in the Chrome Trace Event format, which can be
+    /// loaded into `chrome://tracing`, Perfetto or speedscope.
+    pub fn write_chrome_json(&self, mut out: impl Write) -> io::Result<()> {
+        writeln!(out, "{{")?;
+        writeln!(out, "  \"displayTimeUnit\": \"ms\",")?;
+        writeln!(out, "  \"traceEvents\": [")?;
+        for (i, event) in self.events.iter().enumerate() {
+            let (phase, name) = match &event.name {
+                Some(name) => ("B", format!(", \"name\": {}", json_string(name))),
+                None => ("E", String::new()),
+            };
+            let comma = if i + 1 < self.events.len() { "," } else { "" };
+            writeln!(
+                out,
+                "    {{\"ph\": \"{}\", \"cat\": \"{}\"{}, \"pid\": 1, \"tid\": {}, \"ts\": {}.{:03}}}{}",
+                phase,
+                event.category,
+                name,
+                event.runtime_id.counter,
+                event.timestamp / 1000,
+                event.timestamp % 1000,
+                comma
+            )?;
+        }
+        writeln!(out, "  ]")?;
+        writeln!(out, "}}")
+    }
+}
@@ -0,0 +1,56 @@
+//! Spans for the `tracing` crate. With the `tracing` feature these are
+//! the real thing; without it `debug_span!` ignores its arguments and
+//! produces a span that does nothing.
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Instrument;
+
+/// Opens a span at the debug level, taking the same arguments as
+/// `tracing::debug_span!`.
+#[cfg(feature = "tracing")]
+macro_rules! debug_span {
+    ($($args:tt)*) => {
+        tracing::debug_span!($($args)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! debug_span {
+    ($($args:tt)*) => {
+        crate::span::Span
+    };
+}
+
+/// Opens a span for an operation on `database_key` in `revision`.
+macro_rules! key_span {
+    ($name:literal, $db:expr, $database_key:expr, $revision:expr) => {
+        debug_span!(
+            $name,
+            database_key = ?$database_key.debug(&**$db),
+            revision = ?$revision,
+            runtime_id = ?$db.salsa_runtime().id(),
+        )
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    #[inline]
+    pub(crate) fn entered(self) -> Self {
+        self
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) trait Instrument: Sized {
+    #[inline]
+    fn instrument(self, _span: Span) -> Self {
+        self
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl<T> Instrument for T {}
@@ -1,4 +1,4 @@
-use salsa::{ParallelDatabase, Snapshot};
+use salsa::{Database as _, Durability, ParallelDatabase, Snapshot};
 
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
 struct Error {
@@ -43,6 +43,13 @@ trait Database: salsa::Database {
     fn cycle_b(&self) -> Result<(), Error>;
 
     fn cycle_c(&self) -> Result<(), Error>;
+
+    // `flagged_a` and `flagged_b` only form a cycle while `flag` is set
+    #[salsa::input]
+    fn flag(&self) -> bool;
+    fn flagged_a(&self) -> u32;
+    fn flagged_b(&self) -> u32;
+    fn try_flagged(&self) -> String;
 }
 
 fn recover_a(_db: &dyn Database, cycle: &[String]) -> Result<(), Error> {
@@ -92,6 +99,25 @@ fn cycle_c(db: &dyn Database) -> Result<(), Error> {
     db.cycle_b()
 }
 
+fn flagged_a(db: &dyn Database) -> u32 {
+    if db.flag() {
+        db.flagged_b()
+    } else {
+        1
+    }
+}
+
+fn flagged_b(db: &dyn Database) -> u32 {
+    db.flagged_a()
+}
+
+fn try_flagged(db: &dyn Database) -> String {
+    match FlaggedAQuery.in_db(db).try_get(()) {
+        Ok(value) => format!("ok {}", value),
+        Err(_) => "err".to_string(),
+    }
+}
+
 #[test]
 #[should_panic(expected = "cycle detected")]
 fn cycle_memoized() {
@@ -106,6 +132,56 @@ fn cycle_volatile() {
     query.volatile_a();
 }
 
+#[test]
+fn cycle_memoized_try_get() {
+    let query = DatabaseImpl::default();
+    for _ in 0..2 {
+        let err = MemoizedAQuery.in_db(&query).try_get(()).unwrap_err();
+        let cycle: Vec<_> = err
+            .participants()
+            .iter()
+            .map(|participant| format!("{:?}", participant.debug(&query)))
+            .collect();
+        assert_eq!(cycle, ["memoized_a(())", "memoized_b(())"]);
+        assert_eq!(err.durability(), Durability::HIGH);
+    }
+}
+
+#[test]
+fn cycle_try_get_in_query_depends_on_cycle() {
+    let mut query = DatabaseImpl::default();
+    query.set_flag(true);
+    assert_eq!(query.try_flagged(), "err");
+
+    query.set_flag(false);
+    assert_eq!(query.try_flagged(), "ok 1");
+}
+
+#[test]
+fn cycle_report() {
+    let query = DatabaseImpl::default();
+    let err = MemoizedAQuery.in_db(&query).try_get(()).unwrap_err();
+    let report = err.report(&query);
+    let steps: Vec<_> = report
+        .steps()
+        .iter()
+        .map(|step| (step.query.as_str(), step.runtime_id))
+        .collect();
+    let runtime_id = Some(query.salsa_runtime().id());
+    assert_eq!(
+        steps,
+        [
+            ("memoized_a(())", runtime_id),
+            ("memoized_b(())", runtime_id)
+        ]
+    );
+    assert!(!report.spans_runtimes());
+    assert_eq!(
+        report.to_string(),
+        "cycle detected:\n    memoized_a(())\n    memoized_b(())\n"
+    );
+}
+
 #[test]
 fn cycle_cycle() {
     let query = DatabaseImpl::default();
@@ -0,0 +1,131 @@
+//! Test the reverse dependency index behind `QueryTable::dependents`.
+
+use salsa::{Database as _, Durability};
+
+#[salsa::query_group(QueryGroupStorage)]
+trait QueryGroup: salsa::Database {
+    #[salsa::input]
+    fn input(&self, x: u32) -> u32;
+
+    #[salsa::input]
+    fn use_second(&self) -> bool;
+
+    fn double(&self, x: u32) -> u32;
+    fn pick(&self) -> u32;
+}
+
+fn double(db: &dyn QueryGroup, x: u32) -> u32 {
+    db.input(x) * 2
+}
+
+fn pick(db: &dyn QueryGroup) -> u32 {
+    if db.use_second() {
+        db.double(2)
+    } else {
+        db.double(1)
+    }
+}
+
+#[salsa::database(QueryGroupStorage)]
+#[derive(Default)]
+struct Database {
+    storage: salsa::Storage<Self>,
+}
+
+impl salsa::Database for Database {}
+
+impl Database {
+    fn new() -> Self {
+        let mut db = Database::default();
+        db.set_input(1, 10);
+        db.set_input(2, 20);
+        db.set_use_second(false);
+        db
+    }
+}
+
+fn dependents_of_input(db: &Database, x: u32) -> Vec<String> {
+    InputQuery
+        .in_db(db)
+        .dependents(&x)
+        .into_iter()
+        .map(|key| format!("{:?}", key.debug(db)))
+        .collect()
+}
+
+fn dependents_of_double(db: &Database, x: u32) -> Vec<String> {
+    DoubleQuery
+        .in_db(db)
+        .dependents(&x)
+        .into_iter()
+        .map(|key| format!("{:?}", key.debug(db)))
+        .collect()
+}
+
+#[test]
+fn dependents_follow_the_latest_memo() {
+    let mut db = Database::new();
+    db.enable_dependents_index();
+
+    assert_eq!(db.pick(), 20);
+    assert_eq!(dependents_of_input(&db, 1), vec!["double(1)"]);
+    assert_eq!(dependents_of_input(&db, 2), Vec::<String>::new());
+    assert_eq!(dependents_of_double(&db, 1), vec!["pick(())"]);
+
+    db.set_use_second(true);
+    assert_eq!(db.pick(), 40);
+    assert_eq!(dependents_of_double(&db, 1), Vec::<String>::new());
+    assert_eq!(dependents_of_double(&db, 2), vec!["pick(())"]);
+    assert_eq!(dependents_of_input(&db, 2), vec!["double(2)"]);
+}
+
+#[test]
+fn stale_memos_are_not_dependents() {
+    let mut db = Database::new();
+    db.enable_dependents_index();
+    assert_eq!(db.pick(), 20);
+
+    // `double(1)` is still memoized, but nothing asked for it in this
+    // revision, so it did not read its input in it.
+    db.set_use_second(true);
+    assert_eq!(db.pick(), 40);
+    assert_eq!(dependents_of_input(&db, 1), Vec::<String>::new());
+
+    // Validating the memo brings it back.
+    assert_eq!(db.double(1), 20);
+    assert_eq!(dependents_of_input(&db, 1), vec!["double(1)"]);
+}
+
+#[test]
+fn enabling_indexes_existing_memos() {
+    let mut db = Database::new();
+    assert_eq!(db.pick(), 20);
+
+    db.enable_dependents_index();
+    assert_eq!(dependents_of_input(&db, 1), vec!["double(1)"]);
+    assert_eq!(dependents_of_double(&db, 1), vec!["pick(())"]);
+}
+
+#[test]
+fn invalidate_and_sweep_remove_dependents() {
+    let mut db = Database::new();
+    db.enable_dependents_index();
+    assert_eq!(db.pick(), 20);
+
+    PickQuery.in_db_mut(&mut db).invalidate(&());
+    assert_eq!(dependents_of_double(&db, 1), Vec::<String>::new());
+    assert_eq!(db.pick(), 20);
+    assert_eq!(dependents_of_double(&db, 1), vec!["pick(())"]);
+
+    db.salsa_runtime_mut().synthetic_write(Durability::LOW);
+    db.sweep_all(salsa::SweepStrategy::discard_outdated());
+    assert_eq!(dependents_of_input(&db, 1), Vec::<String>::new());
+    assert_eq!(dependents_of_double(&db, 1), Vec::<String>::new());
+}
+
+#[test]
+#[should_panic(expected = "enable_dependents_index")]
+fn dependents_require_the_index() {
+    let db = Database::new();
+    InputQuery.in_db(&db).dependents(&1);
+}
@@ -0,0 +1,225 @@
+//! Test the events reported to `salsa_event` for the lifecycle of
+//! revisions, memos and interned values.
+
+use salsa::{
+    Database as _, Durability, EventKind, InternId, ParallelDatabase, Snapshot, SweepStrategy,
+};
+use std::sync::{Arc, Mutex};
+
+#[salsa::query_group(QueryGroupStorage)]
+trait QueryGroup: salsa::Database {
+    #[salsa::input]
+    fn input(&self, x: u32) -> u32;
+
+    #[salsa::interned]
+    fn intern(&self, x: u32) -> InternId;
+
+    fn double(&self, x: u32) -> u32;
+
+    #[salsa::cycle(recover)]
+    fn cyclic(&self) -> u32;
+
+    fn wait_for_cancellation(&self) -> ();
+}
+
+fn double(db: &dyn QueryGroup, x: u32) -> u32 {
+    db.input(x) * 2
+}
+
+fn cyclic(db: &dyn QueryGroup) -> u32 {
+    db.cyclic()
+}
+
+fn recover(_db: &dyn QueryGroup, _cycle: &[String]) -> u32 {
+    0
+}
+
+fn wait_for_cancellation(db: &dyn QueryGroup) {
+    while !db.is_current_revision_canceled() {
+        std::thread::yield_now();
+    }
+}
+
+#[salsa::database(QueryGroupStorage)]
+#[derive(Default)]
+struct Database {
+    storage: salsa::Storage<Self>,
+    events: Arc<Mutex<Vec<String>>>,
+}
+
+impl salsa::Database for Database {
+    fn salsa_event(&self, event: salsa::Event) {
+        let event = match event.kind {
+            EventKind::DidIncrementRevision {
+                revision,
+                durability,
+            } => format!("DidIncrementRevision({:?}, {:?})", revision, durability),
+            EventKind::DidSetInput {
+                database_key,
+                durability,
+            } => format!(
+                "DidSetInput({:?}, {:?})",
+                database_key.debug(self),
+                durability
+            ),
+            EventKind::DidRemoveInput { database_key } => {
+                format!("DidRemoveInput({:?})", database_key.debug(self))
+            }
+            EventKind::DidEvictValue { database_key } => {
+                format!("DidEvictValue({:?})", database_key.debug(self))
+            }
+            EventKind::DidSweep {
+                database_key,
+                memo_removed,
+            } => format!("DidSweep({:?}, {})", database_key.debug(self), memo_removed),
+            EventKind::DidCollectInterned { database_key } => {
+                format!("DidCollectInterned({})", database_key.key_index())
+            }
+            EventKind::DidDetectCycle {
+                database_key,
+                cycle,
+            } => format!(
+                "DidDetectCycle({:?}, {:?})",
+                database_key.debug(self),
+                cycle
+                    .iter()
+                    .map(|key| format!("{:?}", key.debug(self)))
+                    .collect::<Vec<_>>()
+            ),
+            EventKind::DidObserveCancellation => "DidObserveCancellation".to_string(),
+            _ => return,
+        };
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+impl ParallelDatabase for Database {
+    fn snapshot(&self) -> Snapshot<Self> {
+        Snapshot::new(Database {
+            storage: self.storage.snapshot(),
+            events: self.events.clone(),
+        })
+    }
+
+    fn fork(&self, forker: salsa::ForkState) -> Snapshot<Self> {
+        Snapshot::new(Database {
+            storage: self.storage.fork(forker),
+            events: self.events.clone(),
+        })
+    }
+}
+
+impl Database {
+    fn take_events(&self) -> Vec<String> {
+        std::mem::take(&mut *self.events.lock().unwrap())
+    }
+}
+
+#[test]
+fn set_input_and_synthetic_write() {
+    let mut db = Database::default();
+    db.set_input(1, 10);
+    db.set_input_with_durability(1, 20, Durability::HIGH);
+    db.synthetic_write(Durability::MEDIUM);
+    assert_eq!(
+        db.take_events(),
+        vec![
+            "DidIncrementRevision(R2, None)",
+            "DidSetInput(input(1), Durability(0))",
+            "DidIncrementRevision(R3, Some(Durability(0)))",
+            "DidSetInput(input(1), Durability(2))",
+            "DidIncrementRevision(R4, Some(Durability(1)))",
+        ]
+    );
+}
+
+#[test]
+fn remove_input() {
+    let mut db = Database::default();
+    db.set_input_with_durability(1, 10, Durability::HIGH);
+    db.take_events();
+    assert_eq!(InputQuery.in_db_mut(&mut db).remove(&1), Some(10));
+    // Removing a missing key is a no-op.
+    assert_eq!(InputQuery.in_db_mut(&mut db).remove(&1), None);
+    assert_eq!(
+        db.take_events(),
+        vec![
+            "DidIncrementRevision(R3, Some(Durability(2)))",
+            "DidRemoveInput(input(<removed>))",
+        ]
+    );
+}
+
+#[test]
+fn sweep_and_collect_interned() {
+    let mut db = Database::default();
+    db.set_input(1, 10);
+    db.set_input(2, 20);
+    db.double(1);
+    db.double(2);
+    db.intern(1);
+    db.synthetic_write(Durability::HIGH);
+    db.take_events();
+
+    // Only `double(1)` is used in the new revision, so it is the only
+    // memo that is kept.
+    db.double(1);
+    db.sweep_all(SweepStrategy::discard_outdated());
+    assert_eq!(
+        db.take_events(),
+        vec!["DidCollectInterned(0)", "DidSweep(double(2), true)"]
+    );
+}
+
+#[test]
+fn lru_eviction() {
+    let mut db = Database::default();
+    DoubleQuery.in_db_mut(&mut db).set_lru_capacity(8);
+    for x in 0..32 {
+        db.set_input(x, x);
+    }
+    db.take_events();
+
+    for x in 0..32 {
+        db.double(x);
+    }
+    let events = db.take_events();
+    assert!(!events.is_empty());
+    assert!(events
+        .iter()
+        .all(|event| event.starts_with("DidEvictValue(double(")));
+}
+
+#[test]
+fn cycle() {
+    let db = Database::default();
+    assert_eq!(db.cyclic(), 0);
+    assert_eq!(
+        db.take_events(),
+        vec![r#"DidDetectCycle(cyclic(()), ["cyclic(())"])"#]
+    );
+}
+
+#[test]
+fn cancellation() {
+    let mut db = Database::default();
+    let snapshot = db.snapshot();
+    // The query either observes the cancellation itself, or is cancelled
+    // when it is fetched after the write started.
+    let thread = std::thread::spawn(move || {
+        let _ = salsa::catch_cancellation(|| snapshot.wait_for_cancellation());
+    });
+
+    // Blocks until the snapshot has observed the cancellation and is
+    // dropped.
+    db.set_input(1, 10);
+    thread.join().unwrap();
+    assert_eq!(
+        db.take_events(),
+        vec![
+            "DidObserveCancellation",
+            "DidIncrementRevision(R2, None)",
+            "DidSetInput(input(1), Durability(0))",
+        ]
+    );
+}
@@ -0,0 +1,168 @@
+//! Test the reasons reported for executing a query, both through
+//! `QueryTable::explain` and the `WillExecuteBecause` event.
+
+use salsa::{Database as _, Durability, ExecuteReason, SweepStrategy};
+use std::{cell::RefCell, rc::Rc};
+
+#[salsa::query_group(QueryGroupStorage)]
+trait QueryGroup: salsa::Database {
+    #[salsa::input]
+    fn input(&self, x: u32) -> u32;
+
+    fn double(&self, x: u32) -> u32;
+    fn volatile(&self) -> u32;
+
+    #[salsa::dependencies]
+    fn not_memoized(&self, x: u32) -> u32;
+}
+
+fn double(db: &dyn QueryGroup, x: u32) -> u32 {
+    db.input(x) * 2
+}
+
+fn volatile(db: &dyn QueryGroup) -> u32 {
+    db.salsa_runtime().report_untracked_read();
+    db.input(1)
+}
+
+fn not_memoized(db: &dyn QueryGroup, x: u32) -> u32 {
+    db.double(x) + 1
+}
+
+#[salsa::database(QueryGroupStorage)]
+#[derive(Default)]
+struct Database {
+    storage: salsa::Storage<Self>,
+    reasons: Rc<RefCell<Vec<String>>>,
+}
+
+impl salsa::Database for Database {
+    fn salsa_event(&self, event: salsa::Event) {
+        if let salsa::EventKind::WillExecuteBecause {
+            database_key,
+            reason,
+        } = event.kind
+        {
+            self.reasons.borrow_mut().push(format!(
+                "{:?}: {}",
+                database_key.debug(self),
+                self.describe(reason)
+            ));
+        }
+    }
+}
+
+impl Database {
+    fn new() -> Self {
+        let mut db = Database::default();
+        db.set_input(1, 10);
+        db.set_input(2, 20);
+        db
+    }
+
+    fn describe(&self, reason: ExecuteReason) -> String {
+        match reason {
+            ExecuteReason::InputChanged { input } => format!("{:?} changed", input.debug(self)),
+            reason => format!("{:?}", reason),
+        }
+    }
+
+    fn take_reasons(&self) -> Vec<String> {
+        std::mem::take(&mut *self.reasons.borrow_mut())
+    }
+}
+
+#[test]
+fn first_execution_and_changed_input() {
+    let mut db = Database::new();
+    assert_eq!(DoubleQuery.in_db(&db).explain(&1), None);
+
+    db.double(1);
+    assert_eq!(db.take_reasons(), vec!["double(1): NotComputed"]);
+    assert_eq!(
+        DoubleQuery.in_db(&db).explain(&1),
+        Some(ExecuteReason::NotComputed)
+    );
+
+    db.set_input(1, 11);
+    db.double(1);
+    assert_eq!(db.take_reasons(), vec!["double(1): input(1) changed"]);
+    let input = InputQuery.in_db(&db).database_key_index(&1).unwrap();
+    assert_eq!(
+        DoubleQuery.in_db(&db).explain(&1),
+        Some(ExecuteReason::InputChanged { input })
+    );
+
+    // Validated memos keep the reason of their last execution.
+    db.set_input(2, 21);
+    db.double(1);
+    assert_eq!(db.take_reasons(), Vec::<String>::new());
+    assert_eq!(
+        DoubleQuery.in_db(&db).explain(&1),
+        Some(ExecuteReason::InputChanged { input })
+    );
+}
+
+#[test]
+fn untracked_and_invalidated() {
+    let mut db = Database::new();
+    db.volatile();
+    db.double(1);
+    db.take_reasons();
+
+    db.salsa_runtime_mut().synthetic_write(Durability::LOW);
+    db.volatile();
+    assert_eq!(db.take_reasons(), vec!["volatile(()): UntrackedRead"]);
+
+    DoubleQuery.in_db_mut(&mut db).invalidate(&1);
+    db.double(1);
+    assert_eq!(db.take_reasons(), vec!["double(1): Invalidated"]);
+}
+
+#[test]
+fn evicted() {
+    let mut db = Database::new();
+    DoubleQuery.in_db_mut(&mut db).set_lru_capacity(8);
+    for i in 0..32 {
+        db.set_input(i, i);
+    }
+    for i in 0..32 {
+        db.double(i);
+    }
+    db.take_reasons();
+
+    for i in 0..32 {
+        db.double(i);
+    }
+    let reasons = db.take_reasons();
+    assert!(!reasons.is_empty());
+    assert!(reasons.iter().all(|reason| reason.ends_with(": Evicted")));
+}
+
+#[test]
+fn swept_and_not_memoized() {
+    let mut db = Database::new();
+    db.double(1);
+    db.take_reasons();
+
+    db.salsa_runtime_mut().synthetic_write(Durability::LOW);
+    db.sweep_all(SweepStrategy::default().discard_values().sweep_outdated());
+    db.double(1);
+    assert_eq!(db.take_reasons(), vec!["double(1): Swept"]);
+
+    db.salsa_runtime_mut().synthetic_write(Durability::LOW);
+    db.sweep_all(SweepStrategy::discard_outdated());
+    db.double(1);
+    assert_eq!(db.take_reasons(), vec!["double(1): Swept"]);
+
+    db.not_memoized(2);
+    db.not_memoized(2);
+    assert_eq!(
+        db.take_reasons(),
+        vec![
+            "not_memoized(2): NotComputed",
+            "double(2): NotComputed",
+            "not_memoized(2): NotMemoized",
+        ]
+    );
+}
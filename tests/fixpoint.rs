@@ -0,0 +1,146 @@
+//! Test iterating cycles to a fixpoint with `set_cycle_fixpoint`.
+
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[salsa::query_group(QueryGroupStorage)]
+trait QueryGroup: salsa::Database {
+    #[salsa::input]
+    fn edges(&self, node: u32) -> Arc<Vec<u32>>;
+
+    /// The nodes that can be reached from `node` in one or more steps.
+    fn reachable(&self, node: u32) -> BTreeSet<u32>;
+
+    /// Never converges, since each iteration adds one.
+    fn count(&self, x: u32) -> u32;
+
+    /// A cycle where only `configured` is iterated to a fixpoint.
+    fn configured(&self, x: u32) -> u32;
+    fn unconfigured(&self, x: u32) -> u32;
+}
+
+fn reachable(db: &dyn QueryGroup, node: u32) -> BTreeSet<u32> {
+    let mut reachable = BTreeSet::new();
+    for &next in db.edges(node).iter() {
+        reachable.insert(next);
+        reachable.extend(db.reachable(next));
+    }
+    reachable
+}
+
+fn count(db: &dyn QueryGroup, x: u32) -> u32 {
+    db.count(x) + 1
+}
+
+fn configured(db: &dyn QueryGroup, x: u32) -> u32 {
+    db.unconfigured(x).max(1)
+}
+
+fn unconfigured(db: &dyn QueryGroup, x: u32) -> u32 {
+    db.configured(x)
+}
+
+#[salsa::database(QueryGroupStorage)]
+#[derive(Default)]
+struct Database {
+    storage: salsa::Storage<Self>,
+    executions: Arc<AtomicUsize>,
+}
+
+impl salsa::Database for Database {
+    fn salsa_event(&self, event: salsa::Event) {
+        if let salsa::EventKind::WillExecute { .. } = event.kind {
+            self.executions.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Database {
+    /// A graph with the cycle `0 -> 1 -> 2 -> 0` and the edge `2 -> 3`.
+    fn new() -> Self {
+        let mut db = Database::default();
+        ReachableQuery
+            .in_db_mut(&mut db)
+            .set_cycle_fixpoint(|_| BTreeSet::new(), 10);
+        CountQuery.in_db_mut(&mut db).set_cycle_fixpoint(|_| 0, 5);
+        ConfiguredQuery
+            .in_db_mut(&mut db)
+            .set_cycle_fixpoint(|_| 0, 5);
+        db.set_edges(0, Arc::new(vec![1]));
+        db.set_edges(1, Arc::new(vec![2]));
+        db.set_edges(2, Arc::new(vec![0, 3]));
+        db.set_edges(3, Arc::new(vec![]));
+        db
+    }
+
+    fn executions(&self) -> usize {
+        self.executions.load(Ordering::SeqCst)
+    }
+}
+
+fn set(nodes: &[u32]) -> BTreeSet<u32> {
+    nodes.iter().copied().collect()
+}
+
+#[test]
+fn converges() {
+    let db = Database::new();
+    assert_eq!(db.reachable(0), set(&[0, 1, 2, 3]));
+    assert_eq!(db.reachable(1), set(&[0, 1, 2, 3]));
+    assert_eq!(db.reachable(2), set(&[0, 1, 2, 3]));
+    assert_eq!(db.reachable(3), set(&[]));
+}
+
+#[test]
+fn memoizes_converged_values() {
+    let mut db = Database::new();
+    db.reachable(0);
+    let executions = db.executions();
+    db.reachable(1);
+    db.reachable(2);
+    assert_eq!(db.executions(), executions);
+
+    // The inputs of the cycle did not change.
+    db.set_edges(9, Arc::new(vec![]));
+    assert_eq!(db.reachable(2), set(&[0, 1, 2, 3]));
+    assert_eq!(db.reachable(0), set(&[0, 1, 2, 3]));
+    assert_eq!(db.executions(), executions);
+}
+
+#[test]
+fn recomputes_after_input_change() {
+    let mut db = Database::new();
+    db.reachable(0);
+
+    db.set_edges(3, Arc::new(vec![4]));
+    db.set_edges(4, Arc::new(vec![]));
+
+    // Enter the cycle through another query this time.
+    assert_eq!(db.reachable(1), set(&[0, 1, 2, 3, 4]));
+    let executions = db.executions();
+    assert_eq!(db.reachable(0), set(&[0, 1, 2, 3, 4]));
+    assert_eq!(db.reachable(2), set(&[0, 1, 2, 3, 4]));
+    assert_eq!(db.executions(), executions);
+}
+
+#[test]
+#[should_panic(expected = "cycle detected")]
+fn fails_after_max_iterations() {
+    let db = Database::new();
+    db.count(0);
+}
+
+#[test]
+#[should_panic(expected = "unconfigured(0) is part of a cycle that is iterated to a fixpoint")]
+fn fails_if_the_head_is_not_iterated() {
+    let db = Database::new();
+    db.unconfigured(0);
+}
+
+#[test]
+#[should_panic(expected = "unconfigured(0) is part of a cycle that is iterated to a fixpoint")]
+fn fails_if_a_participant_is_not_iterated() {
+    let db = Database::new();
+    db.configured(0);
+}
@@ -74,3 +74,37 @@ fn set_after_no_change() {
     assert_eq!(v, 44);
     db.assert_log(&["Max invoked"]);
 }
+
+/// Test that `set_if_changed` with the same value does not trigger a
+/// new revision.
+#[test]
+fn set_if_changed_after_no_change() {
+    let db = &mut TestContextImpl::default();
+
+    db.set_input2(0);
+
+    db.set_input1(44);
+    let v = db.max();
+    assert_eq!(v, 44);
+    db.assert_log(&["Max invoked"]);
+
+    assert!(!Input1Query.in_db_mut(db).set_if_changed((), 44));
+    let v = db.max();
+    assert_eq!(v, 44);
+    db.assert_log(&[]);
+
+    assert!(Input1Query.in_db_mut(db).set_if_changed((), 64));
+    let v = db.max();
+    assert_eq!(v, 64);
+    db.assert_log(&["Max invoked"]);
+
+    // Changing the durability counts as a change.
+    assert!(Input1Query.in_db_mut(db).set_with_durability_if_changed(
+        (),
+        64,
+        salsa::Durability::HIGH
+    ));
+    let v = db.max();
+    assert_eq!(v, 64);
+    db.assert_log(&["Max invoked"]);
+}
@@ -0,0 +1,103 @@
+//! Test reading the keys of an input query with `QueryTable::keys`.
+
+use salsa::Durability;
+use std::cell::Cell;
+
+#[salsa::query_group(QueryGroupStorage)]
+trait QueryGroup: salsa::Database + AsRef<Cell<usize>> {
+    #[salsa::input]
+    fn text(&self, name: String) -> String;
+
+    fn names(&self) -> Vec<String>;
+    fn total_length(&self) -> usize;
+}
+
+fn names(db: &dyn QueryGroup) -> Vec<String> {
+    let executions = db.as_ref();
+    executions.set(executions.get() + 1);
+    TextQuery.in_db(db).keys()
+}
+
+fn total_length(db: &dyn QueryGroup) -> usize {
+    db.names()
+        .iter()
+        .map(|name| db.text(name.clone()).len())
+        .sum()
+}
+
+#[salsa::database(QueryGroupStorage)]
+#[derive(Default)]
+struct Database {
+    storage: salsa::Storage<Self>,
+    executions: Cell<usize>,
+}
+
+impl salsa::Database for Database {}
+
+impl AsRef<Cell<usize>> for Database {
+    fn as_ref(&self) -> &Cell<usize> {
+        &self.executions
+    }
+}
+
+impl Database {
+    fn executions(&self) -> usize {
+        self.executions.replace(0)
+    }
+}
+
+#[test]
+fn keys_are_listed_in_insertion_order() {
+    let mut db = Database::default();
+    assert_eq!(db.names(), Vec::<String>::new());
+    db.set_text("b".to_string(), "bb".to_string());
+    db.set_text("a".to_string(), "a".to_string());
+    assert_eq!(db.names(), vec!["b", "a"]);
+    assert_eq!(db.total_length(), 3);
+}
+
+#[test]
+fn setting_values_does_not_change_keys() {
+    let mut db = Database::default();
+    db.set_text("a".to_string(), "a".to_string());
+    db.names();
+    db.executions();
+
+    db.set_text("a".to_string(), "aaa".to_string());
+    assert_eq!(db.names(), vec!["a"]);
+    assert_eq!(db.total_length(), 3);
+    assert_eq!(db.executions(), 0);
+}
+
+#[test]
+fn adding_and_removing_keys_changes_keys() {
+    let mut db = Database::default();
+    db.set_text_with_durability("a".to_string(), "a".to_string(), Durability::HIGH);
+    db.names();
+    db.executions();
+
+    db.set_text_with_durability("b".to_string(), "bb".to_string(), Durability::HIGH);
+    assert_eq!(db.names(), vec!["a", "b"]);
+    assert_eq!(db.executions(), 1);
+
+    TextQuery.in_db_mut(&mut db).remove(&"a".to_string());
+    assert_eq!(db.names(), vec!["b"]);
+    assert_eq!(db.total_length(), 2);
+    assert_eq!(db.executions(), 1);
+}
+
+#[test]
+fn keys_can_be_formatted() {
+    let mut db = Database::default();
+    db.set_text("a".to_string(), "a".to_string());
+    db.names();
+    db.set_text("b".to_string(), "b".to_string());
+    db.names();
+    let reason = NamesQuery.in_db(&db).explain(&()).unwrap();
+    match reason {
+        salsa::ExecuteReason::InputChanged { input } => {
+            assert_eq!(format!("{:?}", input.debug(&db)), "text(<keys>)")
+        }
+        reason => panic!("unexpected reason {:?}", reason),
+    }
+}
@@ -1,7 +1,9 @@
 //! Test setting LRU actually limits the number of things in the database;
+use salsa::debug::DebugQueryTable;
+use std::collections::VecDeque;
 use std::sync::{
     atomic::{AtomicUsize, Ordering},
-    Arc,
+    Arc, Mutex,
 };
 
 #[derive(Debug, PartialEq, Eq)]
@@ -26,6 +28,8 @@ impl Drop for HotPotato {
 trait QueryGroup: salsa::Database {
     fn get(&self, x: u32) -> Arc<HotPotato>;
     fn get_volatile(&self, x: u32) -> usize;
+    fn get_bytes(&self, x: u32) -> Vec<u8>;
+    fn get_untracked(&self, x: u32) -> u32;
 }
 
 fn get(_db: &dyn QueryGroup, x: u32) -> Arc<HotPotato> {
@@ -38,6 +42,15 @@ fn get_volatile(db: &dyn QueryGroup, _x: u32) -> usize {
     COUNTER.fetch_add(1, Ordering::SeqCst)
 }
 
+fn get_bytes(_db: &dyn QueryGroup, x: u32) -> Vec<u8> {
+    vec![0; x as usize]
+}
+
+fn get_untracked(db: &dyn QueryGroup, x: u32) -> u32 {
+    db.salsa_runtime().report_untracked_read();
+    x
+}
+
 #[salsa::database(QueryGroupStorage)]
 #[derive(Default)]
 struct Database {
@@ -100,3 +113,344 @@ fn lru_doesnt_break_volatile_queries() {
         assert_eq!(x, i)
     }
 }
+
+#[test]
+fn lru_weight_capacity() {
+    let mut db = Database::default();
+    GetBytesQuery
+        .in_db_mut(&mut db)
+        .set_lru_weight_capacity(1000, |bytes| bytes.len());
+    let weight_in_db = |db: &Database| -> usize {
+        GetBytesQuery
+            .in_db(db)
+            .entries::<Vec<_>>()
+            .into_iter()
+            .filter_map(|entry| entry.value)
+            .map(|bytes| bytes.len())
+            .sum()
+    };
+
+    for i in 0..100u32 {
+        assert_eq!(db.get_bytes(i).len(), i as usize);
+        assert!(weight_in_db(&db) <= 1000);
+    }
+
+    // A single value that is over the capacity is kept.
+    db.get_bytes(2000);
+    assert_eq!(weight_in_db(&db), 2000);
+
+    // Special case: setting capacity to zero disables LRU
+    GetBytesQuery
+        .in_db_mut(&mut db)
+        .set_lru_weight_capacity(0, |bytes| bytes.len());
+    for i in 0..100u32 {
+        db.get_bytes(i);
+    }
+    assert_eq!(weight_in_db(&db), 2000 + (0..100).sum::<usize>());
+}
+
+#[test]
+fn lru_weight_function_can_be_replaced() {
+    let mut db = Database::default();
+    GetBytesQuery
+        .in_db_mut(&mut db)
+        .set_lru_weight_capacity(1000, |bytes| bytes.len());
+    db.get_bytes(500);
+    db.get_bytes(499);
+    let evictions = GetBytesQuery.in_db(&db).lru_stats().evictions;
+
+    // Values that are used again are weighed with the new function.
+    GetBytesQuery
+        .in_db_mut(&mut db)
+        .set_lru_weight_capacity(1000, |_| 1);
+    db.get_bytes(500);
+    db.get_bytes(499);
+    for i in 1..=10u32 {
+        db.get_bytes(i);
+    }
+    let stats = GetBytesQuery.in_db(&db).lru_stats();
+    assert_eq!(stats.weight, 12);
+    assert_eq!(stats.evictions, evictions);
+}
+
+#[test]
+fn lru_stats() {
+    let mut db = Database::default();
+    GetBytesQuery.in_db_mut(&mut db).set_lru_capacity(32);
+    for i in 0..128u32 {
+        db.get_bytes(i);
+    }
+    let stats = GetBytesQuery.in_db(&db).lru_stats();
+    assert_eq!(
+        stats,
+        salsa::LruStats {
+            capacity: 32,
+            weight_capacity: 0,
+            len: 32,
+            weight: 0,
+            green_zone: 3,
+            yellow_zone: 6,
+            red_zone: 23,
+            evictions: 96,
+            evicted_executions: 0,
+        }
+    );
+
+    // Every value that is no longer in the list has to be executed
+    // again, evicting another one.
+    for i in 0..128u32 {
+        db.get_bytes(i);
+    }
+    let stats = GetBytesQuery.in_db(&db).lru_stats();
+    assert!(stats.evicted_executions >= 96);
+    assert_eq!(stats.evictions, 96 + stats.evicted_executions);
+
+    // The count belongs to the query, not to its list.
+    GetBytesQuery
+        .in_db_mut(&mut db)
+        .set_eviction_policy(salsa::EvictionPolicy::Clock);
+    let evicted_executions = GetBytesQuery.in_db(&db).lru_stats().evicted_executions;
+    assert_eq!(evicted_executions, stats.evicted_executions);
+    GetBytesQuery
+        .in_db_mut(&mut db)
+        .set_eviction_policy(salsa::EvictionPolicy::ApproximateLru);
+
+    GetBytesQuery
+        .in_db_mut(&mut db)
+        .set_lru_weight_capacity(1000, |bytes| bytes.len());
+    for i in 0..100u32 {
+        db.get_bytes(i);
+    }
+    let stats = GetBytesQuery.in_db(&db).lru_stats();
+    assert_eq!((stats.capacity, stats.weight_capacity), (0, 1000));
+    assert!(stats.weight <= 1000);
+    assert_eq!(
+        stats.len,
+        stats.green_zone + stats.yellow_zone + stats.red_zone
+    );
+}
+
+fn bytes_in_db(db: &Database) -> Vec<u32> {
+    let mut keys: Vec<_> = GetBytesQuery
+        .in_db(db)
+        .entries::<Vec<_>>()
+        .into_iter()
+        .filter(|entry| entry.value.is_some())
+        .map(|entry| entry.key)
+        .collect();
+    keys.sort_unstable();
+    keys
+}
+
+#[test]
+fn deterministic_eviction_policies() {
+    for &policy in &[salsa::EvictionPolicy::Lru, salsa::EvictionPolicy::Clock] {
+        let mut db = Database::default();
+        GetBytesQuery.in_db_mut(&mut db).set_lru_capacity(4);
+        GetBytesQuery.in_db_mut(&mut db).set_eviction_policy(policy);
+        for i in 0..4u32 {
+            db.get_bytes(i);
+        }
+
+        // `0` was used again, so `1` is the one to go.
+        db.get_bytes(0);
+        db.get_bytes(4);
+        assert_eq!(bytes_in_db(&db), vec![0, 2, 3, 4], "{:?}", policy);
+
+        for i in 5..100u32 {
+            db.get_bytes(i);
+        }
+        assert_eq!(bytes_in_db(&db), vec![96, 97, 98, 99], "{:?}", policy);
+
+        let stats = GetBytesQuery.in_db(&db).lru_stats();
+        assert_eq!((stats.capacity, stats.len), (4, 4), "{:?}", policy);
+        assert_eq!(stats.evictions, 96, "{:?}", policy);
+    }
+}
+
+/// Evicts the values in the order they were first used in.
+struct Fifo;
+
+#[derive(Debug)]
+struct FifoList<Node> {
+    state: Mutex<FifoState<Node>>,
+}
+
+#[derive(Debug)]
+struct FifoState<Node> {
+    capacity: usize,
+    nodes: VecDeque<Arc<Node>>,
+    evictions: u64,
+}
+
+impl<Node> Default for FifoState<Node> {
+    fn default() -> Self {
+        FifoState {
+            capacity: 0,
+            nodes: VecDeque::new(),
+            evictions: 0,
+        }
+    }
+}
+
+impl salsa::CustomEvictionPolicy for Fifo {
+    fn new_list<Node>(&self) -> Box<dyn salsa::EvictionList<Node> + Send + Sync>
+    where
+        Node: salsa::LruNode + Send + Sync + 'static,
+    {
+        Box::new(FifoList {
+            state: Mutex::new(FifoState::default()),
+        })
+    }
+}
+
+impl<Node: salsa::LruNode> salsa::EvictionList<Node> for FifoList<Node> {
+    fn set_lru_capacity(&self, len: usize) {
+        let mut state = self.state.lock().unwrap();
+        for node in state.nodes.drain(..) {
+            node.lru_index().clear();
+        }
+        state.capacity = len;
+    }
+
+    fn set_weight_capacity(&self, _max_weight: usize) {
+        unimplemented!("only limited by number of values")
+    }
+
+    fn record_use(&self, node: &Arc<Node>) -> Vec<Arc<Node>> {
+        let mut state = self.state.lock().unwrap();
+        if state.capacity == 0 || node.lru_index().is_in_lru() {
+            return Vec::new();
+        }
+        node.lru_index().store(0);
+        state.nodes.push_back(node.clone());
+        let mut evicted = Vec::new();
+        while state.nodes.len() > state.capacity {
+            let victim = state.nodes.pop_front().unwrap();
+            victim.lru_index().clear();
+            state.evictions += 1;
+            evicted.push(victim);
+        }
+        evicted
+    }
+
+    fn record_weighted_use(&self, node: &Arc<Node>, _weight: usize) -> Vec<Arc<Node>> {
+        self.record_use(node)
+    }
+
+    fn remove(&self, node: &Arc<Node>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let len = state.nodes.len();
+        state.nodes.retain(|member| !Arc::ptr_eq(member, node));
+        node.lru_index().clear();
+        state.nodes.len() != len
+    }
+
+    fn purge(&self) {
+        *self.state.lock().unwrap() = FifoState::default();
+    }
+
+    fn heap_size(&self) -> usize {
+        0
+    }
+
+    fn stats(&self) -> salsa::LruStats {
+        let state = self.state.lock().unwrap();
+        salsa::LruStats {
+            capacity: state.capacity,
+            len: state.nodes.len(),
+            red_zone: state.nodes.len(),
+            evictions: state.evictions,
+            ..Default::default()
+        }
+    }
+}
+
+#[test]
+fn custom_eviction_policy() {
+    let mut db = Database::default();
+    GetBytesQuery.in_db_mut(&mut db).set_lru_capacity(4);
+    GetBytesQuery
+        .in_db_mut(&mut db)
+        .set_custom_eviction_policy(Fifo);
+    for i in 0..4u32 {
+        db.get_bytes(i);
+    }
+
+    // Unlike with LRU, using `0` again does not keep it.
+    db.get_bytes(0);
+    db.get_bytes(4);
+    assert_eq!(bytes_in_db(&db), vec![1, 2, 3, 4]);
+
+    let stats = GetBytesQuery.in_db(&db).lru_stats();
+    assert_eq!((stats.capacity, stats.len, stats.evictions), (4, 4, 1));
+
+    // Going back to a built-in policy keeps the capacity.
+    GetBytesQuery
+        .in_db_mut(&mut db)
+        .set_eviction_policy(salsa::EvictionPolicy::Lru);
+    assert_eq!(GetBytesQuery.in_db(&db).lru_stats().capacity, 4);
+}
+
+#[test]
+fn eviction_policies_with_weight_capacity() {
+    for &policy in &[salsa::EvictionPolicy::Lru, salsa::EvictionPolicy::Clock] {
+        let mut db = Database::default();
+        GetBytesQuery
+            .in_db_mut(&mut db)
+            .set_lru_weight_capacity(1000, |bytes| bytes.len());
+        GetBytesQuery.in_db_mut(&mut db).set_eviction_policy(policy);
+        for i in 0..100u32 {
+            db.get_bytes(i);
+            let weight: u32 = bytes_in_db(&db).into_iter().sum();
+            assert!(weight <= 1000, "{:?}", policy);
+        }
+        let stats = GetBytesQuery.in_db(&db).lru_stats();
+        assert_eq!(stats.weight_capacity, 1000, "{:?}", policy);
+        assert_eq!(
+            stats.weight as u32,
+            bytes_in_db(&db).into_iter().sum::<u32>(),
+            "{:?}",
+            policy
+        );
+
+        // A single value that is over the capacity is kept.
+        db.get_bytes(2000);
+        assert_eq!(bytes_in_db(&db), vec![2000], "{:?}", policy);
+    }
+}
+
+#[test]
+fn lru_evicts_untracked_memos() {
+    let mut db = Database::default();
+    GetUntrackedQuery.in_db_mut(&mut db).set_lru_capacity(32);
+    GetUntrackedQuery
+        .in_db_mut(&mut db)
+        .set_lru_evict_untracked(true);
+    let values_in_db = |db: &Database| {
+        GetUntrackedQuery
+            .in_db(db)
+            .entries::<Vec<_>>()
+            .into_iter()
+            .filter(|entry| entry.value.is_some())
+            .count()
+    };
+
+    // The memos can't be discarded in the revision they were used in.
+    for i in 0..128u32 {
+        db.get_untracked(i);
+    }
+    assert_eq!(values_in_db(&db), 128);
+
+    // Once a new revision has started, they are.
+    salsa::Database::synthetic_write(&mut db, salsa::Durability::LOW);
+    db.get_untracked(128);
+    assert_eq!(values_in_db(&db), 32);
+    assert_eq!(GetUntrackedQuery.in_db(&db).lru_stats().len, 32);
+
+    for i in 0..128u32 {
+        assert_eq!(db.get_untracked(i), i);
+    }
+    let stats = GetUntrackedQuery.in_db(&db).lru_stats();
+    assert!(stats.evicted_executions >= 96);
+}
@@ -0,0 +1,185 @@
+//! Test the memory estimates reported by `memory::report`.
+
+use salsa::memory::{self, HeapSize};
+use salsa::{Database as _, Durability, InternId, SweepStrategy};
+
+#[salsa::query_group(QueryGroupStorage)]
+trait QueryGroup: salsa::Database {
+    #[salsa::input]
+    fn text(&self, x: u32) -> String;
+
+    #[salsa::interned]
+    fn intern(&self, text: String) -> InternId;
+
+    fn words(&self, x: u32) -> Vec<String>;
+    fn word_count(&self) -> usize;
+    fn double(&self, x: u32) -> u32;
+}
+
+fn words(db: &dyn QueryGroup, x: u32) -> Vec<String> {
+    db.text(x).split(' ').map(|word| word.to_string()).collect()
+}
+
+fn word_count(db: &dyn QueryGroup) -> usize {
+    db.words(1).len() + db.words(2).len()
+}
+
+fn double(_db: &dyn QueryGroup, x: u32) -> u32 {
+    x * 2
+}
+
+#[salsa::database(QueryGroupStorage)]
+#[derive(Default)]
+struct Database {
+    storage: salsa::Storage<Self>,
+}
+
+impl salsa::Database for Database {}
+
+impl Database {
+    fn new() -> Self {
+        let mut db = Database::default();
+        db.set_text(1, "a b c".to_string());
+        db.set_text(2, "d e".to_string());
+        db.intern("a".to_string());
+        db
+    }
+}
+
+#[test]
+fn counts_slots_values_and_edges() {
+    let db = Database::new();
+    assert_eq!(db.word_count(), 5);
+
+    let report = memory::report(&db);
+    let counts = |name| {
+        let memory = report.query(name).unwrap();
+        (memory.slots, memory.values, memory.edges)
+    };
+    assert_eq!(counts("text"), (2, 2, 0));
+    assert_eq!(counts("intern"), (1, 1, 0));
+    assert_eq!(counts("words"), (2, 2, 2));
+    assert_eq!(counts("word_count"), (1, 1, 2));
+    assert!(report.query("lookup_intern").is_none());
+
+    let bytes: Vec<_> = report.queries().iter().map(|memory| memory.bytes).collect();
+    assert!(bytes.windows(2).all(|pair| pair[0] >= pair[1]));
+    assert_eq!(report.total_bytes(), bytes.iter().sum::<usize>());
+    assert!(report.to_string().lines().nth(1).is_some());
+
+    // Sweeping the values leaves the slots and their dependencies.
+    db.sweep_all(
+        SweepStrategy::default()
+            .discard_values()
+            .sweep_all_revisions(),
+    );
+    let report = memory::report(&db);
+    let words = report.query("words").unwrap();
+    assert_eq!((words.slots, words.values, words.edges), (2, 0, 2));
+}
+
+#[test]
+fn heap_size_is_opt_in() {
+    let mut db = Database::new();
+    db.word_count();
+    let bytes = |db: &Database, name| memory::report(db).query(name).unwrap().bytes;
+    let text = bytes(&db, "text");
+    let words = bytes(&db, "words");
+    let intern = bytes(&db, "intern");
+
+    TextQuery.in_db_mut(&mut db).enable_heap_size();
+    WordsQuery.in_db_mut(&mut db).enable_heap_size();
+    InternQuery.in_db_mut(&mut db).enable_heap_size();
+    assert_eq!(bytes(&db, "text"), text + "a b c".len() + "d e".len());
+    let word_bytes: usize = db
+        .words(1)
+        .iter()
+        .chain(db.words(2).iter())
+        .map(|word| std::mem::size_of::<String>() + word.heap_size())
+        .sum();
+    assert_eq!(bytes(&db, "words"), words + word_bytes);
+    // The interned string is stored both as the key of the map and in
+    // the slot.
+    assert_eq!(bytes(&db, "intern"), intern + 2);
+}
+
+#[test]
+fn compact_removes_dead_keys() {
+    let mut db = Database::new();
+    for x in 0..100 {
+        db.double(x);
+    }
+    assert_eq!(db.word_count(), 5);
+    let memory = |db: &Database, name| {
+        let report = memory::report(db);
+        let memory = report.query(name).unwrap();
+        (memory.slots, memory.bytes)
+    };
+    let (slots, bytes) = memory(&db, "double");
+    assert_eq!(slots, 100);
+
+    // Keys that still have a memo are kept.
+    db.compact_all();
+    assert_eq!(memory(&db, "double").0, 100);
+
+    let everything = SweepStrategy::default()
+        .discard_everything()
+        .sweep_all_revisions();
+    DoubleQuery.in_db(&db).sweep(everything);
+    WordsQuery.in_db(&db).sweep(everything);
+    db.compact_all();
+    let (slots, compacted_bytes) = memory(&db, "double");
+    assert_eq!(slots, 0);
+    assert!(compacted_bytes < bytes);
+    assert_eq!(memory(&db, "words").0, 0);
+    assert_eq!(memory(&db, "word_count").0, 1);
+
+    // `word_count` depended on keys that are gone, so it is recomputed
+    // in the next revision and brings them back.
+    salsa::Database::synthetic_write(&mut db, Durability::LOW);
+    assert_eq!(db.word_count(), 5);
+    assert_eq!(memory(&db, "words").0, 2);
+    assert_eq!(db.double(7), 14);
+    assert_eq!(memory(&db, "double").0, 1);
+}
+
+#[test]
+fn compact_removes_keys_of_lru_members() {
+    let mut db = Database::new();
+    DoubleQuery.in_db_mut(&mut db).set_lru_capacity(10);
+    let everything = SweepStrategy::default()
+        .discard_everything()
+        .sweep_all_revisions();
+    let key_index = |db: &Database, x| {
+        DoubleQuery
+            .in_db(db)
+            .database_key_index(&x)
+            .unwrap()
+            .key_index()
+    };
+
+    // Memos verified before the key was removed may still store its
+    // index, so it is not handed out again right away.
+    db.double(1000);
+    DoubleQuery.in_db(&db).sweep(everything);
+    db.compact_all();
+    db.double(1001);
+    assert_eq!(key_index(&db, 1001), 1);
+    DoubleQuery.in_db(&db).sweep(everything);
+    db.compact_all();
+
+    for round in 1..4 {
+        // After a durable change, every memo checks its inputs again,
+        // so the indices of the removed keys can be reused.
+        salsa::Database::synthetic_write(&mut db, Durability::HIGH);
+        for x in 0..100 {
+            db.double(round * 100 + x);
+        }
+        assert!((0..100).all(|x| key_index(&db, round * 100 + x) < 100));
+
+        DoubleQuery.in_db(&db).sweep(everything);
+        db.compact_all();
+        assert_eq!(memory::report(&db).query("double").unwrap().slots, 0);
+        assert_eq!(DoubleQuery.in_db(&db).lru_stats().len, 0);
+    }
+}
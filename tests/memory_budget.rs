@@ -0,0 +1,195 @@
+//! Test evicting values to stay within `Runtime::set_memory_budget`.
+
+use salsa::{memory, Database as _, SweepStrategy};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[salsa::query_group(QueryGroupStorage)]
+trait QueryGroup: salsa::Database {
+    fn first(&self, x: u32) -> Vec<u8>;
+    fn second(&self, x: u32) -> Vec<u8>;
+    fn unbudgeted(&self, x: u32) -> Vec<u8>;
+    fn volatile(&self, x: u32) -> Vec<u8>;
+}
+
+fn first(_db: &dyn QueryGroup, x: u32) -> Vec<u8> {
+    vec![x as u8; 1000]
+}
+
+fn second(_db: &dyn QueryGroup, x: u32) -> Vec<u8> {
+    vec![x as u8; 1000]
+}
+
+fn unbudgeted(_db: &dyn QueryGroup, x: u32) -> Vec<u8> {
+    vec![x as u8; 1000]
+}
+
+fn volatile(db: &dyn QueryGroup, x: u32) -> Vec<u8> {
+    db.salsa_runtime().report_untracked_read();
+    vec![x as u8; 1000]
+}
+
+#[salsa::database(QueryGroupStorage)]
+#[derive(Default)]
+struct Database {
+    storage: salsa::Storage<Self>,
+    evictions: Arc<AtomicUsize>,
+}
+
+impl salsa::Database for Database {
+    fn salsa_event(&self, event: salsa::Event) {
+        if let salsa::EventKind::DidEvictValue { .. } = event.kind {
+            self.evictions.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Database {
+    fn new(budget: usize) -> Self {
+        let mut db = Database::default();
+        FirstQuery.in_db_mut(&mut db).enable_heap_size();
+        FirstQuery.in_db_mut(&mut db).enable_memory_budget();
+        SecondQuery.in_db_mut(&mut db).enable_heap_size();
+        SecondQuery.in_db_mut(&mut db).enable_memory_budget();
+        UnbudgetedQuery.in_db_mut(&mut db).enable_heap_size();
+        VolatileQuery.in_db_mut(&mut db).enable_heap_size();
+        VolatileQuery.in_db_mut(&mut db).enable_memory_budget();
+        db.salsa_runtime().set_memory_budget(budget);
+        db
+    }
+
+    fn values(&self, query_name: &str) -> usize {
+        memory::report(self).query(query_name).unwrap().values
+    }
+}
+
+#[test]
+fn budget_is_shared_by_queries() {
+    let db = Database::new(8_000);
+    for x in 0..20 {
+        db.first(x);
+        db.unbudgeted(x);
+    }
+    for x in 0..20 {
+        db.second(x);
+    }
+
+    // Each value weighs more than 1000 bytes, so at most 7 fit.
+    let values = db.values("first") + db.values("second");
+    assert!(values <= 7, "{} values kept", values);
+    assert!(db.values("second") > 0);
+    assert_eq!(db.evictions.load(Ordering::SeqCst), 40 - values);
+    assert_eq!(db.values("unbudgeted"), 20);
+}
+
+#[test]
+fn evicted_values_are_recomputed() {
+    let db = Database::new(4_000);
+    for x in 0..10 {
+        assert_eq!(db.first(x), vec![x as u8; 1000]);
+    }
+    for x in 0..10 {
+        assert_eq!(db.first(x), vec![x as u8; 1000]);
+    }
+    assert!(db.values("first") <= 3);
+}
+
+#[test]
+fn zero_budget_disables_eviction() {
+    let db = Database::new(8_000);
+    db.salsa_runtime().set_memory_budget(0);
+    for x in 0..20 {
+        db.first(x);
+    }
+    assert_eq!(db.values("first"), 20);
+    assert_eq!(db.evictions.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn purged_values_leave_the_budget() {
+    let db = Database::new(8_000);
+    for x in 0..5 {
+        db.first(x);
+    }
+    // `QueryTable::purge` is only there for async queries.
+    let group_storage = salsa::plumbing::HasQueryGroup::group_storage(&db);
+    salsa::plumbing::QueryStorageMassOps::purge(&*group_storage.first, db.salsa_runtime());
+
+    for x in 0..5 {
+        db.second(x);
+    }
+    assert_eq!(db.values("second"), 5);
+    assert_eq!(db.evictions.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn swept_values_leave_the_budget() {
+    let db = Database::new(8_000);
+    for x in 0..5 {
+        db.first(x);
+    }
+    db.sweep_all(SweepStrategy::default().discard_values().sweep_all_revisions());
+    assert_eq!(db.values("first"), 0);
+
+    for x in 0..5 {
+        db.second(x);
+    }
+    assert_eq!(db.values("second"), 5);
+    assert_eq!(db.evictions.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn values_that_cannot_be_evicted_stay_in_the_budget() {
+    let db = Database::new(8_000);
+    for x in 0..5 {
+        db.volatile(x);
+    }
+    for x in 0..20 {
+        db.first(x);
+    }
+
+    // Values that read untracked inputs are never evicted, but they
+    // still take up most of the budget.
+    assert_eq!(db.values("volatile"), 5);
+    let values = db.values("volatile") + db.values("first");
+    assert!(values <= 7, "{} values kept", values);
+}
+
+#[test]
+fn budget_evicts_untracked_memos_when_enabled() {
+    let mut db = Database::new(8_000);
+    VolatileQuery
+        .in_db_mut(&mut db)
+        .set_lru_evict_untracked(true);
+    for x in 0..5 {
+        db.volatile(x);
+    }
+
+    // The memos can only be discarded once their revision is over.
+    salsa::Database::synthetic_write(&mut db, salsa::Durability::LOW);
+    for x in 0..20 {
+        db.first(x);
+    }
+    assert!(db.values("volatile") < 5);
+    let values = db.values("volatile") + db.values("first");
+    assert!(values <= 7, "{} values kept", values);
+}
+
+#[test]
+fn purge_forgets_deferred_evictions() {
+    let mut db = Database::new(0);
+    VolatileQuery.in_db_mut(&mut db).set_lru_capacity(3);
+    VolatileQuery
+        .in_db_mut(&mut db)
+        .set_lru_evict_untracked(true);
+    for x in 0..10 {
+        db.volatile(x);
+    }
+    let group_storage = salsa::plumbing::HasQueryGroup::group_storage(&db);
+    salsa::plumbing::QueryStorageMassOps::purge(&*group_storage.volatile, db.salsa_runtime());
+
+    // The purged memos are not evicted once their revision is over.
+    salsa::Database::synthetic_write(&mut db, salsa::Durability::LOW);
+    db.volatile(0);
+    assert_eq!(db.evictions.load(Ordering::SeqCst), 0);
+}
@@ -1,12 +1,12 @@
-use crate::setup::{CancelationFlag, Canceled, Knobs, ParDatabase, ParDatabaseImpl, WithValue};
-use salsa::ParallelDatabase;
+use crate::setup::{CancelationFlag, InputQuery, Knobs, ParDatabase, ParDatabaseImpl, WithValue};
+use salsa::{Cancelled, Database as _, ParallelDatabase};
 
 macro_rules! assert_canceled {
     ($flag:expr, $thread:expr) => {
         if $flag == CancelationFlag::Panic {
             match $thread.join() {
                 Ok(value) => panic!("expected cancelation, got {:?}", value),
-                Err(payload) => match payload.downcast::<Canceled>() {
+                Err(payload) => match payload.downcast::<Cancelled>() {
                     Ok(_) => {}
                     Err(payload) => ::std::panic::resume_unwind(payload),
                 },
@@ -40,6 +40,10 @@ fn in_par_get_set_cancellation_immediate() {
         let thread1 = std::thread::spawn({
             let db = db.snapshot();
             move || {
+                // The write below is started as soon as we enter `sum`,
+                // and would cancel reads of the inputs after that.
+                db.knobs().sum_read_inputs_on_entry.set(true);
+
                 // This will not return until it sees cancellation is
                 // signaled.
                 db.knobs().sum_signal_on_entry.with_value(1, || {
@@ -83,6 +87,10 @@ fn in_par_get_set_cancellation_transitive() {
         let thread1 = std::thread::spawn({
             let db = db.snapshot();
             move || {
+                // The write below is started as soon as we enter `sum`,
+                // and would cancel reads of the inputs after that.
+                db.knobs().sum_read_inputs_on_entry.set(true);
+
                 // This will not return until it sees cancellation is
                 // signaled.
                 db.knobs().sum_signal_on_entry.with_value(1, || {
@@ -121,6 +129,10 @@ fn no_back_dating_in_cancellation() {
         let thread1 = std::thread::spawn({
             let db = db.snapshot();
             move || {
+                // The write below is started as soon as we enter `sum`,
+                // and would cancel reads of the inputs after that.
+                db.knobs().sum_read_inputs_on_entry.set(true);
+
                 // Here we compute a long-chain of queries,
                 // but the last one gets cancelled.
                 db.knobs().sum_signal_on_entry.with_value(1, || {
@@ -162,6 +174,10 @@ fn transitive_cancellation() {
     let thread1 = std::thread::spawn({
         let db = db.snapshot();
         move || {
+            // The write below is started as soon as we enter `sum`,
+            // and would cancel reads of the inputs after that.
+            db.knobs().sum_read_inputs_on_entry.set(true);
+
             // Here we compute a long-chain of queries,
             // but the last one gets cancelled.
             db.knobs().sum_signal_on_entry.with_value(1, || {
@@ -185,3 +201,149 @@ fn transitive_cancellation() {
 
     assert_eq!(thread1.join().unwrap(), 22);
 }
+
+/// Check that setting an input to the value it already has with
+/// `set_if_changed` neither blocks on a snapshot nor cancels it.
+#[test]
+fn set_if_changed_without_change() {
+    let mut db = ParDatabaseImpl::default();
+    db.set_input('a', 100);
+
+    let snapshot = db.snapshot();
+    assert!(!InputQuery.in_db_mut(&mut db).set_if_changed('a', 100));
+    assert!(!snapshot.is_current_revision_canceled());
+    assert_eq!(snapshot.input('a'), 100);
+}
+
+/// Check that a thread that is blocked on a query which unwinds with
+/// `Cancelled` is cancelled as well, rather than seeing a panic.
+#[test]
+fn cancellation_propagates_to_blocked_thread() {
+    let mut db = ParDatabaseImpl::default();
+    db.set_input('a', 100);
+
+    let thread1 = std::thread::spawn({
+        let db = db.snapshot();
+        move || {
+            db.knobs().sum_signal_on_entry.with_value(1, || {
+                db.knobs()
+                    .sum_wait_for_cancellation
+                    .with_value(CancelationFlag::Panic, || {
+                        salsa::catch_cancellation(|| db.sum("a"))
+                    })
+            })
+        }
+    });
+
+    let thread2 = std::thread::spawn({
+        let db = db.snapshot();
+        move || {
+            db.wait_for(1);
+            db.knobs().signal_on_will_block.set(2);
+            salsa::catch_cancellation(|| db.sum("a"))
+        }
+    });
+
+    // Wait until `thread2` is blocked on `thread1`.
+    db.wait_for(2);
+    db.set_input('b', 10);
+
+    assert_eq!(thread1.join().unwrap(), Err(Cancelled));
+    assert_eq!(thread2.join().unwrap(), Err(Cancelled));
+    assert_eq!(db.sum("ab"), 110);
+}
+
+/// Check that cancelling the token of a snapshot cancels its queries
+/// only: a thread blocked on one of them executes it itself, and no new
+/// revision is started.
+#[test]
+fn cancellation_token_cancels_one_snapshot() {
+    let mut db = ParDatabaseImpl::default();
+    db.set_input('a', 100);
+
+    let snapshot1 = db.snapshot();
+    let token = snapshot1.salsa_runtime().cancellation_token();
+    let thread1 = std::thread::spawn(move || {
+        let db = snapshot1;
+        db.knobs().sum_signal_on_entry.with_value(1, || {
+            db.knobs()
+                .sum_wait_for_cancellation
+                .with_value(CancelationFlag::Panic, || {
+                    salsa::catch_cancellation(|| db.sum("a"))
+                })
+        })
+    });
+
+    let thread2 = std::thread::spawn({
+        let db = db.snapshot();
+        move || {
+            db.wait_for(1);
+            db.knobs().signal_on_will_block.set(2);
+            let sum = salsa::catch_cancellation(|| db.sum("a"));
+            assert!(!db.salsa_runtime().is_cancelled());
+            sum
+        }
+    });
+
+    // Wait until `thread2` is blocked on `thread1`.
+    db.wait_for(2);
+    token.cancel();
+
+    assert_eq!(thread1.join().unwrap(), Err(Cancelled));
+    assert_eq!(thread2.join().unwrap(), Ok(100));
+    assert!(!db.salsa_runtime().is_cancelled());
+    assert_eq!(db.sum("a"), 100);
+}
+
+/// Check that a query that panics after the token of its snapshot was
+/// cancelled still propagates the panic to the threads blocked on it,
+/// rather than letting them execute it themselves.
+#[test]
+fn cancellation_token_does_not_hide_panics() {
+    let mut db = ParDatabaseImpl::default();
+    db.set_input('a', 100);
+
+    let snapshot1 = db.snapshot();
+    let token = snapshot1.salsa_runtime().cancellation_token();
+    let thread1 = std::thread::spawn(move || {
+        let db = snapshot1;
+        db.knobs().sum_signal_on_entry.with_value(1, || {
+            db.knobs().sum_wait_for_on_entry.with_value(3, || {
+                db.knobs().sum_should_panic.with_value(true, || db.sum("a"))
+            })
+        })
+    });
+
+    let thread2 = std::thread::spawn({
+        let db = db.snapshot();
+        move || {
+            db.wait_for(1);
+            db.knobs().signal_on_will_block.set(2);
+            db.sum("a")
+        }
+    });
+
+    // Wait until `thread2` is blocked on `thread1`, then let `thread1`
+    // panic.
+    db.wait_for(2);
+    token.cancel();
+    db.signal(3);
+
+    assert!(thread1.join().is_err());
+    assert!(thread2.join().is_err());
+}
+
+/// Check that cancelling the token of the database itself only cancels
+/// its queries until the next revision.
+#[test]
+fn cancellation_token_of_database_lasts_one_revision() {
+    let mut db = ParDatabaseImpl::default();
+    db.set_input('a', 100);
+
+    db.salsa_runtime().cancellation_token().cancel();
+    assert_eq!(salsa::catch_cancellation(|| db.sum("a")), Err(Cancelled));
+
+    db.set_input('b', 10);
+    assert!(!db.salsa_runtime().is_cancelled());
+    assert_eq!(db.sum("ab"), 110);
+}
@@ -1,6 +1,6 @@
-use crate::setup::{ParDatabase, ParDatabaseImpl};
+use crate::setup::{InputQuery, ParDatabase, ParDatabaseImpl};
 use crate::signal::Signal;
-use salsa::{Database, ParallelDatabase};
+use salsa::{Cancelled, Database, ParallelDatabase};
 use std::sync::Arc;
 
 /// Add test where a call to `sum` is cancelled by a simultaneous
@@ -8,6 +8,40 @@ use std::sync::Arc;
 /// though none of the inputs have changed.
 #[test]
 fn in_par_get_set_cancellation() {
+    // Fetching the input would unwind with `Cancelled` while the write
+    // is pending, so look at the stored value instead.
+    let (a, b, c) = read_while_set_is_pending(|db| InputQuery.in_db(db).peek(&'a'));
+
+    // Since we have not yet released revision lock, we should see 1
+    // here. Since this is a snapshotted database, we are in a
+    // consistent revision, so both reads must yield the same value.
+    assert_eq!(a, Some(1));
+    assert_eq!(b, Some(1));
+
+    assert_eq!(c, 2);
+}
+
+/// Add test where a snapshot fetches an input while a write is pending.
+/// Check that the fetch unwinds with `Cancelled` instead of returning a
+/// value of the old revision.
+#[test]
+fn in_par_get_set_cancellation_of_reads() {
+    let (a, b, c) = read_while_set_is_pending(|db| salsa::catch_cancellation(|| db.input('a')));
+
+    // Each read is cancelled, as long as we hold the revision lock.
+    assert_eq!(a, Err(Cancelled));
+    assert_eq!(b, Err(Cancelled));
+
+    assert_eq!(c, 2);
+}
+
+/// Sets input `a` to 1, then calls `read` twice from a snapshot while
+/// another thread is waiting to set it to 2. Returns the results of
+/// both reads, and the value that the other thread sees afterwards.
+fn read_while_set_is_pending<T>(read: fn(&ParDatabaseImpl) -> T) -> (T, T, usize)
+where
+    T: Send + 'static,
+{
     let mut db = ParDatabaseImpl::default();
 
     db.set_input('a', 1);
@@ -30,13 +64,8 @@ fn in_par_get_set_cancellation() {
                 std::thread::yield_now();
             }
 
-            // Since we have not yet released revision lock, we should
-            // see 1 here.
-            let v = db.input('a');
-
-            // Since this is a snapshotted database, we are in a consistent
-            // revision, so this must yield the same value.
-            let w = db.input('a');
+            let v = read(&db);
+            let w = read(&db);
 
             (v, w)
         }
@@ -57,9 +86,6 @@ fn in_par_get_set_cancellation() {
     });
 
     let (a, b) = thread1.join().unwrap();
-    assert_eq!(a, 1);
-    assert_eq!(b, 1);
-
     let c = thread2.join().unwrap();
-    assert_eq!(c, 2);
+    (a, b, c)
 }
@@ -25,16 +25,6 @@ pub(crate) trait ParDatabase: Knobs {
     fn sum3_drop_sum(&self, key: &'static str) -> usize;
 }
 
-#[derive(PartialEq, Eq)]
-pub(crate) struct Canceled;
-
-impl Canceled {
-    fn throw() -> ! {
-        // Don't print backtrace
-        std::panic::resume_unwind(Box::new(Canceled));
-    }
-}
-
 /// Various "knobs" and utilities used by tests to force
 /// a certain behavior.
 pub(crate) trait Knobs {
@@ -92,6 +82,11 @@ pub(crate) struct KnobsStruct {
     /// Invocations of `sum` will wait for this stage on entry.
     pub(crate) sum_wait_for_on_entry: Cell<usize>,
 
+    /// If true, invocations of `sum` will read their inputs before they
+    /// signal `sum_signal_on_entry`, so that a write started by that
+    /// signal does not cancel the reads.
+    pub(crate) sum_read_inputs_on_entry: Cell<bool>,
+
     /// If true, invocations of `sum` will panic before they exit.
     pub(crate) sum_should_panic: Cell<bool>,
 
@@ -110,8 +105,13 @@ pub(crate) struct KnobsStruct {
 }
 
 fn sum(db: &dyn ParDatabase, key: &'static str) -> usize {
+    let read_inputs = || key.chars().map(|ch| db.input(ch)).sum::<usize>();
     let mut sum = 0;
 
+    if db.knobs().sum_read_inputs_on_entry.get() {
+        sum = read_inputs();
+    }
+
     db.signal(db.knobs().sum_signal_on_entry.get());
 
     db.wait_for(db.knobs().sum_wait_for_on_entry.get());
@@ -120,20 +120,20 @@ fn sum(db: &dyn ParDatabase, key: &'static str) -> usize {
         panic!("query set to panic before exit")
     }
 
-    for ch in key.chars() {
-        sum += db.input(ch);
+    if !db.knobs().sum_read_inputs_on_entry.get() {
+        sum = read_inputs();
     }
 
     match db.knobs().sum_wait_for_cancellation.get() {
         CancelationFlag::Down => (),
         flag => {
             log::debug!("waiting for cancellation");
-            while !db.salsa_runtime().is_current_revision_canceled() {
+            while !db.salsa_runtime().is_cancelled() {
                 std::thread::yield_now();
             }
             log::debug!("observed cancelation");
             if flag == CancelationFlag::Panic {
-                Canceled::throw();
+                db.salsa_runtime().unwind_if_cancelled();
             }
         }
     }
@@ -194,10 +194,6 @@ impl Database for ParDatabaseImpl {
             _ => {}
         }
     }
-
-    fn on_propagated_panic(&self) -> ! {
-        Canceled::throw()
-    }
 }
 
 impl ParallelDatabase for ParDatabaseImpl {
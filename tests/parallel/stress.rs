@@ -205,7 +205,13 @@ fn stress_test() {
                 check_cancellation,
             } => all_threads.push(std::thread::spawn({
                 let db = db.snapshot();
-                move || db_reader_thread(&db, ops, check_cancellation)
+                move || {
+                    // Readers that do not check for cancellation
+                    // themselves are cancelled when they fetch a query.
+                    let _ = salsa::catch_cancellation(|| {
+                        db_reader_thread(&db, ops, check_cancellation)
+                    });
+                }
             })),
         }
     }
@@ -0,0 +1,202 @@
+//! Test that query tables can be saved and restored into a new database.
+
+use salsa::DatabaseKeyIndex;
+use std::{cell::RefCell, rc::Rc};
+
+#[salsa::query_group(QueryGroupStorage)]
+trait QueryGroup: salsa::Database {
+    #[salsa::input]
+    fn input(&self, x: u32) -> u32;
+
+    fn double(&self, x: u32) -> u32;
+    fn sum(&self, x: u32, y: u32) -> u32;
+    fn not_persisted(&self, x: u32) -> u32;
+
+    #[salsa::interned]
+    fn intern(&self, x: u32) -> salsa::InternId;
+    fn with_interned(&self, x: u32) -> u32;
+}
+
+fn double(db: &dyn QueryGroup, x: u32) -> u32 {
+    db.input(x) * 2
+}
+
+fn sum(db: &dyn QueryGroup, x: u32, y: u32) -> u32 {
+    db.double(x) + db.double(y)
+}
+
+fn not_persisted(db: &dyn QueryGroup, x: u32) -> u32 {
+    db.double(x) + 1
+}
+
+fn with_interned(db: &dyn QueryGroup, x: u32) -> u32 {
+    db.intern(x).as_u32() * 100 + db.input(x)
+}
+
+#[salsa::database(QueryGroupStorage)]
+#[derive(Default)]
+struct Database {
+    storage: salsa::Storage<Self>,
+    executed: Rc<RefCell<Vec<DatabaseKeyIndex>>>,
+}
+
+impl salsa::Database for Database {
+    fn salsa_event(&self, event: salsa::Event) {
+        if let salsa::EventKind::WillExecute { database_key } = event.kind {
+            self.executed.borrow_mut().push(database_key);
+        }
+    }
+}
+
+impl Database {
+    fn new() -> Self {
+        let mut db = Database::default();
+        InputQuery.in_db_mut(&mut db).enable_persistence();
+        DoubleQuery.in_db_mut(&mut db).enable_persistence();
+        SumQuery.in_db_mut(&mut db).enable_persistence();
+        WithInternedQuery.in_db_mut(&mut db).enable_persistence();
+        db
+    }
+
+    fn take_executed_keys(&self) -> Vec<DatabaseKeyIndex> {
+        std::mem::take(&mut *self.executed.borrow_mut())
+    }
+
+    fn take_executed(&self) -> Vec<String> {
+        self.take_executed_keys()
+            .into_iter()
+            .map(|key| format!("{:?}", key.debug(self)))
+            .collect()
+    }
+}
+
+fn saved_database() -> Vec<u8> {
+    let mut db = Database::new();
+    db.set_input(1, 10);
+    db.set_input(2, 20);
+    assert_eq!(db.sum(1, 2), 60);
+    assert_eq!(db.not_persisted(1), 21);
+
+    let mut bytes = Vec::new();
+    salsa::persist::save(&db, &mut bytes).unwrap();
+    bytes
+}
+
+#[test]
+fn restored_memos_are_reused() {
+    let bytes = saved_database();
+
+    let mut db = Database::new();
+    salsa::persist::load(&mut db, &bytes[..]).unwrap();
+
+    assert_eq!(db.input(1), 10);
+    assert_eq!(db.sum(1, 2), 60);
+    assert_eq!(db.take_executed(), Vec::<String>::new());
+
+    // Queries that did not opt in start out empty.
+    assert_eq!(db.not_persisted(1), 21);
+    assert_eq!(db.take_executed(), vec!["not_persisted(1)"]);
+}
+
+#[test]
+fn restored_memos_track_their_inputs() {
+    let bytes = saved_database();
+
+    let mut db = Database::new();
+    salsa::persist::load(&mut db, &bytes[..]).unwrap();
+
+    db.set_input(1, 15);
+    assert_eq!(db.sum(1, 2), 70);
+    assert_eq!(db.take_executed(), vec!["double(1)", "sum((1, 2))"]);
+}
+
+#[test]
+fn load_requires_fresh_database() {
+    let bytes = saved_database();
+
+    let mut db = Database::new();
+    db.set_input(1, 10);
+    assert!(salsa::persist::load(&mut db, &bytes[..]).is_err());
+}
+
+#[test]
+fn load_rejects_corrupt_data() {
+    let bytes = saved_database();
+
+    let mut db = Database::new();
+    assert!(salsa::persist::load(&mut db, &bytes[..bytes.len() - 1]).is_err());
+
+    let mut db = Database::new();
+    assert!(salsa::persist::load(&mut db, &b"not a database"[..]).is_err());
+}
+
+#[test]
+fn load_rejects_other_compilers() {
+    let mut bytes = saved_database();
+
+    // The header is the magic number, the format version and then the
+    // length-prefixed version of the compiler that saved the database.
+    let compiler = 8 + 4 + 8;
+    assert_eq!(&bytes[compiler..compiler + 5], b"rustc");
+    bytes[compiler] = b'R';
+
+    let mut db = Database::new();
+    assert!(salsa::persist::load(&mut db, &bytes[..]).is_err());
+}
+
+#[test]
+fn stable_keys_ignore_insertion_order() {
+    let mut db1 = Database::new();
+    db1.set_input(1, 10);
+    db1.set_input(2, 20);
+    db1.double(1);
+    db1.double(2);
+    let keys1 = db1.take_executed_keys();
+
+    let mut db2 = Database::new();
+    db2.set_input(1, 10);
+    db2.set_input(2, 20);
+    db2.double(2);
+    db2.double(1);
+    let keys2 = db2.take_executed_keys();
+
+    let stable1: Vec<_> = keys1.iter().map(|k| k.stable_key(&db1).unwrap()).collect();
+    let stable2: Vec<_> = keys2.iter().map(|k| k.stable_key(&db2).unwrap()).collect();
+    assert_eq!(stable1[0].query_name(), "double");
+    assert_eq!(stable1[0], stable2[1]);
+    assert_eq!(stable1[1], stable2[0]);
+
+    assert_ne!(keys1[0], keys2[1]);
+    assert_eq!(stable1[0].resolve(&db2), Some(keys2[1]));
+    assert_eq!(stable1[1].resolve(&db2), Some(keys2[0]));
+}
+
+#[test]
+fn stable_keys_require_persistence() {
+    let mut db = Database::new();
+    db.set_input(1, 10);
+    db.not_persisted(1);
+    let keys = db.take_executed_keys();
+    assert_eq!(keys.len(), 2);
+    assert!(keys[0].stable_key(&db).is_none());
+    assert!(keys[1].stable_key(&db).is_some());
+}
+
+#[test]
+fn memos_reading_interned_ids_are_executed_again() {
+    let mut db = Database::new();
+    InputQuery
+        .in_db_mut(&mut db)
+        .set_with_durability(1, 10, salsa::Durability::HIGH);
+    db.intern(7);
+    assert_eq!(db.with_interned(1), 110);
+    let mut bytes = Vec::new();
+    salsa::persist::save(&db, &mut bytes).unwrap();
+
+    // The interned ids of the old database mean nothing in the new one,
+    // even though the memo only read durable values.
+    let mut db = Database::new();
+    salsa::persist::load(&mut db, &bytes[..]).unwrap();
+    assert_eq!(db.with_interned(1), 10);
+    assert_eq!(db.take_executed(), vec!["with_interned(1)"]);
+}
@@ -0,0 +1,97 @@
+//! Test the per-query counters collected with the `profiling` feature.
+#![cfg(feature = "profiling")]
+
+use salsa::profile::{self, SortBy};
+use std::time::Duration;
+
+#[salsa::query_group(QueryGroupStorage)]
+trait QueryGroup: salsa::Database {
+    #[salsa::input]
+    fn input(&self, x: u32) -> u32;
+
+    fn sleep(&self, x: u32) -> u32;
+    fn sum(&self) -> u32;
+}
+
+fn sleep(db: &dyn QueryGroup, x: u32) -> u32 {
+    std::thread::sleep(Duration::from_millis(20));
+    db.input(x)
+}
+
+fn sum(db: &dyn QueryGroup) -> u32 {
+    db.sleep(1) + db.sleep(2) + db.sleep(1)
+}
+
+#[salsa::database(QueryGroupStorage)]
+#[derive(Default)]
+struct Database {
+    storage: salsa::Storage<Self>,
+}
+
+impl salsa::Database for Database {}
+
+#[test]
+fn counts_fetches_hits_validations_and_executions() {
+    let mut db = Database::default();
+    db.set_input(1, 1);
+    db.set_input(2, 2);
+    assert_eq!(db.sum(), 4);
+    assert_eq!(db.sum(), 4);
+
+    let report = profile::report(&db);
+    let sleep = report.query("sleep").unwrap();
+    assert_eq!(
+        (
+            sleep.fetches,
+            sleep.hits,
+            sleep.validations,
+            sleep.executions
+        ),
+        (3, 1, 0, 2)
+    );
+    let sum = report.query("sum").unwrap();
+    assert_eq!(
+        (sum.fetches, sum.hits, sum.validations, sum.executions),
+        (2, 1, 0, 1)
+    );
+
+    // `sleep(1)` is re-executed but `sleep(2)` is validated, and since
+    // the result is the same `sum` is validated as well.
+    db.set_input(1, 1);
+    assert_eq!(db.sum(), 4);
+    let report = profile::report(&db);
+    let sleep = report.query("sleep").unwrap();
+    assert_eq!((sleep.validations, sleep.executions), (1, 3));
+    let sum = report.query("sum").unwrap();
+    assert_eq!((sum.validations, sum.executions), (1, 1));
+
+    profile::reset(&db);
+    let report = profile::report(&db);
+    assert_eq!(report.query("sum").unwrap().fetches, 0);
+}
+
+#[test]
+fn self_time_excludes_children() {
+    let mut db = Database::default();
+    db.set_input(1, 1);
+    db.set_input(2, 2);
+    db.sum();
+
+    let mut report = profile::report(&db);
+    let sum = report.query("sum").unwrap();
+    assert!(sum.total_time >= Duration::from_millis(40));
+    assert!(sum.self_time < Duration::from_millis(20));
+    let sleep = report.query("sleep").unwrap();
+    assert!(sleep.total_time >= Duration::from_millis(40));
+    assert_eq!(sleep.self_time, sleep.total_time);
+
+    report.sort_by(SortBy::SelfTime);
+    let names: Vec<_> = report.queries().iter().map(|q| q.query_name).collect();
+    assert_eq!(names, vec!["sleep", "sum"]);
+    assert!(report
+        .to_string()
+        .lines()
+        .nth(1)
+        .unwrap()
+        .starts_with("sleep"));
+}
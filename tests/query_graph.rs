@@ -0,0 +1,106 @@
+//! Test exporting the dependency graph with `debug::write_query_graph`.
+
+use salsa::debug::{write_query_graph, GraphFormat};
+
+#[salsa::query_group(QueryGroupStorage)]
+trait QueryGroup: salsa::Database {
+    #[salsa::input]
+    fn input(&self, x: u32) -> String;
+
+    fn length(&self, x: u32) -> usize;
+    fn total(&self) -> usize;
+    fn volatile(&self) -> usize;
+}
+
+fn length(db: &dyn QueryGroup, x: u32) -> usize {
+    db.input(x).len()
+}
+
+fn total(db: &dyn QueryGroup) -> usize {
+    db.length(1) + db.length(2)
+}
+
+fn volatile(db: &dyn QueryGroup) -> usize {
+    db.salsa_runtime().report_untracked_read();
+    db.length(3)
+}
+
+#[salsa::database(QueryGroupStorage)]
+#[derive(Default)]
+struct Database {
+    storage: salsa::Storage<Self>,
+}
+
+impl salsa::Database for Database {}
+
+fn database() -> Database {
+    let mut db = Database::default();
+    db.set_input(1, "a".to_string());
+    db.set_input(2, "b\"c".to_string());
+    db.set_input(3, "d".to_string());
+    assert_eq!(db.total(), 4);
+    assert_eq!(db.volatile(), 1);
+    db
+}
+
+fn write(db: &Database, root: Option<salsa::DatabaseKeyIndex>, format: GraphFormat) -> String {
+    let mut out = Vec::new();
+    write_query_graph(db, root, format, &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+fn id(key: salsa::DatabaseKeyIndex) -> String {
+    format!(
+        "{}:{}:{}",
+        key.group_index(),
+        key.query_index(),
+        key.key_index()
+    )
+}
+
+#[test]
+fn dot_contains_all_memos() {
+    let db = database();
+    let dot = write(&db, None, GraphFormat::Dot);
+    assert!(dot.starts_with("digraph salsa {\n"), "{}", dot);
+    assert!(dot.ends_with("}\n"), "{}", dot);
+
+    let total = TotalQuery.in_db(&db).database_key_index(&()).unwrap();
+    let length1 = LengthQuery.in_db(&db).database_key_index(&1).unwrap();
+    let input1 = InputQuery.in_db(&db).database_key_index(&1).unwrap();
+    assert!(dot.contains(&format!("\"{}\" -> \"{}\";", id(total), id(length1))));
+    assert!(dot.contains(&format!("\"{}\" -> \"{}\";", id(length1), id(input1))));
+    assert!(dot.contains("label=\"total(())\\nLOW changed_at=R3 verified_at=R4\""));
+    assert!(dot.contains("label=\"input(1)\\nLOW changed_at=R2\""));
+    assert!(
+        dot.contains("volatile(())\\nLOW changed_at=R4 verified_at=R4\\nuntracked\", style=dashed")
+    );
+}
+
+#[test]
+fn root_restricts_to_transitive_inputs() {
+    let db = database();
+    let total = TotalQuery.in_db(&db).database_key_index(&()).unwrap();
+    let json = write(&db, Some(total), GraphFormat::Json);
+
+    assert!(json.contains("\"name\": \"total(())\""), "{}", json);
+    assert!(json.contains("\"name\": \"length(2)\""), "{}", json);
+    assert!(json.contains("\"name\": \"input(2)\""), "{}", json);
+    assert!(!json.contains("volatile"), "{}", json);
+    assert!(!json.contains("input(3)"), "{}", json);
+    assert_eq!(json.matches("\"from\"").count(), 4, "{}", json);
+}
+
+#[test]
+fn json_is_well_formed() {
+    let db = database();
+    let json = write(&db, None, GraphFormat::Json);
+    let input1 = InputQuery.in_db(&db).database_key_index(&1).unwrap();
+    assert!(json.contains(&format!(
+        "{{\"id\": \"{}\", \"name\": \"input(1)\", \"durability\": \"LOW\", \
+         \"changed_at\": 2, \"verified_at\": null, \"has_value\": true, \"untracked\": false}}",
+        id(input1)
+    )));
+    assert!(json.contains("\"untracked\": true"), "{}", json);
+    assert!(json.trim_end().ends_with("]\n}"), "{}", json);
+}
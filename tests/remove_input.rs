@@ -0,0 +1,105 @@
+//! Test removing the keys of input queries with `QueryTableMut::remove`.
+
+use std::cell::Cell;
+
+#[salsa::query_group(QueryGroupStorage)]
+trait QueryGroup: salsa::Database + AsRef<Cell<usize>> {
+    #[salsa::input]
+    fn text(&self, x: u32) -> String;
+
+    fn length(&self, x: u32) -> usize;
+    fn length_if_set(&self, x: u32) -> Option<usize>;
+}
+
+fn length(db: &dyn QueryGroup, x: u32) -> usize {
+    let executions = db.as_ref();
+    executions.set(executions.get() + 1);
+    db.text(x).len()
+}
+
+fn length_if_set(db: &dyn QueryGroup, x: u32) -> Option<usize> {
+    let executions = db.as_ref();
+    executions.set(executions.get() + 1);
+    Some(TextQuery.in_db(db).get_opt(x)?.len())
+}
+
+#[salsa::database(QueryGroupStorage)]
+#[derive(Default)]
+struct Database {
+    storage: salsa::Storage<Self>,
+    executions: Cell<usize>,
+}
+
+impl salsa::Database for Database {}
+
+impl AsRef<Cell<usize>> for Database {
+    fn as_ref(&self) -> &Cell<usize> {
+        &self.executions
+    }
+}
+
+impl Database {
+    fn new() -> Self {
+        let mut db = Database::default();
+        db.set_text(1, "a".to_string());
+        db.set_text(2, "bb".to_string());
+        assert_eq!(db.length(1), 1);
+        assert_eq!(db.length(2), 2);
+        db.executions.set(0);
+        db
+    }
+}
+
+#[test]
+fn remove_returns_the_old_value() {
+    let mut db = Database::new();
+    assert_eq!(
+        TextQuery.in_db_mut(&mut db).remove(&1),
+        Some("a".to_string())
+    );
+    assert_eq!(TextQuery.in_db(&db).peek(&1), None);
+    assert_eq!(TextQuery.in_db_mut(&mut db).remove(&1), None);
+}
+
+#[test]
+fn other_keys_are_unaffected() {
+    let mut db = Database::new();
+    TextQuery.in_db_mut(&mut db).remove(&1);
+    assert_eq!(db.length(2), 2);
+    assert_eq!(db.executions.get(), 0);
+}
+
+#[test]
+fn removed_keys_can_be_set_again() {
+    let mut db = Database::new();
+    TextQuery.in_db_mut(&mut db).remove(&1);
+    db.set_text(1, "ccc".to_string());
+    assert_eq!(db.length(1), 3);
+    assert_eq!(db.executions.get(), 1);
+}
+
+#[test]
+#[should_panic(expected = "no value set")]
+fn readers_of_removed_keys_are_executed_again() {
+    let mut db = Database::new();
+    TextQuery.in_db_mut(&mut db).remove(&1);
+    db.length(1);
+}
+
+#[test]
+fn optional_readers_see_removed_keys() {
+    let mut db = Database::new();
+    assert_eq!(db.length_if_set(1), Some(1));
+    TextQuery.in_db_mut(&mut db).remove(&1);
+    assert_eq!(db.length_if_set(1), None);
+    assert_eq!(db.executions.get(), 2);
+
+    // Other inputs don't affect the missing key.
+    db.set_text(2, "ccc".to_string());
+    assert_eq!(db.length_if_set(1), None);
+    assert_eq!(db.executions.get(), 2);
+
+    db.set_text(1, "dddd".to_string());
+    assert_eq!(db.length_if_set(1), Some(4));
+    assert_eq!(db.executions.get(), 3);
+}
@@ -0,0 +1,152 @@
+//! Test recording a trace of query executions with
+//! `Runtime::start_trace`.
+
+use salsa::{Database as _, ParallelDatabase, Snapshot};
+use std::panic::{self, AssertUnwindSafe};
+
+#[salsa::query_group(QueryGroupStorage)]
+trait QueryGroup: salsa::Database {
+    #[salsa::input]
+    fn input(&self, x: u32) -> u32;
+
+    fn double(&self, x: u32) -> u32;
+    fn sum(&self) -> u32;
+}
+
+fn double(db: &dyn QueryGroup, x: u32) -> u32 {
+    let input = db.input(x);
+    assert_ne!(input, 0, "input is zero");
+    input * 2
+}
+
+fn sum(db: &dyn QueryGroup) -> u32 {
+    db.double(1) + db.double(2)
+}
+
+#[salsa::database(QueryGroupStorage)]
+#[derive(Default)]
+struct Database {
+    storage: salsa::Storage<Self>,
+}
+
+impl salsa::Database for Database {}
+
+impl ParallelDatabase for Database {
+    fn snapshot(&self) -> Snapshot<Self> {
+        Snapshot::new(Database {
+            storage: self.storage.snapshot(),
+        })
+    }
+
+    fn fork(&self, forker: salsa::ForkState) -> Snapshot<Self> {
+        Snapshot::new(Database {
+            storage: self.storage.fork(forker),
+        })
+    }
+}
+
+fn chrome_json(trace: &salsa::debug::QueryTrace) -> Vec<String> {
+    let mut out = Vec::new();
+    trace.write_chrome_json(&mut out).unwrap();
+    String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .filter(|line| line.contains("\"ph\""))
+        .map(|line| {
+            // Drop the timestamps, which vary between runs.
+            let end = line.find(", \"ts\"").unwrap();
+            line[..end].trim().to_string()
+        })
+        .collect()
+}
+
+#[test]
+fn executions_and_validations_nest() {
+    let mut db = Database::default();
+    db.set_input(1, 1);
+    db.set_input(2, 2);
+
+    db.salsa_runtime().start_trace();
+    db.sum();
+    db.set_input(1, 10);
+    db.sum();
+    let trace = db.salsa_runtime().finish_trace();
+
+    assert_eq!(trace.span_count(), 8);
+    assert_eq!(
+        chrome_json(&trace),
+        vec![
+            r#"{"ph": "B", "cat": "execute", "name": "sum(())", "pid": 1, "tid": 0"#,
+            r#"{"ph": "B", "cat": "execute", "name": "double(1)", "pid": 1, "tid": 0"#,
+            r#"{"ph": "E", "cat": "execute", "pid": 1, "tid": 0"#,
+            r#"{"ph": "B", "cat": "execute", "name": "double(2)", "pid": 1, "tid": 0"#,
+            r#"{"ph": "E", "cat": "execute", "pid": 1, "tid": 0"#,
+            r#"{"ph": "E", "cat": "execute", "pid": 1, "tid": 0"#,
+            r#"{"ph": "B", "cat": "validate", "name": "sum(())", "pid": 1, "tid": 0"#,
+            r#"{"ph": "B", "cat": "validate", "name": "double(1)", "pid": 1, "tid": 0"#,
+            r#"{"ph": "E", "cat": "validate", "pid": 1, "tid": 0"#,
+            r#"{"ph": "B", "cat": "execute", "name": "double(1)", "pid": 1, "tid": 0"#,
+            r#"{"ph": "E", "cat": "execute", "pid": 1, "tid": 0"#,
+            r#"{"ph": "E", "cat": "validate", "pid": 1, "tid": 0"#,
+            r#"{"ph": "B", "cat": "execute", "name": "sum(())", "pid": 1, "tid": 0"#,
+            r#"{"ph": "B", "cat": "validate", "name": "double(2)", "pid": 1, "tid": 0"#,
+            r#"{"ph": "E", "cat": "validate", "pid": 1, "tid": 0"#,
+            r#"{"ph": "E", "cat": "execute", "pid": 1, "tid": 0"#,
+        ]
+    );
+
+    // Nothing is recorded once the trace is finished.
+    db.set_input(2, 20);
+    db.sum();
+    assert_eq!(db.salsa_runtime().finish_trace().span_count(), 0);
+}
+
+#[test]
+fn spans_are_closed_when_validation_unwinds() {
+    let mut db = Database::default();
+    db.set_input(1, 1);
+    db.set_input(2, 2);
+    db.sum();
+
+    db.set_input(1, 0);
+    db.salsa_runtime().start_trace();
+    assert!(panic::catch_unwind(AssertUnwindSafe(|| db.sum())).is_err());
+    let trace = db.salsa_runtime().finish_trace();
+
+    assert_eq!(
+        chrome_json(&trace),
+        vec![
+            r#"{"ph": "B", "cat": "validate", "name": "sum(())", "pid": 1, "tid": 0"#,
+            r#"{"ph": "B", "cat": "validate", "name": "double(1)", "pid": 1, "tid": 0"#,
+            r#"{"ph": "E", "cat": "validate", "pid": 1, "tid": 0"#,
+            r#"{"ph": "B", "cat": "execute", "name": "double(1)", "pid": 1, "tid": 0"#,
+            r#"{"ph": "E", "cat": "execute", "pid": 1, "tid": 0"#,
+            r#"{"ph": "E", "cat": "validate", "pid": 1, "tid": 0"#,
+        ]
+    );
+}
+
+#[test]
+fn snapshots_are_separate_threads() {
+    let mut db = Database::default();
+    db.set_input(1, 1);
+    db.set_input(2, 2);
+
+    db.salsa_runtime().start_trace();
+    let snapshot = db.snapshot();
+    std::thread::spawn(move || snapshot.double(1))
+        .join()
+        .unwrap();
+    db.double(2);
+    let trace = db.salsa_runtime().finish_trace();
+
+    assert_eq!(
+        chrome_json(&trace),
+        vec![
+            r#"{"ph": "B", "cat": "execute", "name": "double(1)", "pid": 1, "tid": 1"#,
+            r#"{"ph": "E", "cat": "execute", "pid": 1, "tid": 1"#,
+            r#"{"ph": "B", "cat": "execute", "name": "double(2)", "pid": 1, "tid": 0"#,
+            r#"{"ph": "E", "cat": "execute", "pid": 1, "tid": 0"#,
+        ]
+    );
+}
@@ -0,0 +1,104 @@
+//! Test the spans opened with the `tracing` feature.
+#![cfg(feature = "tracing")]
+
+use salsa::{Database as _, Durability, SweepStrategy};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+#[salsa::query_group(QueryGroupStorage)]
+trait QueryGroup: salsa::Database {
+    #[salsa::input]
+    fn input(&self, x: u32) -> u32;
+
+    fn double(&self, x: u32) -> u32;
+}
+
+fn double(db: &dyn QueryGroup, x: u32) -> u32 {
+    db.input(x) * 2
+}
+
+#[salsa::database(QueryGroupStorage)]
+#[derive(Default)]
+struct Database {
+    storage: salsa::Storage<Self>,
+}
+
+impl salsa::Database for Database {}
+
+/// Records each span as its name followed by its `database_key` field,
+/// if it has one.
+#[derive(Clone, Default)]
+struct Recorder {
+    spans: Arc<Mutex<Vec<String>>>,
+}
+
+struct DatabaseKey(Option<String>);
+
+impl Visit for DatabaseKey {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "database_key" {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+impl Subscriber for Recorder {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let mut key = DatabaseKey(None);
+        span.record(&mut key);
+        let mut spans = self.spans.lock().unwrap();
+        spans.push(match key.0 {
+            Some(key) => format!("{} {}", span.metadata().name(), key),
+            None => span.metadata().name().to_string(),
+        });
+        Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, _event: &Event<'_>) {}
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
+
+#[test]
+fn spans_for_fetch_execute_and_validation() {
+    let recorder = Recorder::default();
+    let spans = recorder.spans.clone();
+    tracing::subscriber::with_default(recorder, || {
+        let mut db = Database::default();
+        db.set_input(1, 1);
+        db.set_input(2, 2);
+        db.double(1);
+        db.set_input(2, 20);
+        db.double(1);
+        db.salsa_runtime_mut().synthetic_write(Durability::LOW);
+        db.sweep_all(SweepStrategy::discard_outdated());
+    });
+
+    assert_eq!(
+        *spans.lock().unwrap(),
+        vec![
+            "with_incremented_revision",
+            "with_incremented_revision",
+            "fetch double(1)",
+            "execute double(1)",
+            "with_incremented_revision",
+            "fetch double(1)",
+            "maybe_changed_since input(1)",
+            "with_incremented_revision",
+            "sweep",
+        ]
+    );
+}
@@ -0,0 +1,171 @@
+//! Test batching writes into one revision with `Database::transaction`.
+
+use salsa::{Database as _, Durability, EventKind, ParallelDatabase, Snapshot};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+
+#[salsa::query_group(QueryGroupStorage)]
+trait QueryGroup: salsa::Database {
+    #[salsa::input]
+    fn text(&self, x: u32) -> String;
+
+    #[salsa::input]
+    fn separator(&self) -> char;
+
+    fn joined(&self) -> String;
+}
+
+fn joined(db: &dyn QueryGroup) -> String {
+    let separator = db.separator().to_string();
+    let texts: Vec<_> = (0..3).map(|x| db.text(x)).collect();
+    texts.join(&separator)
+}
+
+#[salsa::database(QueryGroupStorage)]
+#[derive(Default)]
+struct Database {
+    storage: salsa::Storage<Self>,
+    events: Arc<Mutex<Vec<String>>>,
+}
+
+impl salsa::Database for Database {
+    fn salsa_event(&self, event: salsa::Event) {
+        let event = match event.kind {
+            EventKind::DidIncrementRevision {
+                revision,
+                durability,
+            } => format!("DidIncrementRevision({:?}, {:?})", revision, durability),
+            EventKind::DidSetInput { database_key, .. } => {
+                format!("DidSetInput({:?})", database_key.debug(self))
+            }
+            EventKind::WillExecute { database_key } => {
+                format!("WillExecute({:?})", database_key.debug(self))
+            }
+            _ => return,
+        };
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+impl ParallelDatabase for Database {
+    fn snapshot(&self) -> Snapshot<Self> {
+        Snapshot::new(Database {
+            storage: self.storage.snapshot(),
+            events: self.events.clone(),
+        })
+    }
+
+    fn fork(&self, forker: salsa::ForkState) -> Snapshot<Self> {
+        Snapshot::new(Database {
+            storage: self.storage.fork(forker),
+            events: self.events.clone(),
+        })
+    }
+}
+
+impl Database {
+    fn new() -> Self {
+        let mut db = Database::default();
+        db.set_separator_with_durability(',', Durability::HIGH);
+        for x in 0..3 {
+            db.set_text(x, x.to_string());
+        }
+        assert_eq!(db.joined(), "0,1,2");
+        db.take_events();
+        db
+    }
+
+    fn take_events(&self) -> Vec<String> {
+        std::mem::take(&mut *self.events.lock().unwrap())
+    }
+}
+
+#[test]
+fn writes_share_one_revision() {
+    let mut db = Database::new();
+    db.transaction(|db| {
+        db.set_text(0, "a".to_string());
+        db.set_text(2, "c".to_string());
+        db.set_separator_with_durability(';', Durability::HIGH);
+    });
+    assert_eq!(db.joined(), "a;1;c");
+    assert_eq!(
+        db.take_events(),
+        vec![
+            "DidSetInput(text(0))",
+            "DidSetInput(text(2))",
+            "DidSetInput(separator(()))",
+            "DidIncrementRevision(R6, Some(Durability(2)))",
+            "WillExecute(joined(()))",
+        ]
+    );
+}
+
+#[test]
+fn inputs_can_be_read() {
+    let mut db = Database::new();
+    db.transaction(|db| {
+        let text = db.text(0);
+        db.set_text(0, text + "!");
+        assert_eq!(db.text(0), "0!");
+    });
+    assert_eq!(db.joined(), "0!,1,2");
+}
+
+#[test]
+#[should_panic(expected = "during a transaction")]
+fn derived_queries_cannot_be_fetched() {
+    let mut db = Database::new();
+    db.transaction(|db| {
+        db.set_text(0, "a".to_string());
+        db.joined();
+    });
+}
+
+#[test]
+#[should_panic(expected = "during a transaction")]
+fn snapshots_cannot_be_taken() {
+    let mut db = Database::new();
+    db.transaction(|db| {
+        db.set_text(0, "a".to_string());
+        db.snapshot();
+    });
+}
+
+#[test]
+fn nested_transactions_are_merged() {
+    let mut db = Database::new();
+    db.transaction(|db| {
+        db.set_text(0, "a".to_string());
+        db.transaction(|db| db.set_text(1, "b".to_string()));
+        db.synthetic_write(Durability::MEDIUM);
+    });
+    assert_eq!(db.joined(), "a,b,2");
+    assert_eq!(
+        db.take_events(),
+        vec![
+            "DidSetInput(text(0))",
+            "DidSetInput(text(1))",
+            "DidIncrementRevision(R6, Some(Durability(1)))",
+            "WillExecute(joined(()))",
+        ]
+    );
+}
+
+#[test]
+fn panics_end_the_transaction() {
+    let mut db = Database::new();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        db.transaction(|db| {
+            db.set_text(0, "a".to_string());
+            panic!("oops");
+        })
+    }));
+    assert!(result.is_err());
+
+    // The write lock was released, and the writes made before the
+    // panic were kept.
+    assert_eq!(db.snapshot().joined(), "a,1,2");
+    db.set_text(1, "b".to_string());
+    assert_eq!(db.joined(), "a,b,2");
+}